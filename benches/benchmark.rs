@@ -1,8 +1,11 @@
 use std::time::Duration;
 
 use corrset::{
-  dispatch_inner_method, dispatch_outer_method, inner::alloc::AllocCorrSet, CorrSetInner,
-  CorrSetOuter, Row,
+  inner::alloc::AllocCorrSet,
+  metric::Pearson,
+  outer::{DirectSearch, InnerSearch, SearchOptions},
+  registry::{self, InnerTask, OuterTask},
+  CorrSetInner, CorrSetOuter, Dataset,
 };
 use criterion::{criterion_group, criterion_main, Bencher, Criterion};
 
@@ -13,22 +16,47 @@ fn corrset_outer(c: &mut Criterion) {
   g.measurement_time(Duration::from_secs(10))
     .warm_up_time(Duration::from_secs(1))
     .sample_size(10);
-  let data = corrset::load_rows("data/data-large.json").unwrap();
+  let data = Dataset::load("data/data-large.json").unwrap();
 
   const NUM_COMBS: usize = 5_000_000;
+  /// Direct searches can't stop after `NUM_COMBS` sets, so they search every
+  /// set of a smaller size, about as many.
+  const DIRECT_K: usize = 3;
+
+  struct Run<'a, 'b> {
+    b: &'a mut Bencher<'b>,
+    data: &'a Dataset,
+  }
+
+  impl OuterTask for Run<'_, '_> {
+    type Output = ();
+
+    fn run<T: CorrSetOuter>(self) {
+      let outer = T::new();
+      let inner = AllocCorrSet::build(self.data);
+      self.b.iter(|| {
+        let combs = inner.combinations(K);
+        outer.k_set(&inner, &Pearson, combs.take(NUM_COMBS));
+      });
+    }
+
+    fn run_direct<S: DirectSearch>(self) {
+      let search = S::build(self.data);
+      self.b.iter(|| search.k_set(&Pearson, DIRECT_K));
+    }
+
+    fn run_search<S: InnerSearch>(self) {
+      let search = S::new(&SearchOptions::default());
+      let inner = AllocCorrSet::build(self.data);
+      self.b.iter(|| {
+        search.k_top_n(&inner, &Pearson, K, 1);
+      });
+    }
+  }
 
   for impl_name in corrset::outer_names() {
-    g.bench_function(&impl_name, |b| {
-      fn run<T: CorrSetOuter>(b: &mut Bencher, data: &[Row]) {
-        let outer = T::new();
-        let inner = AllocCorrSet::build(&data);
-        b.iter(|| {
-          let combs = inner.combinations(K);
-          outer.k_set(&inner, combs.take(NUM_COMBS));
-        });
-      }
-
-      dispatch_outer_method!(impl_name.as_str(), run, b, &data);
+    g.bench_function(impl_name, |b| {
+      registry::run_outer(impl_name, Run { b, data: &data }).unwrap()
     });
   }
 
@@ -37,18 +65,27 @@ fn corrset_outer(c: &mut Criterion) {
 
 fn corrset_inner(c: &mut Criterion) {
   let mut g = c.benchmark_group("corrset-inner");
-  let data = corrset::load_rows("data/data-large.json").unwrap();
+  let data = Dataset::load("data/data-large.json").unwrap();
+
+  struct Run<'a, 'b> {
+    b: &'a mut Bencher<'b>,
+    data: &'a Dataset,
+  }
+
+  impl InnerTask for Run<'_, '_> {
+    type Output = ();
+
+    fn run<T: CorrSetInner>(self) {
+      let cs = T::build(self.data);
+      let qs = cs.combinations(K).next().unwrap();
+      let mut scratch = cs.init_scratch();
+      self.b.iter(|| cs.corr_set(&Pearson, &mut scratch, &qs));
+    }
+  }
 
   for impl_name in corrset::inner_names() {
-    g.bench_function(&impl_name, |b| {
-      fn run<'a, T: CorrSetInner<'a>>(b: &mut Bencher, data: &'a [Row]) {
-        let cs = T::build(data);
-        let qs = cs.combinations(K).next().unwrap();
-        let mut scratch = cs.init_scratch();
-        b.iter(|| cs.corr_set(&mut scratch, &qs));
-      }
-
-      dispatch_inner_method!(impl_name.as_str(), run, b, &data);
+    g.bench_function(impl_name, |b| {
+      registry::run_inner(impl_name, Run { b, data: &data }).unwrap()
     });
   }
 
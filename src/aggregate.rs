@@ -0,0 +1,556 @@
+//! How a user's scores on a question set's questions are combined into the one
+//! value that is correlated against their target.
+//!
+//! An [`Aggregator`] names the combination and [`Missing`] what to do about
+//! unanswered questions, and [`Aggregation`] resolves both against the
+//! questions of an index. Every inner stores its [`Aggregation`] alongside its
+//! [`Targets`](crate::target::Targets), which are still sums of the answered
+//! scores.
+
+use std::{
+  fmt,
+  hash::{Hash, Hasher},
+  path::Path,
+  sync::Arc,
+};
+
+use anyhow::Result;
+use fxhash::{FxHashMap as HashMap, FxHasher64};
+use indexical::IndexedDomain;
+use itertools::Itertools;
+
+use crate::{
+  dataset::QuestionIdx, inner::imap::QuestionMap, load::Columns, utils, BuildOptions, Dataset,
+  Question,
+};
+
+/// How the scores on a set's questions are combined.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Aggregator {
+  /// The sum of the scores.
+  #[default]
+  Sum,
+  /// The mean of the scores.
+  Mean,
+  /// The highest score.
+  Max,
+  /// The lowest score.
+  Min,
+  /// The sum of each score times its question's weight. Questions without a
+  /// weight count once.
+  Weighted(Arc<Weights>),
+  /// The sum of each score's z-score among the answers to its question, so
+  /// that questions scored on different scales count equally. A question
+  /// whose answers are all equal contributes nothing.
+  ZSum,
+}
+
+/// Written as `sum`, `mean`, `max`, `min`, `weighted:` and the weights'
+/// fingerprint, or `z-sum`, so that state saved under one aggregator isn't
+/// reused under another.
+impl fmt::Display for Aggregator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Aggregator::Sum => write!(f, "sum"),
+      Aggregator::Mean => write!(f, "mean"),
+      Aggregator::Max => write!(f, "max"),
+      Aggregator::Min => write!(f, "min"),
+      Aggregator::Weighted(weights) => write!(f, "weighted:{:016x}", weights.fingerprint()),
+      Aggregator::ZSum => write!(f, "z-sum"),
+    }
+  }
+}
+
+/// What to do about a user who didn't answer every question of a set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Missing {
+  /// Leave the user out.
+  #[default]
+  CompleteCase,
+  /// Score each unanswered question 0.
+  Zero,
+  /// Score each unanswered question its mean score among those who answered
+  /// it.
+  Mean,
+  /// Leave the user out if they answered fewer than this many of the set's
+  /// questions (and at least one), and otherwise combine only their answers,
+  /// scaling sums up to the size of the set.
+  AtLeast(usize),
+}
+
+/// Written as `complete-case`, `zero`, `mean` or `at-least:` and the count.
+impl fmt::Display for Missing {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Missing::CompleteCase => write!(f, "complete-case"),
+      Missing::Zero => write!(f, "zero"),
+      Missing::Mean => write!(f, "mean"),
+      Missing::AtLeast(m) => write!(f, "at-least:{m}"),
+    }
+  }
+}
+
+impl Missing {
+  /// The policy as one number, for the mapped index.
+  pub(crate) fn code(self) -> u64 {
+    match self {
+      Missing::CompleteCase => 0,
+      Missing::Zero => 1,
+      Missing::Mean => 2,
+      Missing::AtLeast(m) => 3 + m as u64,
+    }
+  }
+
+  pub(crate) fn from_code(code: u64) -> Self {
+    match code {
+      0 => Missing::CompleteCase,
+      1 => Missing::Zero,
+      2 => Missing::Mean,
+      m => Missing::AtLeast((m - 3) as usize),
+    }
+  }
+}
+
+/// A weight per question, for [`Aggregator::Weighted`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Weights(HashMap<Question, f64>);
+
+impl Weights {
+  pub fn get(&self, question: &Question) -> Option<f64> {
+    self.0.get(question).copied()
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  /// The weighted questions, by name.
+  pub fn questions(&self) -> impl Iterator<Item = &Question> {
+    self.0.keys().sorted_by(|a, b| a.0.cmp(&b.0))
+  }
+
+  /// A hash of every question and weight, independent of their order.
+  pub fn fingerprint(&self) -> u64 {
+    let mut hasher = FxHasher64::default();
+    for question in self.questions() {
+      question.hash(&mut hasher);
+      self.0[question].to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+  }
+
+  /// Reads `path`, a CSV or TSV file with `question` and `weight` columns, or
+  /// a JSON object from question to weight.
+  pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+    let question = &Columns::default().question;
+    let table = utils::load_table(path.as_ref(), "weights", question, "weight")?;
+    Ok(
+      table
+        .into_iter()
+        .map(|(q, weight)| (Question(q), weight))
+        .collect(),
+    )
+  }
+}
+
+impl FromIterator<(Question, f64)> for Weights {
+  fn from_iter<I: IntoIterator<Item = (Question, f64)>>(iter: I) -> Self {
+    Weights(iter.into_iter().collect())
+  }
+}
+
+/// How a question's scores are prepared for combining: a score, or `fill` for
+/// an unanswered question, is mapped to `score * scale + offset`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub(crate) struct Transform {
+  pub scale: f64,
+  pub offset: f64,
+  pub fill: f64,
+}
+
+impl Default for Transform {
+  fn default() -> Self {
+    Transform {
+      scale: 1.,
+      offset: 0.,
+      fill: 0.,
+    }
+  }
+}
+
+/// How transformed scores are folded together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Combine {
+  /// The sum of the raw scores, without transforms.
+  Total,
+  Sum,
+  Mean,
+  Max,
+  Min,
+}
+
+impl Combine {
+  pub(crate) const ALL: [Combine; 5] = [
+    Combine::Total,
+    Combine::Sum,
+    Combine::Mean,
+    Combine::Max,
+    Combine::Min,
+  ];
+
+  /// Folds `scores`, each with its question's transform, into the total of
+  /// the answered scores and the combined value, or `None` if `missing` leaves
+  /// the user out.
+  #[inline]
+  pub(crate) fn apply(
+    self,
+    missing: Missing,
+    scores: impl Iterator<Item = (Option<u32>, Transform)>,
+  ) -> Option<(u32, f64)> {
+    let mut total = 0;
+    if self == Combine::Total && missing == Missing::CompleteCase {
+      for (score, _) in scores {
+        total += score?;
+      }
+      return Some((total, total as f64));
+    }
+
+    let mut value = match self {
+      Combine::Max => f64::NEG_INFINITY,
+      Combine::Min => f64::INFINITY,
+      _ => 0.,
+    };
+    let (mut n, mut answered) = (0, 0);
+    for (score, t) in scores {
+      n += 1;
+      let score = match (score, missing) {
+        (Some(score), _) => {
+          total += score;
+          answered += 1;
+          score as f64
+        }
+        (None, Missing::CompleteCase) => return None,
+        (None, Missing::AtLeast(_)) => continue,
+        (None, Missing::Zero | Missing::Mean) => t.fill,
+      };
+      let x = score * t.scale + t.offset;
+      value = match self {
+        Combine::Max => value.max(x),
+        Combine::Min => value.min(x),
+        _ => value + x,
+      };
+    }
+    let min_answered = match missing {
+      Missing::AtLeast(m) => m.max(1),
+      _ => 1,
+    };
+    if answered < min_answered {
+      return None;
+    }
+    let counted = match missing {
+      Missing::AtLeast(_) => answered,
+      _ => n,
+    };
+    match self {
+      Combine::Mean => value /= counted as f64,
+      Combine::Total | Combine::Sum if counted < n => value *= n as f64 / counted as f64,
+      _ => {}
+    }
+    Some((total, value))
+  }
+}
+
+/// The count, sum and sum of squares of a question's scores.
+#[derive(Clone, Copy, Default)]
+struct Stats {
+  n: u64,
+  sum: u64,
+  sum_sq: u128,
+}
+
+impl Stats {
+  fn add(&mut self, score: u32) {
+    self.n += 1;
+    self.sum += score as u64;
+    self.sum_sq += (score as u128).pow(2);
+  }
+
+  fn remove(&mut self, score: u32) {
+    self.n -= 1;
+    self.sum -= score as u64;
+    self.sum_sq -= (score as u128).pow(2);
+  }
+
+  /// The scale and offset that turn a score into its z-score.
+  fn z_transform(&self) -> (f64, f64) {
+    let n = self.n as u128;
+    // n² times the variance, exactly.
+    let spread = n * self.sum_sq - (self.sum as u128).pow(2);
+    if spread == 0 {
+      return (0., 0.);
+    }
+    let sd = (spread as f64).sqrt() / self.n as f64;
+    let mean = self.sum as f64 / self.n as f64;
+    (1. / sd, -mean / sd)
+  }
+}
+
+/// An [`Aggregator`] and [`Missing`] policy resolved to a transform for each
+/// question of an index.
+pub struct Aggregation {
+  aggregator: Aggregator,
+  combine: Combine,
+  missing: Missing,
+  transforms: QuestionMap<Transform>,
+  /// Each question's score statistics, for z-scores and imputed means.
+  stats: QuestionMap<Stats>,
+}
+
+impl Aggregation {
+  pub fn new(opts: &BuildOptions, data: &Dataset) -> Self {
+    let (aggregator, questions) = (&opts.aggregator, &data.questions);
+    let mut aggregation = Aggregation {
+      aggregator: aggregator.clone(),
+      combine: match aggregator {
+        Aggregator::Sum => Combine::Total,
+        Aggregator::Mean => Combine::Mean,
+        Aggregator::Max => Combine::Max,
+        Aggregator::Min => Combine::Min,
+        Aggregator::Weighted(_) | Aggregator::ZSum => Combine::Sum,
+      },
+      missing: opts.missing,
+      transforms: QuestionMap::new(questions, |_| Transform::default()),
+      stats: QuestionMap::new(questions, |_| Stats::default()),
+    };
+    for ((_, q), score) in data.latest_scores() {
+      aggregation.stats[q].add(score);
+    }
+    for q in questions.indices() {
+      aggregation.refresh(q, questions.value(q));
+    }
+    aggregation
+  }
+
+  pub fn aggregator(&self) -> &Aggregator {
+    &self.aggregator
+  }
+
+  pub fn missing(&self) -> Missing {
+    self.missing
+  }
+
+  /// Whether the combined value is the plain sum of the answered scores, so
+  /// it is an exact integer.
+  pub fn is_total(&self) -> bool {
+    self.combine == Combine::Total && matches!(self.missing, Missing::CompleteCase | Missing::Zero)
+  }
+
+  /// Whether users who didn't answer every question of a set are left out, so
+  /// that only the intersection of the questions' users is scored. Otherwise
+  /// their union is.
+  pub fn is_complete_case(&self) -> bool {
+    self.missing == Missing::CompleteCase
+  }
+
+  pub(crate) fn combine(&self) -> Combine {
+    self.combine
+  }
+
+  /// Every question's transform, in question order.
+  pub(crate) fn transforms(&self) -> impl Iterator<Item = Transform> + '_ {
+    self.transforms.values().copied()
+  }
+
+  fn refresh(&mut self, q: QuestionIdx, question: &Question) {
+    let stats = &self.stats[q];
+    let (scale, offset) = match &self.aggregator {
+      Aggregator::Weighted(weights) => (weights.get(question).unwrap_or(1.), 0.),
+      Aggregator::ZSum => stats.z_transform(),
+      _ => (1., 0.),
+    };
+    let fill = match self.missing {
+      Missing::Mean if stats.n > 0 => stats.sum as f64 / stats.n as f64,
+      _ => 0.,
+    };
+    self.transforms[q] = Transform {
+      scale,
+      offset,
+      fill,
+    };
+  }
+
+  /// Combines a user's `scores` on a set's questions into the total of their
+  /// answers and the aggregated value, or `None` if they are left out for
+  /// missing answers.
+  #[inline]
+  pub fn try_combine(
+    &self,
+    scores: impl Iterator<Item = (QuestionIdx, Option<u32>)>,
+  ) -> Option<(u32, f64)> {
+    let scores = scores.map(|(q, score)| (score, self.transforms[q]));
+    self.combine.apply(self.missing, scores)
+  }
+
+  /// Like [`try_combine`](Self::try_combine), without checking that the
+  /// questions are in the index.
+  ///
+  /// # Safety
+  /// Every question must be one of the index's questions.
+  #[inline]
+  pub unsafe fn try_combine_unchecked(
+    &self,
+    scores: impl Iterator<Item = (QuestionIdx, Option<u32>)>,
+  ) -> Option<(u32, f64)> {
+    let transform = |q| unsafe { *self.transforms.get_unchecked(q) };
+    let scores = scores.map(|(q, score)| (score, transform(q)));
+    self.combine.apply(self.missing, scores)
+  }
+
+  /// Accounts for an answer to `question` changing from `old` to `new`, where
+  /// `None` is no answer.
+  pub(crate) fn update(
+    &mut self,
+    q: QuestionIdx,
+    question: &Question,
+    old: Option<u32>,
+    new: Option<u32>,
+  ) {
+    if let Some(old) = old {
+      self.stats[q].remove(old);
+    }
+    if let Some(new) = new {
+      self.stats[q].add(new);
+    }
+    self.refresh(q, question);
+  }
+
+  /// Resolves the transforms of questions added to the index's domain.
+  pub(crate) fn resize(&mut self, questions: &Arc<IndexedDomain<Question>>) {
+    self.stats = QuestionMap::new(questions, |q| {
+      self.stats.get(q).copied().unwrap_or_default()
+    });
+    self.transforms = QuestionMap::new(questions, |_| Transform::default());
+    for q in questions.indices() {
+      self.refresh(q, questions.value(q));
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::inner::test_utils::mock_data;
+
+  fn question(name: &str) -> Question {
+    Question(name.to_string())
+  }
+
+  fn combine(aggregator: Aggregator, scores: &[(&str, Option<u32>)]) -> Option<(u32, f64)> {
+    combine_missing(aggregator, Missing::CompleteCase, scores)
+  }
+
+  fn combine_missing(
+    aggregator: Aggregator,
+    missing: Missing,
+    scores: &[(&str, Option<u32>)],
+  ) -> Option<(u32, f64)> {
+    let data = mock_data();
+    let opts = BuildOptions {
+      aggregator,
+      missing,
+      ..Default::default()
+    };
+    let aggregation = Aggregation::new(&opts, &data);
+    let scores = scores
+      .iter()
+      .map(|(q, s)| (data.questions.index(&question(q)), *s));
+    aggregation.try_combine(scores)
+  }
+
+  #[test]
+  fn aggregators() {
+    let scores = [("1", Some(1)), ("3", Some(0))];
+    assert_eq!(combine(Aggregator::Sum, &scores), Some((1, 1.)));
+    assert_eq!(combine(Aggregator::Mean, &scores), Some((1, 0.5)));
+    assert_eq!(combine(Aggregator::Max, &scores), Some((1, 1.)));
+    assert_eq!(combine(Aggregator::Min, &scores), Some((1, 0.)));
+    assert_eq!(
+      combine(Aggregator::Sum, &[("1", Some(1)), ("2", None)]),
+      None
+    );
+
+    let weights = Weights::from_iter([(question("1"), 3.), (question("9"), 2.)]);
+    let weighted = Aggregator::Weighted(Arc::new(weights));
+    let scores = [("1", Some(1)), ("3", Some(1))];
+    assert_eq!(combine(weighted, &scores), Some((2, 4.)));
+
+    // Question 1 is answered 1, 0, 1, so its mean is 2/3 and its standard
+    // deviation √2/3.
+    let (total, z) = combine(Aggregator::ZSum, &[("1", Some(1))]).unwrap();
+    assert_eq!(total, 1);
+    assert!((z - 1. / 2f64.sqrt()).abs() < 1e-12);
+  }
+
+  #[test]
+  fn missing() {
+    // Question 2 is answered 1, 0, 1, so its mean is 2/3.
+    let scores = [("1", Some(1)), ("2", None), ("3", Some(1))];
+    let sum = |missing| combine_missing(Aggregator::Sum, missing, &scores);
+    assert_eq!(sum(Missing::CompleteCase), None);
+    assert_eq!(sum(Missing::Zero), Some((2, 2.)));
+    assert_eq!(sum(Missing::Mean), Some((2, 2. + 2. / 3.)));
+    assert_eq!(sum(Missing::AtLeast(2)), Some((2, 3.)));
+    assert_eq!(sum(Missing::AtLeast(3)), None);
+    let mean = combine_missing(Aggregator::Mean, Missing::AtLeast(2), &scores);
+    assert_eq!(mean, Some((2, 1.)));
+
+    let unanswered = [("1", None), ("2", None)];
+    assert_eq!(
+      combine_missing(Aggregator::Sum, Missing::Zero, &unanswered),
+      None
+    );
+    assert_eq!(
+      Missing::from_code(Missing::AtLeast(4).code()),
+      Missing::AtLeast(4)
+    );
+  }
+
+  #[test]
+  fn constant_question() {
+    let rows = [("a", 2), ("b", 2)].map(|(u, score)| crate::Row {
+      user: crate::User(u.to_string()),
+      question: question("1"),
+      score,
+    });
+    let data = Dataset::from_rows(rows).unwrap();
+    let opts = BuildOptions {
+      aggregator: Aggregator::ZSum,
+      ..Default::default()
+    };
+    let aggregation = Aggregation::new(&opts, &data);
+    let q = data.questions.index(&question("1"));
+    assert_eq!(
+      aggregation.try_combine([(q, Some(2))].into_iter()),
+      Some((2, 0.))
+    );
+  }
+
+  #[test]
+  fn load_weights() {
+    let path = std::env::temp_dir().join(format!("corrset-weights-{}.tsv", std::process::id()));
+    std::fs::write(&path, "question\tweight\n1\t0.5\n2\t2\n").unwrap();
+    let weights = Weights::load(&path);
+    std::fs::remove_file(&path).unwrap();
+    let weights = weights.unwrap();
+    assert_eq!(weights.get(&question("2")), Some(2.));
+    assert_eq!(
+      Aggregator::Weighted(Arc::new(weights.clone())).to_string(),
+      format!("weighted:{:016x}", weights.fingerprint())
+    );
+  }
+}
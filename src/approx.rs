@@ -0,0 +1,315 @@
+//! Approximate searches that score a small fraction of the question sets.
+//!
+//! Beam search grows sets one question at a time, keeping only the `width`
+//! best sets at each size; greedy forward selection is a beam of width 1.
+//! Local swap refinement then repeatedly replaces one question of each found
+//! set with the outside question that most improves its score, until no swap
+//! helps. Neither is guaranteed to find the optimum, so compare against an
+//! exhaustive search like [`CorrSetFused`](crate::fused::CorrSetFused)
+//! on data where that is affordable.
+
+use fxhash::FxHashSet as HashSet;
+use itertools::Itertools;
+
+use crate::{
+  inner::{Corr, CorrSetInner},
+  metric::Metric,
+  outer::{CorrSetResult, InnerSearch, KSet, SearchOptions},
+  utils::TopN,
+};
+
+/// The default number of sets a beam search keeps at each size.
+pub const BEAM_WIDTH: usize = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ApproxSearch {
+  /// The number of sets kept at each size.
+  pub width: usize,
+  /// The maximum number of improving swaps made to each found set.
+  pub swap_rounds: usize,
+}
+
+impl ApproxSearch {
+  pub fn greedy() -> Self {
+    Self::beam(1)
+  }
+
+  pub fn beam(width: usize) -> Self {
+    ApproxSearch {
+      width,
+      swap_rounds: 0,
+    }
+  }
+
+  pub fn with_swaps(self, swap_rounds: usize) -> Self {
+    ApproxSearch {
+      swap_rounds,
+      ..self
+    }
+  }
+
+  /// Returns up to `n` of the best question sets found, best first. At most
+  /// `width` sets are found, so `n` larger than `width` returns `width` sets.
+  pub fn k_top_n<'a, T: CorrSetInner, M: Metric + ?Sized>(
+    &self,
+    corrset: &'a T,
+    metric: &M,
+    k: usize,
+    n: usize,
+  ) -> CorrSetResult<'a> {
+    let mut search = Search {
+      qs: corrset.iter_qs().collect_vec(),
+      corrset,
+      metric,
+      scratch: corrset.init_scratch(),
+      n_evaluated: 0,
+      n_nan: 0,
+    };
+
+    let mut beam = vec![Vec::new()];
+    let mut found = Vec::new();
+    for _ in 0..k.min(search.qs.len()) {
+      found = search.extend(&beam, self.width);
+      beam = found.iter().map(|(set, _)| set.clone()).collect();
+    }
+
+    let mut seen = HashSet::default();
+    let mut refined = found
+      .into_iter()
+      .map(|(set, corr)| search.refine(set, corr, self.swap_rounds))
+      .filter(|(set, corr)| !corr.r.is_nan() && seen.insert(set.clone()))
+      .collect_vec();
+    refined
+      .sort_by(|(a, a_corr), (b, b_corr)| b_corr.r.total_cmp(&a_corr.r).then_with(|| a.cmp(b)));
+    refined.truncate(n);
+
+    CorrSetResult {
+      sets: refined
+        .into_iter()
+        .map(|(set, corr)| KSet {
+          questions: set
+            .into_iter()
+            .map(|i| corrset.to_question(search.qs[i].clone()))
+            .collect(),
+          r: corr.r,
+          n_users: corr.n_users,
+        })
+        .collect(),
+      n_evaluated: search.n_evaluated,
+      n_nan: search.n_nan,
+      n_pruned: 0,
+    }
+  }
+
+  pub fn k_set<'a, T: CorrSetInner, M: Metric + ?Sized>(
+    &self,
+    corrset: &'a T,
+    metric: &M,
+    k: usize,
+  ) -> CorrSetResult<'a> {
+    self.k_top_n(corrset, metric, k, 1)
+  }
+}
+
+/// Beam search, of [`SearchOptions::beam_width`], with swaps.
+impl InnerSearch for ApproxSearch {
+  type Report<'a> = CorrSetResult<'a>;
+
+  fn new(opts: &SearchOptions) -> Self {
+    ApproxSearch::beam(opts.beam_width).with_swaps(opts.swap_rounds)
+  }
+
+  fn k_top_n<'a, T: CorrSetInner, M: Metric + ?Sized>(
+    &self,
+    corrset: &'a T,
+    metric: &M,
+    k: usize,
+    n: usize,
+  ) -> CorrSetResult<'a> {
+    ApproxSearch::k_top_n(self, corrset, metric, k, n)
+  }
+}
+
+/// Greedy forward selection with swaps, i.e. [`ApproxSearch::greedy`], as an
+/// [`InnerSearch`] that ignores [`SearchOptions::beam_width`].
+#[derive(Clone, Copy, Debug)]
+pub struct Greedy(pub ApproxSearch);
+
+impl InnerSearch for Greedy {
+  type Report<'a> = CorrSetResult<'a>;
+
+  fn new(opts: &SearchOptions) -> Self {
+    Greedy(ApproxSearch::greedy().with_swaps(opts.swap_rounds))
+  }
+
+  fn k_top_n<'a, T: CorrSetInner, M: Metric + ?Sized>(
+    &self,
+    corrset: &'a T,
+    metric: &M,
+    k: usize,
+    n: usize,
+  ) -> CorrSetResult<'a> {
+    self.0.k_top_n(corrset, metric, k, n)
+  }
+}
+
+/// Question sets are handled as sorted positions into `qs`, so they can be
+/// compared and hashed whatever the inner's question type.
+struct Search<'a, T: CorrSetInner, M: ?Sized> {
+  corrset: &'a T,
+  metric: &'a M,
+  qs: Vec<T::Q>,
+  scratch: T::Scratch,
+  n_evaluated: usize,
+  n_nan: usize,
+}
+
+impl<T: CorrSetInner, M: Metric + ?Sized> Search<'_, T, M> {
+  fn score(&mut self, set: &[usize]) -> Corr {
+    let qs = set.iter().map(|i| self.qs[*i].clone()).collect_vec();
+    let corr = self.corrset.corr_set(self.metric, &mut self.scratch, &qs);
+    self.n_evaluated += 1;
+    if corr.r.is_nan() {
+      self.n_nan += 1;
+    }
+    corr
+  }
+
+  /// Scores every one-question extension of the sets in `beam`, and returns
+  /// the `width` best, best first. If fewer than `width` extensions have a
+  /// score, the rest are filled with NaN-scored ones, since those may still
+  /// grow into scored sets, e.g. once they have enough users.
+  fn extend(&mut self, beam: &[Vec<usize>], width: usize) -> Vec<(Vec<usize>, Corr)> {
+    let mut seen = HashSet::default();
+    let mut top = TopN::new(width);
+    let mut nan = Vec::new();
+    for set in beam {
+      for q in 0..self.qs.len() {
+        if set.contains(&q) {
+          continue;
+        }
+        let mut next = set.clone();
+        next.push(q);
+        next.sort_unstable();
+        if seen.insert(next.clone()) {
+          let corr = self.score(&next);
+          if corr.r.is_nan() && nan.len() < width {
+            nan.push((next.clone(), corr));
+          }
+          top.push(next, corr);
+        }
+      }
+    }
+    let mut found = top.into_sorted_vec();
+    let missing = width - found.len();
+    found.extend(nan.into_iter().take(missing));
+    found
+  }
+
+  /// Makes up to `rounds` swaps that improve the score of `set`, taking the
+  /// best swap each round.
+  fn refine(&mut self, mut set: Vec<usize>, mut corr: Corr, rounds: usize) -> (Vec<usize>, Corr) {
+    for _ in 0..rounds {
+      let mut best: Option<(Vec<usize>, Corr)> = None;
+      for i in 0..set.len() {
+        for q in 0..self.qs.len() {
+          if set.contains(&q) {
+            continue;
+          }
+          let mut next = set.clone();
+          next[i] = q;
+          next.sort_unstable();
+          let next_corr = self.score(&next);
+          let to_beat = best.as_ref().map_or(corr.r, |(_, c)| c.r);
+          if next_corr.r > to_beat || (to_beat.is_nan() && !next_corr.r.is_nan()) {
+            best = Some((next, next_corr));
+          }
+        }
+      }
+      match best {
+        Some(better) => (set, corr) = better,
+        None => break,
+      }
+    }
+    (set, corr)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    fused::CorrSetFused,
+    inner::{
+      alloc::AllocCorrSet,
+      basic::BasicCorrSet,
+      test_utils::{mock_data, sparse_data},
+    },
+    metric::Pearson,
+    outer::{serial::CorrSetSerial, CorrSetOuter, DirectSearch},
+    BuildOptions, Missing, Question,
+  };
+
+  #[test]
+  fn greedy_finds_best_pair() {
+    let cs = BasicCorrSet::build(&mock_data());
+    let result = ApproxSearch::greedy().k_set(&cs, &Pearson, 2);
+    let mut qs = result.best().unwrap().questions.clone();
+    qs.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(qs, vec![&Question("1".into()), &Question("2".into())]);
+  }
+
+  #[test]
+  fn wide_beam_is_exhaustive() {
+    let data = sparse_data();
+    let cs = AllocCorrSet::build(&data);
+    let fused = CorrSetFused::build(&data);
+    let exact = fused.k_top_n(&Pearson, 3, 5);
+    let approx = ApproxSearch::beam(1000).k_top_n(&cs, &Pearson, 3, 5);
+    let scores = |result: &CorrSetResult| result.sets.iter().map(|set| set.r).collect_vec();
+    assert_eq!(scores(&approx), scores(&exact));
+  }
+
+  #[test]
+  fn grows_through_nan_sets() {
+    // Imputing missing answers adds users as sets grow, so with `min_users`
+    // above any one question's users, every set of one question scores NaN.
+    let data = sparse_data();
+    let cs = AllocCorrSet::build(&data);
+    let mut scratch = cs.init_scratch();
+    let most_users = (cs.iter_qs())
+      .map(|q| cs.corr_set(&Pearson, &mut scratch, &[q]).n_users)
+      .max()
+      .unwrap();
+    let opts = BuildOptions {
+      missing: Missing::Zero,
+      min_users: most_users + 1,
+      ..Default::default()
+    };
+    let cs = AllocCorrSet::build_with(&data, &opts);
+    let exact = CorrSetSerial::new().k_top_n(&cs, &Pearson, cs.combinations(2), 5);
+    assert!(!exact.sets.is_empty());
+
+    let greedy = ApproxSearch::greedy().k_set(&cs, &Pearson, 2);
+    assert!(greedy.best().is_some());
+    let approx = ApproxSearch::beam(1000).k_top_n(&cs, &Pearson, 2, 5);
+    let scores = |result: &CorrSetResult| result.sets.iter().map(|set| set.r).collect_vec();
+    assert_eq!(scores(&approx), scores(&exact));
+  }
+
+  #[test]
+  fn swaps_improve_greedy() {
+    let data = sparse_data();
+    let cs = AllocCorrSet::build(&data);
+    let fused = CorrSetFused::build(&data);
+    let exact = fused.k_set(&Pearson, 3);
+    let greedy = ApproxSearch::greedy().k_set(&cs, &Pearson, 3);
+    let swapped = ApproxSearch::greedy()
+      .with_swaps(100)
+      .k_set(&cs, &Pearson, 3);
+    let r = |result: &CorrSetResult| result.best().unwrap().r;
+    assert!(r(&greedy) <= r(&swapped));
+    assert!(r(&swapped) <= r(&exact));
+    assert!(greedy.n_evaluated < swapped.n_evaluated);
+  }
+}
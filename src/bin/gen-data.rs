@@ -1,4 +1,4 @@
-use std::env;
+use std::{env, io};
 
 use corrset::{Question, Row, User};
 use itertools::Itertools;
@@ -10,6 +10,7 @@ fn main() {
   let num_users = args.next().unwrap().parse::<usize>().unwrap();
   let num_questions = args.next().unwrap().parse::<usize>().unwrap();
   let sparsity = args.next().unwrap().parse::<f32>().unwrap();
+  let format = args.next().unwrap_or_else(|| "json".to_string());
 
   let all_qs = (0..num_questions)
     .map(|_| Uuid::new_v4().to_string())
@@ -32,5 +33,9 @@ fn main() {
     }
   }
 
-  println!("{}", serde_json::to_string(&rows).unwrap());
+  match format.as_str() {
+    "json" => println!("{}", serde_json::to_string(&rows).unwrap()),
+    "bin" => corrset::load::write_binary(&rows, io::BufWriter::new(io::stdout().lock())).unwrap(),
+    _ => panic!("unsupported output format: {format}"),
+  }
 }
@@ -1,45 +1,603 @@
+use std::{
+  fmt::Display,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use corrset::{
-  dispatch_inner_method, dispatch_outer_method, fused::CorrSetFused, CorrSetInner, CorrSetOuter,
-  Row,
+  aggregate::Weights,
+  approx::BEAM_WIDTH,
+  checkpoint::{Checkpoint, Checkpointer},
+  inner::{
+    alloc::AllocCorrSet,
+    mmap::{MappedCorrSet, Source},
+  },
+  inner_names,
+  load::{Format, LoadOptions},
+  metric, metric_names,
+  outer::{CorrSetResult, DirectSearch, InnerSearch, SearchOptions, TOP_N},
+  registry::{self, InnerTask, OuterKind, OuterTask},
+  sample::SAMPLES,
+  shard::{self, Shard, ShardOutput, Split},
+  target::Outcomes,
+  Aggregator, BuildOptions, CorrSetInner, CorrSetOuter, Dataset, Metric, Missing, Question, Target,
 };
+use itertools::Itertools;
+use serde::Serialize;
 
-fn main() {
-  let mut args = std::env::args().skip(1);
+/// The most users named when reporting those without an outcome.
+const MAX_LISTED: usize = 5;
 
-  let outer_method = args.next().unwrap();
-  let inner_method = args.next().unwrap();
-  let k = match args.next() {
-    Some(k) => k.parse::<usize>().unwrap(),
-    None => 5,
-  };
-  let kind = match args.next() {
-    Some(kind) => kind,
-    None => "large".to_string(),
-  };
+/// Finds the question sets whose total scores best predict users' overall
+/// scores.
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+  #[command(subcommand)]
+  command: Option<Command>,
+  #[command(flatten)]
+  run: RunArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Lists the names accepted by `--outer`, `--inner` and `--metric`.
+  List,
+  /// Combines the outputs of every shard of a search into its overall answer.
+  Merge {
+    #[arg(required = true)]
+    shards: Vec<PathBuf>,
+    #[arg(long, value_enum, default_value_t = Output::Table)]
+    output: Output,
+  },
+}
+
+#[derive(Args)]
+struct RunArgs {
+  /// The outer loop, or a search that replaces it. See `top list`.
+  #[arg(long, required = true)]
+  outer: Option<String>,
+  /// The inner loop. Not needed for `fused` and `bnb`.
+  #[arg(long)]
+  inner: Option<String>,
+  /// The number of questions in each set.
+  #[arg(short, default_value_t = 5)]
+  k: usize,
+  /// The rows to search.
+  #[arg(long, default_value = "data/data-large.json")]
+  data: PathBuf,
+  /// The format of the data, if not given by its extension.
+  #[arg(long)]
+  format: Option<Format>,
+  /// The score that question sets are ranked by. `point_biserial:<threshold>`
+  /// splits users by whether their total on a set is above `threshold`, 0 for
+  /// plain `point_biserial`.
+  #[arg(long, default_value = "pearson")]
+  metric: String,
+  /// What each user's total on a set is correlated against.
+  #[arg(long, value_enum, default_value_t = TargetKind::GrandTotal)]
+  target: TargetKind,
+  /// The outcomes for `--target outcome`: a CSV or TSV file with `user` and
+  /// `outcome` columns, or a JSON object from user to outcome.
+  #[arg(long)]
+  outcomes: Option<PathBuf>,
+  /// The questions totalled for `--target subset`.
+  #[arg(long, value_delimiter = ',')]
+  subset: Vec<String>,
+  /// How each user's scores on a set's questions are combined.
+  #[arg(long, value_enum, default_value_t = AggregatorKind::Sum)]
+  aggregate: AggregatorKind,
+  /// The weights for `--aggregate weighted`: a CSV or TSV file with
+  /// `question` and `weight` columns, or a JSON object from question to
+  /// weight.
+  #[arg(long)]
+  weights: Option<PathBuf>,
+  /// What to do about users who didn't answer every question of a set.
+  #[arg(long, value_enum, default_value_t = MissingKind::CompleteCase)]
+  missing: MissingKind,
+  /// The fewest of a set's questions a user must answer for `--missing
+  /// at-least`.
+  #[arg(long, value_name = "m")]
+  min_answered: Option<usize>,
+  /// Skips question sets scored by fewer users.
+  #[arg(long, default_value_t = 0)]
+  min_users: usize,
+  /// The number of question sets to report.
+  #[arg(long, default_value_t = TOP_N)]
+  top_n: usize,
+  /// The number of sets `beam` keeps at each size.
+  #[arg(long, default_value_t = BEAM_WIDTH)]
+  beam_width: usize,
+  /// The most improving swaps `greedy` and `beam` make to each set they find.
+  /// Defaults to swapping until no swap helps.
+  #[arg(long)]
+  swap_rounds: Option<usize>,
+  /// The number of random sets `sample` scores.
+  #[arg(long, default_value_t = SAMPLES)]
+  samples: usize,
+  /// The seed of the random sets `sample` scores.
+  #[arg(long, default_value_t = 0)]
+  seed: u64,
+  /// The number of threads to search with. Defaults to one per core.
+  #[arg(long)]
+  threads: Option<usize>,
+  #[arg(long, value_enum, default_value_t = Output::Table)]
+  output: Output,
+  /// Saves the progress of a `fused`, `bnb` or numbered outer's search to
+  /// this file, every minute and when it finishes.
+  #[arg(long, value_name = "PATH")]
+  checkpoint: Option<PathBuf>,
+  /// Continues a search from the file given by `--checkpoint`.
+  #[arg(long, requires = "checkpoint")]
+  resume: bool,
+  /// Searches only shard `i/m`, writing its output next to the data, named
+  /// after the search, for `merge` to combine.
+  #[arg(long, value_name = "i/m")]
+  shard: Option<Shard>,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum TargetKind {
+  /// The user's total over every question.
+  GrandTotal,
+  /// The user's total over the questions not in the set.
+  Rest,
+  /// The user's score in `--outcomes`. Users without one are skipped.
+  Outcome,
+  /// The user's total over the `--subset` questions.
+  Subset,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum AggregatorKind {
+  /// The sum of the scores.
+  Sum,
+  /// The mean of the scores.
+  Mean,
+  /// The highest score.
+  Max,
+  /// The lowest score.
+  Min,
+  /// The sum of the scores times their question's weight in `--weights`.
+  Weighted,
+  /// The sum of the scores' z-scores among their question's answers.
+  ZSum,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum MissingKind {
+  /// Leave the user out.
+  CompleteCase,
+  /// Score unanswered questions 0.
+  Zero,
+  /// Score unanswered questions their mean score.
+  Mean,
+  /// Leave the user out if they answered fewer than `--min-answered` of the
+  /// questions, and scale their sums up to the set's size otherwise.
+  AtLeast,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum Output {
+  Table,
+  Json,
+}
+
+/// Results that can be printed as a table, through their `Display`, or as
+/// JSON.
+trait Report: Serialize + Display {
+  fn print(&self, output: Output) -> Result<()> {
+    match output {
+      Output::Table => println!("{self}"),
+      Output::Json => println!("{}", serde_json::to_string_pretty(self)?),
+    }
+    Ok(())
+  }
+}
+
+impl<T: Serialize + Display> Report for T {}
+
+/// The parts of a search shared by every strategy.
+struct Run<'a> {
+  args: &'a RunArgs,
+  metric: &'a dyn Metric,
+  opts: BuildOptions,
+  search: SearchOptions,
+}
+
+impl Run<'_> {
+  fn print(&self, report: &impl Report) -> Result<()> {
+    report.print(self.args.output)
+  }
+
+  /// Loads the data, and checks the run's options against it.
+  fn load(&self) -> Result<Dataset> {
+    let args = self.args;
+    let opts = LoadOptions {
+      format: args.format,
+      ..Default::default()
+    };
+    let data = Dataset::load_with(&args.data, &opts)
+      .with_context(|| format!("loading {}", args.data.display()))?;
+    ensure_k_fits(args.k, data.questions.len())?;
+    check_options(&self.opts, &data)?;
+    Ok(data)
+  }
+
+  /// Scores every set with outer `O` and the `7_mmap` inner. The index is
+  /// cached next to the data, so only the first run pays for loading the rows
+  /// and building the index. An index built from another version of the data
+  /// file, for another target, aggregator or missing-answer policy, or by an
+  /// older version, is rebuilt.
+  fn mapped<O: CorrSetOuter>(&self) -> Result<()> {
+    let args = self.args;
+    let index_path = sidecar(&args.data, "idx");
+    let (target, aggregator, missing) = (
+      self.opts.target.to_string(),
+      self.opts.aggregator.to_string(),
+      self.opts.missing.to_string(),
+    );
+    let source = Source::of(&args.data)?;
+    let cached = MappedCorrSet::open(&index_path).ok();
+    let mut cs = match cached.filter(|cs| {
+      cs.source == source
+        && cs.target == target
+        && cs.aggregator == aggregator
+        && cs.missing == missing
+    }) {
+      Some(cs) => cs,
+      None => {
+        let data = self.load()?;
+        AllocCorrSet::build_with(&data, &self.opts).save(&index_path, source)?;
+        MappedCorrSet::open(&index_path)?
+      }
+    };
+    ensure_k_fits(args.k, cs.questions.len())?;
+    cs.min_users = self.opts.min_users;
+    let (k, n) = (args.k, args.top_n);
+    self.print(&O::new().k_top_n(&cs, self.metric, cs.combinations(k), n))
+  }
+
+  /// The checkpointer given by `--checkpoint`, if any, and the checkpoint to
+  /// resume from if `--resume` is given.
+  fn checkpointer(&self, data: &Dataset) -> Result<Option<(Checkpointer, Option<Checkpoint>)>> {
+    let args = self.args;
+    let Some(path) = &args.checkpoint else {
+      return Ok(None);
+    };
+    let checkpointer = Checkpointer::new(path, data, &args.metric).with_options(&self.opts);
+    let resume = (args.resume)
+      .then(|| checkpointer.load(args.k, args.top_n))
+      .transpose()?;
+    Ok(Some((checkpointer, resume)))
+  }
+
+  /// Prints `result`, or saves it as the output of this run's shard, which was
+  /// split from the whole search by `split`.
+  fn finish(&self, data: &Dataset, split: Split, result: &CorrSetResult) -> Result<()> {
+    let args = self.args;
+    let Some(shard) = args.shard else {
+      return self.print(result);
+    };
+    let output = ShardOutput::new(
+      data,
+      &args.metric,
+      &self.opts,
+      args.k,
+      args.top_n,
+      shard,
+      split,
+      result,
+    );
+    let path = sidecar(&args.data, &output.file_name());
+    output.save(&path)?;
+    eprintln!("wrote shard {shard} to {}", path.display());
+    Ok(())
+  }
+}
+
+/// A file kept next to the data, named after it.
+fn sidecar(data: &Path, extension: &str) -> PathBuf {
+  data.with_extension(extension)
+}
+
+/// Scores every set, or every set in the run's shard, with an outer.
+struct Exhaustive<'a, O> {
+  data: &'a Dataset,
+  run: &'a Run<'a>,
+  outer: O,
+}
+
+impl<O: CorrSetOuter> InnerTask for Exhaustive<'_, O> {
+  type Output = Result<()>;
+
+  fn run<I: CorrSetInner>(self) -> Result<()> {
+    let Exhaustive { data, run, outer } = self;
+    let (k, n) = (run.args.k, run.args.top_n);
+    let cs = I::build_with(data, &run.opts);
+    if let Some((checkpointer, resume)) = run.checkpointer(data)? {
+      let result = outer.k_top_n_checkpointed(&cs, run.metric, k, n, &checkpointer, resume)?;
+      return run.print(&result);
+    }
+    let result = match run.args.shard {
+      Some(shard) => {
+        let ranks = shard.ranks(cs.iter_qs().count(), k);
+        outer.k_top_n(&cs, run.metric, cs.combination_range(k, ranks), n)
+      }
+      None => outer.k_top_n(&cs, run.metric, cs.combinations(k), n),
+    };
+    run.finish(data, Split::Ranks, &result)
+  }
+}
+
+/// Runs a search through an inner.
+struct WithSearch<'a, S> {
+  data: &'a Dataset,
+  run: &'a Run<'a>,
+  search: S,
+}
+
+impl<S: InnerSearch> InnerTask for WithSearch<'_, S> {
+  type Output = Result<()>;
+
+  fn run<I: CorrSetInner>(self) -> Result<()> {
+    let WithSearch { data, run, search } = self;
+    let cs = I::build_with(data, &run.opts);
+    let report = search.k_top_n(&cs, run.metric, run.args.k, run.args.top_n);
+    run.print(&report)
+  }
+}
+
+/// Runs the chosen outer, with the chosen inner if it takes one.
+struct Search<'a> {
+  run: &'a Run<'a>,
+  inner: &'a str,
+}
+
+impl OuterTask for Search<'_> {
+  type Output = Result<()>;
 
-  let data = &corrset::load_rows(format!("data/data-{kind}.json")).unwrap();
+  fn run<O: CorrSetOuter>(self) -> Result<()> {
+    let Search { run, inner } = self;
+    if inner == "7_mmap" {
+      return run.mapped::<O>();
+    }
+    let data = &run.load()?;
+    registry::run_inner(
+      inner,
+      Exhaustive {
+        data,
+        run,
+        outer: O::new(),
+      },
+    )?
+  }
 
-  fn run_outer<O: CorrSetOuter>(data: &[Row], k: usize, inner_method: &str) {
-    let outer = O::new();
-    fn run_inner<'a, I: CorrSetInner<'a>>(data: &'a [Row], k: usize, outer: impl CorrSetOuter) {
-      let cs = I::build(data);
-      let combs = cs.combinations(k);
-      println!("{:#?}", outer.k_set(&cs, combs));
+  fn run_direct<S: DirectSearch>(self) -> Result<()> {
+    let run = self.run;
+    let args = run.args;
+    let (k, n) = (args.k, args.top_n);
+    S::check_metric(run.metric)?;
+    let data = &run.load()?;
+    let cs = S::build_with(data, &run.opts);
+    if let Some(shard) = args.shard {
+      let result = cs.k_top_n_shard(run.metric, k, n, shard);
+      return run.finish(data, Split::Roots, &result);
     }
-    dispatch_inner_method!(inner_method, run_inner, data, k, outer);
+    let Some((checkpointer, resume)) = run.checkpointer(data)? else {
+      return run.print(&cs.k_top_n(run.metric, k, n));
+    };
+    run.print(&cs.k_top_n_checkpointed(run.metric, k, n, &checkpointer, resume)?)
   }
 
-  if outer_method.as_str() == "fused" {
-    let cs = CorrSetFused::build(data);
-    println!("{:#?}", cs.k_set(k));
-    return;
+  fn run_search<S: InnerSearch>(self) -> Result<()> {
+    let Search { run, inner } = self;
+    let data = &run.load()?;
+    let search = S::new(&run.search);
+    registry::run_inner(inner, WithSearch { data, run, search })?
   }
+}
 
-  dispatch_outer_method!(
-    outer_method.as_str(),
-    run_outer,
-    data,
-    k,
-    inner_method.as_str()
+/// Checks that a subset target's questions are in `data`, and reports the
+/// users that an outcome target leaves out and the weights that match no
+/// question.
+fn check_options(opts: &BuildOptions, data: &Dataset) -> Result<()> {
+  if let Aggregator::Weighted(weights) = &opts.aggregator {
+    let unknown = (weights.questions())
+      .filter(|q| !data.questions.contains(q))
+      .count();
+    if unknown > 0 {
+      eprintln!("ignoring {unknown} weights for questions not in the data");
+    }
+  }
+  match &opts.target {
+    Target::Subset(questions) => {
+      if let Some(q) = questions.iter().find(|q| !data.questions.contains(q)) {
+        bail!("`--subset` question `{}` isn't in the data", q.0);
+      }
+    }
+    Target::Outcome(outcomes) => {
+      let join = outcomes.join(&data.users);
+      if !join.missing.is_empty() {
+        let names = join
+          .missing
+          .iter()
+          .take(MAX_LISTED)
+          .map(|u| &u.0)
+          .join(", ");
+        let more = if join.missing.len() > MAX_LISTED {
+          ", ..."
+        } else {
+          ""
+        };
+        eprintln!(
+          "skipping {} of {} users without an outcome: {names}{more}",
+          join.missing.len(),
+          data.users.len()
+        );
+      }
+      if !join.unknown.is_empty() {
+        eprintln!(
+          "ignoring {} outcomes for users not in the data",
+          join.unknown.len()
+        );
+      }
+      ensure!(
+        join.missing.len() < data.users.len(),
+        "no user in the data has an outcome"
+      );
+    }
+    Target::GrandTotal | Target::Rest => {}
+  }
+  Ok(())
+}
+
+fn ensure_k_fits(k: usize, num_questions: usize) -> Result<()> {
+  ensure!(
+    k <= num_questions,
+    "`-k` is {k}, but the data only has {num_questions} questions"
   );
+  Ok(())
+}
+
+fn list() {
+  println!("outers:");
+  for strategy in registry::outers::<()>() {
+    println!("  {:<12} {}", strategy.name, strategy.description);
+  }
+  println!("inners:");
+  for strategy in registry::inners::<()>() {
+    println!("  {:<12} {}", strategy.name, strategy.description);
+  }
+  println!("metrics: {}", metric_names().join(", "));
+}
+
+fn search(args: &RunArgs) -> Result<()> {
+  let outer = args.outer.as_deref().unwrap_or_default();
+  let Some(kind) = registry::outer_kind(outer) else {
+    bail!("unknown outer `{outer}`; see `top list`");
+  };
+  let inner = match (&args.inner, kind) {
+    (Some(inner), _) => inner.as_str(),
+    (None, OuterKind::Direct) => "6_alloc",
+    (None, _) => bail!("the `{outer}` outer needs an `--inner`; see `top list`"),
+  };
+  ensure!(
+    inner_names().contains(&inner),
+    "unknown inner `{inner}`; see `top list`"
+  );
+  let Some(metric) = metric::from_name(&args.metric) else {
+    bail!("unknown metric `{}`; see `top list`", args.metric);
+  };
+  ensure!(args.k > 0, "`-k` must be at least 1");
+  ensure!(args.beam_width > 0, "`--beam-width` must be at least 1");
+  let target = match args.target {
+    TargetKind::GrandTotal => Target::GrandTotal,
+    TargetKind::Rest => Target::Rest,
+    TargetKind::Outcome => {
+      let Some(path) = &args.outcomes else {
+        bail!("`--target outcome` needs `--outcomes`");
+      };
+      Target::Outcome(Arc::new(Outcomes::load(path)?))
+    }
+    TargetKind::Subset => {
+      ensure!(
+        !args.subset.is_empty(),
+        "`--target subset` needs `--subset`"
+      );
+      Target::Subset(args.subset.iter().cloned().map(Question).collect())
+    }
+  };
+  let aggregator = match args.aggregate {
+    AggregatorKind::Sum => Aggregator::Sum,
+    AggregatorKind::Mean => Aggregator::Mean,
+    AggregatorKind::Max => Aggregator::Max,
+    AggregatorKind::Min => Aggregator::Min,
+    AggregatorKind::Weighted => {
+      let Some(path) = &args.weights else {
+        bail!("`--aggregate weighted` needs `--weights`");
+      };
+      Aggregator::Weighted(Arc::new(Weights::load(path)?))
+    }
+    AggregatorKind::ZSum => Aggregator::ZSum,
+  };
+  let missing = match args.missing {
+    MissingKind::CompleteCase => Missing::CompleteCase,
+    MissingKind::Zero => Missing::Zero,
+    MissingKind::Mean => Missing::Mean,
+    MissingKind::AtLeast => {
+      let Some(m) = args.min_answered else {
+        bail!("`--missing at-least` needs `--min-answered`");
+      };
+      ensure!(
+        (1..=args.k).contains(&m),
+        "`--min-answered` must be between 1 and `-k`"
+      );
+      Missing::AtLeast(m)
+    }
+  };
+  if args.shard.is_some() {
+    ensure!(
+      kind != OuterKind::Search,
+      "the `{outer}` outer can't run a single shard"
+    );
+    ensure!(
+      kind != OuterKind::Exhaustive || inner != "7_mmap",
+      "the `7_mmap` inner can't run a single shard"
+    );
+    ensure!(
+      args.checkpoint.is_none(),
+      "a single shard can't be checkpointed"
+    );
+  }
+  if args.checkpoint.is_some() {
+    ensure!(
+      kind != OuterKind::Search,
+      "the `{outer}` outer can't be checkpointed"
+    );
+    ensure!(
+      kind != OuterKind::Exhaustive || inner != "7_mmap",
+      "the `7_mmap` inner can't be checkpointed"
+    );
+  }
+  if let Some(threads) = args.threads {
+    rayon::ThreadPoolBuilder::new()
+      .num_threads(threads)
+      .build_global()?;
+  }
+
+  let run = &Run {
+    args,
+    metric: &*metric,
+    opts: BuildOptions {
+      target,
+      aggregator,
+      missing,
+      min_users: args.min_users,
+    },
+    search: SearchOptions {
+      beam_width: args.beam_width,
+      swap_rounds: args.swap_rounds.unwrap_or(usize::MAX),
+      samples: args.samples,
+      seed: args.seed,
+    },
+  };
+  registry::run_outer(outer, Search { run, inner })?
+}
+
+fn main() -> Result<()> {
+  let cli = Cli::parse();
+  match cli.command {
+    Some(Command::List) => {
+      list();
+      Ok(())
+    }
+    Some(Command::Merge { shards, output }) => {
+      let outputs = shards.iter().map(ShardOutput::load).try_collect()?;
+      shard::merge(outputs)?.print(output)
+    }
+    None => search(&cli.run),
+  }
 }
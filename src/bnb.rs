@@ -0,0 +1,346 @@
+//! An exact search that skips whole subtrees of question sets.
+//!
+//! Sets are enumerated depth-first in the same order as
+//! [`CorrSetFused`](crate::fused::CorrSetFused), intersecting user sets one
+//! question at a time. At each prefix, the metric's [`Metric::upper_bound`]
+//! bounds the score of every completion from the number of users who answered
+//! the prefix, since adding questions only removes users. If the bound can't
+//! beat the `n`-th best score found so far by any thread, the completions are
+//! counted as pruned and never scored. The completions of a prefix answered by
+//! fewer than [`BuildOptions::min_users`] users are skipped too, but counted as
+//! NaN, as every search counts such sets. When missing answers are imputed,
+//! adding questions adds users instead, so nothing is pruned.
+//!
+//! Only metrics whose bound tightens as users are left out, like
+//! [`MutualInformation`](crate::metric::MutualInformation), have one, so only
+//! they are accepted by [`DirectSearch::check_metric`]. Under any other metric
+//! this scores every set [`CorrSetFused`](crate::fused::CorrSetFused) does.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{ensure, Result};
+use itertools::Itertools;
+use rayon::prelude::*;
+
+use crate::{
+  checkpoint::{Checkpoint, Checkpointer},
+  dataset::QuestionIdx,
+  inner::alloc::{AllocCorrSet, UserSet},
+  metric::Metric,
+  outer::{self, CorrSetResult, DirectSearch},
+  shard::Shard,
+  utils::{choose, TopN},
+  BuildOptions, CorrSetInner, Dataset,
+};
+
+pub struct CorrSetBranchBound {
+  pub(crate) inner: AllocCorrSet,
+}
+
+/// The score a set must reach to make the top `n`, shared across threads so
+/// that a good set found under one root prunes the others.
+struct Threshold(AtomicU64);
+
+impl Threshold {
+  fn new() -> Self {
+    Threshold(AtomicU64::new(f64::NEG_INFINITY.to_bits()))
+  }
+
+  #[inline]
+  fn get(&self) -> f64 {
+    f64::from_bits(self.0.load(Ordering::Relaxed))
+  }
+
+  #[inline]
+  fn raise(&self, r: f64) {
+    let _ = self
+      .0
+      .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+        (r > f64::from_bits(old)).then(|| r.to_bits())
+      });
+  }
+}
+
+struct Scratch {
+  qs: Vec<QuestionIdx>,
+  /// The users who answered the first `i + 1` questions of `qs`, for each `i`,
+  /// or any of them when missing answers are imputed.
+  sets: Vec<UserSet>,
+  qs_scores: Vec<f64>,
+  target_scores: Vec<f64>,
+}
+
+struct Search<'a, M: ?Sized> {
+  inner: &'a AllocCorrSet,
+  metric: &'a M,
+  bound: Option<fn(usize) -> f64>,
+  k: usize,
+  threshold: &'a Threshold,
+}
+
+impl<'a, M: Metric + ?Sized> Search<'a, M> {
+  fn new(inner: &'a AllocCorrSet, metric: &'a M, k: usize, threshold: &'a Threshold) -> Self {
+    Search {
+      inner,
+      metric,
+      bound: metric.upper_bound(),
+      k,
+      threshold,
+    }
+  }
+
+  fn init_scratch(&self) -> Scratch {
+    Scratch {
+      qs: Vec::with_capacity(self.k),
+      sets: vec![UserSet::new(&self.inner.users); self.k],
+      qs_scores: Vec::new(),
+      target_scores: Vec::new(),
+    }
+  }
+
+  /// Whether any completion of a prefix answered by `n_users` users might
+  /// make the top. When missing answers are imputed, adding questions can add
+  /// users, so every prefix is viable, as is every prefix under a metric
+  /// without a bound.
+  #[inline]
+  fn viable(&self, n_users: usize) -> bool {
+    match self.bound {
+      Some(bound) if self.inner.aggregation.is_complete_case() => {
+        let bound = bound(n_users);
+        bound > f64::NEG_INFINITY && bound >= self.threshold.get()
+      }
+      _ => true,
+    }
+  }
+
+  /// Appends `q` to the prefix in `s`, then scores or prunes every completion.
+  fn visit(&self, s: &mut Scratch, top: &mut TopN<QuestionIdx>, q: QuestionIdx) {
+    let depth = s.qs.len();
+    let (prev, rest) = s.sets.split_at_mut(depth);
+    let users = &mut rest[0];
+    match prev.last() {
+      Some(prev) => {
+        users.clone_from(prev);
+        self.inner.add_users(users, q);
+      }
+      None => self.inner.init_users(users, q),
+    }
+    s.qs.push(q);
+
+    let num_qs = self.inner.questions.len();
+    let remaining = self.k - depth - 1;
+    if remaining == 0 {
+      let corr = self.inner.corr_set_score(
+        self.metric,
+        &mut s.qs_scores,
+        &mut s.target_scores,
+        &s.sets[depth],
+        &s.qs,
+      );
+      top.push(s.qs.clone(), corr);
+      if let Some(r) = top.threshold() {
+        self.threshold.raise(r);
+      }
+    } else if self.inner.too_few_users(&s.sets[depth]) {
+      top.push_too_few(choose(num_qs - q.index() - 1, remaining));
+    } else if self.viable(s.sets[depth].len()) {
+      for next in q.index() + 1..=num_qs - remaining {
+        self.visit(s, top, QuestionIdx::from_usize(next));
+      }
+    } else {
+      top.n_pruned += choose(num_qs - q.index() - 1, remaining);
+    }
+
+    s.qs.pop();
+  }
+}
+
+impl CorrSetBranchBound {
+  /// The roots of every set, i.e. the questions that can come first.
+  fn roots(&self, k: usize) -> impl Iterator<Item = QuestionIdx> + Send + '_ {
+    let num_roots = (self.inner.questions.len() + 1).saturating_sub(k);
+    self.inner.questions.indices().take(num_roots)
+  }
+
+  fn top_of_roots<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    k: usize,
+    n: usize,
+    roots: Vec<QuestionIdx>,
+  ) -> TopN<QuestionIdx> {
+    let threshold = Threshold::new();
+    let search = Search::new(&self.inner, metric, k, &threshold);
+    roots
+      .into_par_iter()
+      .fold(
+        || (search.init_scratch(), TopN::new(n)),
+        |(mut scratch, mut top), root| {
+          search.visit(&mut scratch, &mut top, root);
+          (scratch, top)
+        },
+      )
+      .map(|(_, top)| top)
+      .reduce(|| TopN::new(n), TopN::merge)
+  }
+}
+
+impl DirectSearch for CorrSetBranchBound {
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    CorrSetBranchBound {
+      inner: AllocCorrSet::build_with(data, opts),
+    }
+  }
+
+  /// Pruning needs a bound on the metric's scores.
+  fn check_metric<M: Metric + ?Sized>(metric: &M) -> Result<()> {
+    ensure!(
+      metric.upper_bound().is_some(),
+      "`bnb` only skips sets under a metric with a bound on its score, like `mutual_info`; \
+       use `fused` instead"
+    );
+    Ok(())
+  }
+
+  /// The scores are the same as an exhaustive search's.
+  fn k_top_n<M: Metric + ?Sized>(&self, metric: &M, k: usize, n: usize) -> CorrSetResult<'_> {
+    let top = self.top_of_roots(metric, k, n, self.roots(k).collect_vec());
+    outer::resolve(&self.inner, top)
+  }
+
+  fn k_top_n_shard<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    k: usize,
+    n: usize,
+    shard: Shard,
+  ) -> CorrSetResult<'_> {
+    let roots = self
+      .roots(k)
+      .filter(|root| shard.has_root(root.index()))
+      .collect_vec();
+    let top = self.top_of_roots(metric, k, n, roots);
+    outer::resolve(&self.inner, top)
+  }
+
+  /// The sets kept by the checkpoint to `resume` from prune the rest of the
+  /// search from the start.
+  fn k_top_n_checkpointed<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    k: usize,
+    n: usize,
+    checkpointer: &Checkpointer,
+    resume: Option<Checkpoint>,
+  ) -> Result<CorrSetResult<'_>> {
+    let threshold = Threshold::new();
+    if let Some(r) = resume.as_ref().and_then(|c| c.top().threshold()) {
+      threshold.raise(r);
+    }
+    let search = Search::new(&self.inner, metric, k, &threshold);
+    let top = checkpointer.search_roots(
+      k,
+      n,
+      self.roots(k),
+      resume,
+      || search.init_scratch(),
+      |scratch, root| {
+        let mut top = TopN::new(n);
+        search.visit(scratch, &mut top, root);
+        top
+      },
+    )?;
+    Ok(outer::resolve(&self.inner, top))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    fused::CorrSetFused,
+    inner::test_utils::{mock_data, sparse_data},
+    metric::{MutualInformation, Pearson},
+  };
+
+  fn check<M: Metric>(data: &Dataset, metric: &M, k: usize, n: usize) -> usize {
+    let bnb = CorrSetBranchBound::build(data);
+    let fused = CorrSetFused::build(data);
+    let (actual, expected) = (bnb.k_top_n(metric, k, n), fused.k_top_n(metric, k, n));
+    let scores = |result: &CorrSetResult| result.sets.iter().map(|set| set.r).collect::<Vec<_>>();
+    assert_eq!(scores(&actual), scores(&expected));
+    assert_eq!(
+      actual.n_evaluated + actual.n_pruned,
+      choose(data.questions.len(), k)
+    );
+    actual.n_pruned
+  }
+
+  #[test]
+  fn same_optimum_as_fused() {
+    check(&mock_data(), &Pearson, 2, 3);
+    let data = sparse_data();
+    for k in 2..=4 {
+      check(&data, &Pearson, k, 5);
+      check(&data, &MutualInformation, k, 5);
+    }
+    assert!(check(&data, &MutualInformation, 3, 5) > 0);
+    assert_eq!(check(&data, &Pearson, 3, 5), 0);
+  }
+
+  #[test]
+  fn needs_a_bound() {
+    assert!(CorrSetBranchBound::check_metric(&MutualInformation).is_ok());
+    assert!(CorrSetBranchBound::check_metric(&Pearson).is_err());
+  }
+
+  #[test]
+  fn shards_and_checkpoints() {
+    let data = sparse_data();
+    let bnb = CorrSetBranchBound::build(&data);
+    let scores = |result: &CorrSetResult| result.sets.iter().map(|set| set.r).collect_vec();
+    let whole = bnb.k_top_n(&Pearson, 3, 5);
+
+    let shards = (0..3)
+      .map(|index| bnb.k_top_n_shard(&Pearson, 3, 5, Shard { index, count: 3 }))
+      .collect_vec();
+    let mut merged = shards.iter().flat_map(scores).collect_vec();
+    merged.sort_by(|a, b| b.total_cmp(a));
+    merged.truncate(5);
+    assert_eq!(merged, scores(&whole));
+    let total = (shards.iter())
+      .map(|shard| shard.n_evaluated + shard.n_pruned)
+      .sum::<usize>();
+    assert_eq!(total, choose(data.questions.len(), 3));
+
+    let path = std::env::temp_dir().join(format!("corrset-bnb-{}.ckpt", std::process::id()));
+    let checkpointer = Checkpointer::new(&path, &data, "pearson");
+    let saved = bnb
+      .k_top_n_checkpointed(&Pearson, 3, 5, &checkpointer, None)
+      .unwrap();
+    let checkpoint = checkpointer.load(3, 5).unwrap();
+    let resumed = bnb
+      .k_top_n_checkpointed(&Pearson, 3, 5, &checkpointer, Some(checkpoint))
+      .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(scores(&saved), scores(&whole));
+    assert_eq!(scores(&resumed), scores(&whole));
+  }
+
+  #[test]
+  fn min_users_count_as_nan() {
+    let data = sparse_data();
+    let opts = BuildOptions {
+      min_users: 3,
+      ..Default::default()
+    };
+    let bnb = CorrSetBranchBound::build_with(&data, &opts);
+    let fused = CorrSetFused::build_with(&data, &opts);
+    for k in 2..=4 {
+      let (actual, expected) = (bnb.k_top_n(&Pearson, k, 5), fused.k_top_n(&Pearson, k, 5));
+      assert_eq!(actual.sets.len(), expected.sets.len());
+      assert_eq!(actual.n_nan + actual.n_pruned, expected.n_nan);
+      assert_eq!(actual.n_evaluated + actual.n_pruned, expected.n_evaluated);
+    }
+  }
+}
@@ -0,0 +1,573 @@
+//! Saving the progress of a long exhaustive search, so that it can be resumed
+//! after being interrupted.
+//!
+//! A [`Checkpoint`] records which roots (first questions) of the search have
+//! been fully scored, or for the [`CorrSetOuter`]s, up to which rank among the
+//! [`combinations`](crate::combinations) every set has been scored, along with
+//! the best sets found so far. Question ids are only meaningful for the
+//! dataset they were assigned from, so a checkpoint carries the
+//! [`Dataset::fingerprint`] and is refused for any other dataset.
+
+use std::{
+  fs, mem,
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
+use indicatif::ProgressIterator;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  dataset::QuestionIdx,
+  inner::Corr,
+  metric::Metric,
+  outer::{CorrSetResult, KSet},
+  shard::Split,
+  target::Target,
+  utils::{choose, pb_style, TopN},
+  Aggregator, BuildOptions, CorrSetInner, CorrSetOuter, Dataset, Missing,
+};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The number of sets a [`CorrSetOuter`] scores between chances to save.
+const RANKS_PER_STEP: usize = 1 << 20;
+
+/// A question set kept by a search, by question id.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SavedSet {
+  pub questions: Vec<usize>,
+  pub r: f64,
+  pub n_users: usize,
+}
+
+/// The state of a search for the best `n` sets of size `k`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+  pub fingerprint: u64,
+  pub metric: String,
+  /// The [`Target`], as written by its `Display`.
+  #[serde(default = "grand_total")]
+  pub target: String,
+  /// The [`Aggregator`](crate::Aggregator), as written by its `Display`.
+  #[serde(default = "sum")]
+  pub aggregator: String,
+  /// The [`Missing`](crate::Missing) policy, as written by its `Display`.
+  #[serde(default = "complete_case")]
+  pub missing: String,
+  /// The [`BuildOptions::min_users`] threshold.
+  #[serde(default)]
+  pub min_users: usize,
+  pub k: usize,
+  pub n: usize,
+  /// Whether the search saved the roots it completed or the rank it reached.
+  #[serde(default = "roots")]
+  pub split: Split,
+  /// The roots whose sets have all been scored, in order of completion.
+  #[serde(default)]
+  pub completed: Vec<usize>,
+  /// Every set ranked below this has been scored.
+  #[serde(default)]
+  pub rank: usize,
+  /// The best sets under the completed roots, best first.
+  pub sets: Vec<SavedSet>,
+  pub n_evaluated: usize,
+  pub n_nan: usize,
+  pub n_pruned: usize,
+}
+
+/// The target of checkpoints saved before targets could be chosen.
+pub(crate) fn grand_total() -> String {
+  Target::GrandTotal.to_string()
+}
+
+/// The aggregator of checkpoints saved before aggregators could be chosen.
+pub(crate) fn sum() -> String {
+  Aggregator::Sum.to_string()
+}
+
+/// The missing-answer policy of checkpoints saved before it could be chosen.
+pub(crate) fn complete_case() -> String {
+  Missing::CompleteCase.to_string()
+}
+
+/// The split of checkpoints saved before searches could be split by rank.
+fn roots() -> Split {
+  Split::Roots
+}
+
+/// The best `n` of `sets`, with the counts of the search that found them.
+fn kept(
+  n: usize,
+  sets: impl Iterator<Item = (Vec<usize>, Corr)>,
+  [n_evaluated, n_nan, n_pruned]: [usize; 3],
+) -> TopN<QuestionIdx> {
+  let mut top = TopN::new(n);
+  for (qs, corr) in sets {
+    top.push(qs.into_iter().map(QuestionIdx::from_usize).collect(), corr);
+  }
+  top.n_evaluated = n_evaluated;
+  top.n_nan = n_nan;
+  top.n_pruned = n_pruned;
+  top
+}
+
+impl Checkpoint {
+  /// The best sets and counts saved, to continue the search from.
+  pub(crate) fn top(&self) -> TopN<QuestionIdx> {
+    let sets = self.sets.iter().map(|set| {
+      let corr = Corr {
+        r: set.r,
+        n_users: set.n_users,
+      };
+      (set.questions.clone(), corr)
+    });
+    kept(self.n, sets, [self.n_evaluated, self.n_nan, self.n_pruned])
+  }
+}
+
+/// Where and how often a search saves its [`Checkpoint`]s.
+pub struct Checkpointer {
+  pub path: PathBuf,
+  /// The minimum time between saves while the search runs.
+  pub interval: Duration,
+  fingerprint: u64,
+  metric: String,
+  target: String,
+  aggregator: String,
+  missing: String,
+  min_users: usize,
+}
+
+impl Checkpointer {
+  /// Checkpoints a search over `data` under the metric named `metric`, with
+  /// the default [`BuildOptions`].
+  pub fn new(path: impl AsRef<Path>, data: &Dataset, metric: &str) -> Self {
+    Checkpointer {
+      path: path.as_ref().to_path_buf(),
+      interval: DEFAULT_INTERVAL,
+      fingerprint: data.fingerprint(),
+      metric: metric.to_string(),
+      target: grand_total(),
+      aggregator: sum(),
+      missing: complete_case(),
+      min_users: 0,
+    }
+  }
+
+  pub fn with_interval(self, interval: Duration) -> Self {
+    Checkpointer { interval, ..self }
+  }
+
+  pub fn with_options(self, opts: &BuildOptions) -> Self {
+    Checkpointer {
+      target: opts.target.to_string(),
+      aggregator: opts.aggregator.to_string(),
+      missing: opts.missing.to_string(),
+      min_users: opts.min_users,
+      ..self
+    }
+  }
+
+  /// Reads the checkpoint at `path`, checking that it was saved by the same
+  /// search: the same dataset, metric, target, aggregator, missing-answer
+  /// policy, minimum number of users, `k` and `n`.
+  pub fn load(&self, k: usize, n: usize) -> Result<Checkpoint> {
+    let contents = fs::read_to_string(&self.path)
+      .with_context(|| format!("reading checkpoint {}", self.path.display()))?;
+    let checkpoint: Checkpoint = serde_json::from_str(&contents)
+      .with_context(|| format!("parsing checkpoint {}", self.path.display()))?;
+    ensure!(
+      checkpoint.fingerprint == self.fingerprint,
+      "checkpoint {} was saved for a different dataset",
+      self.path.display()
+    );
+    ensure!(
+      checkpoint.metric == self.metric,
+      "checkpoint {} was saved for metric `{}`, not `{}`",
+      self.path.display(),
+      checkpoint.metric,
+      self.metric
+    );
+    ensure!(
+      checkpoint.target == self.target,
+      "checkpoint {} was saved for target `{}`, not `{}`",
+      self.path.display(),
+      checkpoint.target,
+      self.target
+    );
+    ensure!(
+      checkpoint.aggregator == self.aggregator,
+      "checkpoint {} was saved for aggregator `{}`, not `{}`",
+      self.path.display(),
+      checkpoint.aggregator,
+      self.aggregator
+    );
+    ensure!(
+      checkpoint.missing == self.missing,
+      "checkpoint {} was saved for missing answers `{}`, not `{}`",
+      self.path.display(),
+      checkpoint.missing,
+      self.missing
+    );
+    ensure!(
+      checkpoint.min_users == self.min_users,
+      "checkpoint {} was saved for at least {} users per set, not {}",
+      self.path.display(),
+      checkpoint.min_users,
+      self.min_users
+    );
+    ensure!(
+      (checkpoint.k, checkpoint.n) == (k, n),
+      "checkpoint {} was saved for k = {} and n = {}, not k = {k} and n = {n}",
+      self.path.display(),
+      checkpoint.k,
+      checkpoint.n
+    );
+    Ok(checkpoint)
+  }
+
+  /// Checks that `checkpoint` was saved by a search split by `split`.
+  fn check_split(&self, checkpoint: &Checkpoint, split: Split) -> Result<()> {
+    ensure!(
+      checkpoint.split == split,
+      "checkpoint {} was saved by a search split by {}, not by {split}",
+      self.path.display(),
+      checkpoint.split
+    );
+    Ok(())
+  }
+
+  /// A checkpoint of `top`, with no progress recorded.
+  fn checkpoint(&self, k: usize, top: &TopN<QuestionIdx>) -> Checkpoint {
+    Checkpoint {
+      fingerprint: self.fingerprint,
+      metric: self.metric.clone(),
+      target: self.target.clone(),
+      aggregator: self.aggregator.clone(),
+      missing: self.missing.clone(),
+      min_users: self.min_users,
+      k,
+      n: top.n(),
+      split: Split::Roots,
+      completed: Vec::new(),
+      rank: 0,
+      sets: top
+        .clone()
+        .into_sorted_vec()
+        .into_iter()
+        .map(|(qs, corr)| SavedSet {
+          questions: qs.into_iter().map(|q| q.index()).collect(),
+          r: corr.r,
+          n_users: corr.n_users,
+        })
+        .collect(),
+      n_evaluated: top.n_evaluated,
+      n_nan: top.n_nan,
+      n_pruned: top.n_pruned,
+    }
+  }
+
+  /// Writes a checkpoint of `top` and the `completed` roots.
+  pub(crate) fn save(&self, k: usize, completed: &[usize], top: &TopN<QuestionIdx>) -> Result<()> {
+    self.write(&Checkpoint {
+      completed: completed.to_vec(),
+      ..self.checkpoint(k, top)
+    })
+  }
+
+  /// Writes a checkpoint of `top`, found among the sets ranked below `rank`.
+  pub(crate) fn save_rank(&self, k: usize, rank: usize, top: &TopN<QuestionIdx>) -> Result<()> {
+    self.write(&Checkpoint {
+      split: Split::Ranks,
+      rank,
+      ..self.checkpoint(k, top)
+    })
+  }
+
+  /// Writes `checkpoint` whole and then renames it into place, so an
+  /// interrupted save leaves the previous checkpoint intact.
+  fn write(&self, checkpoint: &Checkpoint) -> Result<()> {
+    let tmp = self.path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string(checkpoint)?)
+      .with_context(|| format!("writing checkpoint {}", tmp.display()))?;
+    fs::rename(&tmp, &self.path)
+      .with_context(|| format!("writing checkpoint {}", self.path.display()))?;
+    Ok(())
+  }
+
+  /// Merges the best `n` sets of size `k` under each of `roots`, found on
+  /// every thread by `root_top`, saving progress as roots complete and once
+  /// more at the end. Given a checkpoint to `resume` from, starts from its
+  /// sets and skips the roots it completed.
+  pub(crate) fn search_roots<S>(
+    &self,
+    k: usize,
+    n: usize,
+    roots: impl Iterator<Item = QuestionIdx>,
+    resume: Option<Checkpoint>,
+    init_scratch: impl Fn() -> S + Send + Sync,
+    root_top: impl Fn(&mut S, QuestionIdx) -> TopN<QuestionIdx> + Send + Sync,
+  ) -> Result<TopN<QuestionIdx>> {
+    let (top, completed) = match resume {
+      Some(checkpoint) => {
+        self.check_split(&checkpoint, Split::Roots)?;
+        (checkpoint.top(), checkpoint.completed)
+      }
+      None => (TopN::new(n), Vec::new()),
+    };
+    let done = completed.iter().copied().collect::<HashSet<_>>();
+    let roots = roots
+      .filter(|root| !done.contains(&root.index()))
+      .collect_vec();
+    let state = Mutex::new((top, completed, Instant::now()));
+    roots
+      .into_iter()
+      .progress_with_style(pb_style())
+      .par_bridge()
+      .try_for_each_init(init_scratch, |scratch, root| {
+        let root_top = root_top(scratch, root);
+        let (top, completed, last_save) = &mut *state.lock().unwrap();
+        *top = mem::replace(top, TopN::new(n)).merge(root_top);
+        completed.push(root.index());
+        if last_save.elapsed() >= self.interval {
+          self.save(k, completed, top)?;
+          *last_save = Instant::now();
+        }
+        anyhow::Ok(())
+      })?;
+    let (top, completed, _) = state.into_inner().unwrap();
+    self.save(k, &completed, &top)?;
+    Ok(top)
+  }
+
+  /// Scores every set of `k` questions in `corrset` with `outer`, in order of
+  /// rank, saving the rank reached and the best `n` sets so far between steps
+  /// and once more at the end. Given a checkpoint to `resume` from, starts
+  /// from its sets and the rank it reached.
+  pub(crate) fn search_ranks<'a, O, T, M>(
+    &self,
+    outer: &O,
+    corrset: &'a T,
+    metric: &M,
+    k: usize,
+    n: usize,
+    resume: Option<Checkpoint>,
+  ) -> Result<CorrSetResult<'a>>
+  where
+    O: CorrSetOuter + ?Sized,
+    T: CorrSetInner,
+    M: Metric + ?Sized,
+  {
+    // Questions are saved by their position in `iter_qs`, which ranks follow.
+    let questions = (corrset.iter_qs())
+      .map(|q| corrset.to_question(q))
+      .collect_vec();
+    let ids = (questions.iter().enumerate())
+      .map(|(id, q)| (*q, id))
+      .collect::<HashMap<_, _>>();
+    let (mut top, start) = match resume {
+      Some(checkpoint) => {
+        self.check_split(&checkpoint, Split::Ranks)?;
+        (checkpoint.top(), checkpoint.rank)
+      }
+      None => (TopN::new(n), 0),
+    };
+    let total = choose(questions.len(), k);
+    let mut last_save = Instant::now();
+    for step in (start..total)
+      .step_by(RANKS_PER_STEP)
+      .progress_with_style(pb_style())
+    {
+      let end = (step + RANKS_PER_STEP).min(total);
+      let result = outer.k_top_n(corrset, metric, corrset.combination_range(k, step..end), n);
+      let sets = result.sets.iter().map(|set| {
+        let corr = Corr {
+          r: set.r,
+          n_users: set.n_users,
+        };
+        (set.questions.iter().map(|q| ids[q]).collect(), corr)
+      });
+      let counts = [result.n_evaluated, result.n_nan, result.n_pruned];
+      top = top.merge(kept(n, sets, counts));
+      if last_save.elapsed() >= self.interval {
+        self.save_rank(k, end, &top)?;
+        last_save = Instant::now();
+      }
+    }
+    self.save_rank(k, total, &top)?;
+
+    let (n_evaluated, n_nan, n_pruned) = (top.n_evaluated, top.n_nan, top.n_pruned);
+    let sets = (top.into_sorted_vec().into_iter())
+      .map(|(qs, corr)| KSet {
+        questions: qs.into_iter().map(|q| questions[q.index()]).collect(),
+        r: corr.r,
+        n_users: corr.n_users,
+      })
+      .collect();
+    Ok(CorrSetResult {
+      sets,
+      n_evaluated,
+      n_nan,
+      n_pruned,
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    fused::CorrSetFused,
+    inner::{
+      alloc::AllocCorrSet,
+      test_utils::{mock_data, sparse_data},
+    },
+    metric::Pearson,
+    outer::{parallel::CorrSetParallel, CorrSetResult, DirectSearch},
+    CorrSetInner,
+  };
+
+  fn temp_path() -> PathBuf {
+    std::env::temp_dir().join(format!("{}.ckpt", uuid::Uuid::new_v4()))
+  }
+
+  fn scores(result: &CorrSetResult) -> Vec<f64> {
+    result.sets.iter().map(|set| set.r).collect()
+  }
+
+  #[test]
+  fn resume() {
+    let data = sparse_data();
+    let cs = CorrSetFused::build(&data);
+    let expected = cs.k_top_n(&Pearson, 3, 5);
+    let path = temp_path();
+    let checkpointer = Checkpointer::new(&path, &data, "pearson");
+
+    let full = cs
+      .k_top_n_checkpointed(&Pearson, 3, 5, &checkpointer, None)
+      .unwrap();
+    assert_eq!(scores(&full), scores(&expected));
+    let saved = checkpointer.load(3, 5).unwrap();
+    assert_eq!(saved.completed.len(), data.questions.len() - 2);
+    assert_eq!(saved.n_evaluated, expected.n_evaluated);
+
+    // Save a search that has only finished the first root, then resume it.
+    let root = QuestionIdx::from_usize(0);
+    let mut scratch = cs.inner.init_scratch();
+    let top = cs.root_top(&Pearson, root, 3, 5, &mut scratch);
+    checkpointer.save(3, &[0], &top).unwrap();
+    let partial = checkpointer.load(3, 5).unwrap();
+    let resumed = cs
+      .k_top_n_checkpointed(&Pearson, 3, 5, &checkpointer, Some(partial))
+      .unwrap();
+    assert_eq!(scores(&resumed), scores(&expected));
+    assert_eq!(resumed.n_evaluated, expected.n_evaluated);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn resume_by_rank() {
+    let data = sparse_data();
+    let cs = AllocCorrSet::build(&data);
+    let outer = CorrSetParallel::new();
+    let expected = outer.k_top_n(&cs, &Pearson, cs.combinations(3), 5);
+    let path = temp_path();
+    let checkpointer = Checkpointer::new(&path, &data, "pearson");
+
+    let full = outer
+      .k_top_n_checkpointed(&cs, &Pearson, 3, 5, &checkpointer, None)
+      .unwrap();
+    assert_eq!(scores(&full), scores(&expected));
+    let saved = checkpointer.load(3, 5).unwrap();
+    assert_eq!(saved.rank, choose(data.questions.len(), 3));
+    assert_eq!(saved.n_evaluated, expected.n_evaluated);
+
+    // Save a search that has only scored the first 50 sets, then resume it.
+    let mut top = TopN::new(5);
+    let mut scratch = cs.init_scratch();
+    for qs in cs.combination_range(3, 0..50) {
+      let corr = cs.corr_set(&Pearson, &mut scratch, &qs);
+      top.push(qs, corr);
+    }
+    checkpointer.save_rank(3, 50, &top).unwrap();
+    let partial = checkpointer.load(3, 5).unwrap();
+    let resumed = outer
+      .k_top_n_checkpointed(&cs, &Pearson, 3, 5, &checkpointer, Some(partial))
+      .unwrap();
+    assert_eq!(scores(&resumed), scores(&expected));
+    assert_eq!(resumed.n_evaluated, expected.n_evaluated);
+
+    // A checkpoint of completed roots can't be resumed by rank, or vice versa.
+    let by_rank = checkpointer.load(3, 5).unwrap();
+    let fused = CorrSetFused::build(&data);
+    assert!(fused
+      .k_top_n_checkpointed(&Pearson, 3, 5, &checkpointer, Some(by_rank))
+      .is_err());
+    checkpointer.save(3, &[0], &TopN::new(5)).unwrap();
+    let by_root = checkpointer.load(3, 5).unwrap();
+    assert!(outer
+      .k_top_n_checkpointed(&cs, &Pearson, 3, 5, &checkpointer, Some(by_root))
+      .is_err());
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn mismatch() {
+    let data = sparse_data();
+    let path = temp_path();
+    let checkpointer = Checkpointer::new(&path, &data, "pearson");
+    assert!(checkpointer.load(3, 5).is_err());
+
+    checkpointer.save(3, &[], &TopN::new(5)).unwrap();
+    assert!(checkpointer.load(3, 5).is_ok());
+    assert!(checkpointer.load(2, 5).is_err());
+    assert!(Checkpointer::new(&path, &data, "spearman")
+      .load(3, 5)
+      .is_err());
+    assert!(Checkpointer::new(&path, &mock_data(), "pearson")
+      .load(3, 5)
+      .is_err());
+    let rest = BuildOptions {
+      target: Target::Rest,
+      ..Default::default()
+    };
+    assert!(Checkpointer::new(&path, &data, "pearson")
+      .with_options(&rest)
+      .load(3, 5)
+      .is_err());
+    let mean = BuildOptions {
+      aggregator: Aggregator::Mean,
+      ..Default::default()
+    };
+    assert!(Checkpointer::new(&path, &data, "pearson")
+      .with_options(&mean)
+      .load(3, 5)
+      .is_err());
+    let zero = BuildOptions {
+      missing: Missing::Zero,
+      ..Default::default()
+    };
+    assert!(Checkpointer::new(&path, &data, "pearson")
+      .with_options(&zero)
+      .load(3, 5)
+      .is_err());
+    let min_users = BuildOptions {
+      min_users: 3,
+      ..Default::default()
+    };
+    assert!(Checkpointer::new(&path, &data, "pearson")
+      .with_options(&min_users)
+      .load(3, 5)
+      .is_err());
+
+    fs::remove_file(&path).unwrap();
+  }
+}
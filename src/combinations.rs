@@ -0,0 +1,158 @@
+//! A combinatorial number system for `k`-subsets of `0..n`.
+//!
+//! Combinations are ranked in the lexicographic order that
+//! [`Itertools::combinations`](itertools::Itertools::combinations) yields
+//! them in, so [`unrank`] can jump straight to the combination at any
+//! position. That lets a contiguous range of ranks, like a [`shard`], be
+//! enumerated independently of the others, without a shared iterator.
+//!
+//! Ranks are only meaningful while [`choose(n, k)`](choose) fits in a
+//! `usize`.
+
+use std::ops::Range;
+
+use crate::utils::choose;
+
+/// The position of the sorted combination `comb` of `0..n` in lexicographic
+/// order.
+pub fn rank(comb: &[usize], n: usize) -> usize {
+  let k = comb.len();
+  // Reflecting each element to `n - 1 - c` reverses the order, and the
+  // reflected combination's colexicographic rank is a sum of binomials.
+  let colex = comb
+    .iter()
+    .enumerate()
+    .map(|(i, c)| choose(n - 1 - c, k - i))
+    .sum::<usize>();
+  choose(n, k) - 1 - colex
+}
+
+/// The combination of `k` of `0..n` at position `rank` in lexicographic
+/// order, sorted.
+///
+/// # Panics
+///
+/// Panics if `rank` is not less than `choose(n, k)`.
+pub fn unrank(rank: usize, n: usize, k: usize) -> Vec<usize> {
+  let total = choose(n, k);
+  assert!(rank < total, "rank {rank} out of range for {n} choose {k}");
+  let mut colex = total - 1 - rank;
+  let mut x = n;
+  (0..k)
+    .map(|i| {
+      // The largest `x` with `choose(x, k - i) <= colex`, which decreases
+      // with each element.
+      x -= 1;
+      while choose(x, k - i) > colex {
+        x -= 1;
+      }
+      colex -= choose(x, k - i);
+      n - 1 - x
+    })
+    .collect()
+}
+
+/// The `i`-th of `m` contiguous, near-equal ranges of ranks that together
+/// cover all `choose(n, k)` combinations.
+pub fn shard(n: usize, k: usize, i: usize, m: usize) -> Range<usize> {
+  assert!(i < m, "shard {i} out of range for {m} shards");
+  let total = choose(n, k) as u128;
+  let bound = |j: usize| (total * j as u128 / m as u128) as usize;
+  bound(i)..bound(i + 1)
+}
+
+/// The combinations of `k` of `0..n` with ranks in a given range, in order.
+pub struct Combinations {
+  n: usize,
+  next: Vec<usize>,
+  remaining: usize,
+}
+
+impl Combinations {
+  pub fn new(n: usize, k: usize) -> Self {
+    Self::range(n, k, 0..choose(n, k))
+  }
+
+  pub fn range(n: usize, k: usize, ranks: Range<usize>) -> Self {
+    let remaining = ranks.len();
+    Combinations {
+      n,
+      next: if remaining > 0 {
+        unrank(ranks.start, n, k)
+      } else {
+        Vec::new()
+      },
+      remaining,
+    }
+  }
+}
+
+impl Iterator for Combinations {
+  type Item = Vec<usize>;
+
+  #[inline]
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.remaining == 0 {
+      return None;
+    }
+    self.remaining -= 1;
+    let comb = self.next.clone();
+    if self.remaining > 0 {
+      // Increment the last element that isn't at its maximum, and reset the
+      // ones after it to follow on from it.
+      let k = self.next.len();
+      let i = (0..k).rfind(|&i| self.next[i] < self.n - k + i).unwrap();
+      self.next[i] += 1;
+      for j in i + 1..k {
+        self.next[j] = self.next[j - 1] + 1;
+      }
+    }
+    Some(comb)
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    (self.remaining, Some(self.remaining))
+  }
+}
+
+impl ExactSizeIterator for Combinations {}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use itertools::Itertools;
+
+  #[test]
+  fn matches_itertools() {
+    for n in 0..=7 {
+      for k in 0..=n {
+        let expected = (0..n).combinations(k).collect_vec();
+        assert_eq!(Combinations::new(n, k).collect_vec(), expected);
+        for (r, comb) in expected.iter().enumerate() {
+          assert_eq!(rank(comb, n), r);
+          assert_eq!(&unrank(r, n, k), comb);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn shards_partition() {
+    let (n, k) = (10, 4);
+    let all = (0..3)
+      .flat_map(|i| Combinations::range(n, k, shard(n, k, i, 3)))
+      .collect_vec();
+    assert_eq!(all, (0..n).combinations(k).collect_vec());
+    assert_eq!(shard(n, k, 0, 3), 0..70);
+    assert!(Combinations::range(n, k, 5..5).next().is_none());
+  }
+
+  #[test]
+  fn large_ranks() {
+    let (n, k) = (200, 5);
+    let last = choose(n, k) - 1;
+    assert_eq!(unrank(last, n, k), vec![195, 196, 197, 198, 199]);
+    let comb = vec![3, 50, 51, 120, 199];
+    assert_eq!(unrank(rank(&comb, n), n, k), comb);
+  }
+}
@@ -0,0 +1,234 @@
+//! An owned, interned copy of the loaded rows that indices are built from.
+
+use std::{
+  error::Error,
+  fmt,
+  hash::{Hash, Hasher},
+  path::Path,
+  sync::Arc,
+};
+
+use fxhash::{FxHashMap as HashMap, FxHasher64};
+use indexical::{define_index_type, IndexedDomain};
+
+use crate::{
+  load::{self, LoadError, LoadOptions},
+  Question, Row, User,
+};
+
+// Questions are indexed by `u32` rather than `u16`, at the cost of larger
+// combinations, so that any dataset can be searched by the same build.
+define_index_type! {
+  pub struct QuestionIdx for Question = u32;
+  DISABLE_MAX_INDEX_CHECK = cfg!(not(debug_assertions));
+}
+
+define_index_type! {
+  pub struct UserIdx for User = u32;
+  DISABLE_MAX_INDEX_CHECK = cfg!(not(debug_assertions));
+}
+
+/// Returned when a dataset has more users or questions than their index type
+/// can address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexOverflow {
+  pub domain: &'static str,
+  /// The largest number of values the index type can address.
+  pub max: usize,
+}
+
+impl IndexOverflow {
+  /// Checks that `len` questions fit in a [`QuestionIdx`].
+  pub fn check_questions(len: usize) -> Result<(), Self> {
+    Self::check("question", len, QuestionIdx::MAX_INDEX)
+  }
+
+  /// Checks that `len` users fit in a [`UserIdx`].
+  pub fn check_users(len: usize) -> Result<(), Self> {
+    Self::check("user", len, UserIdx::MAX_INDEX)
+  }
+
+  fn check(domain: &'static str, len: usize, max_index: usize) -> Result<(), Self> {
+    let max = max_index.saturating_add(1);
+    if len > max {
+      return Err(IndexOverflow { domain, max });
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Display for IndexOverflow {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "dataset has more than {} {}s", self.max, self.domain)
+  }
+}
+
+impl Error for IndexOverflow {}
+
+/// A [`Row`] with its user and question replaced by their ids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Entry {
+  pub user: UserIdx,
+  pub question: QuestionIdx,
+  pub score: u32,
+}
+
+/// Every user and question is stored once, and shared by reference count with
+/// any index built from the dataset. So an index does not borrow from the
+/// dataset, and either can be dropped first.
+#[derive(Clone)]
+pub struct Dataset {
+  pub users: Arc<IndexedDomain<User>>,
+  pub questions: Arc<IndexedDomain<Question>>,
+  pub entries: Vec<Entry>,
+}
+
+struct Interner<T> {
+  ids: HashMap<T, usize>,
+  values: Vec<T>,
+}
+
+impl<T: Clone + Eq + std::hash::Hash> Interner<T> {
+  fn new() -> Self {
+    Interner {
+      ids: HashMap::default(),
+      values: Vec::new(),
+    }
+  }
+
+  fn intern(&mut self, value: T) -> usize {
+    let values = &mut self.values;
+    *self.ids.entry(value).or_insert_with_key(|value| {
+      values.push(value.clone());
+      values.len() - 1
+    })
+  }
+}
+
+impl Dataset {
+  /// Interns `rows`, stopping at the first error. Ids are assigned in order of
+  /// first appearance.
+  ///
+  /// Fails with [`IndexOverflow`] if there are more users or questions than
+  /// [`UserIdx`] or [`QuestionIdx`] can address.
+  pub fn try_from_rows<E: From<IndexOverflow>>(
+    rows: impl IntoIterator<Item = Result<Row, E>>,
+  ) -> Result<Self, E> {
+    let mut users = Interner::new();
+    let mut questions = Interner::new();
+    let mut entries = Vec::new();
+    for row in rows {
+      let row = row?;
+      let (user, question) = (users.intern(row.user), questions.intern(row.question));
+      IndexOverflow::check_users(user + 1)?;
+      IndexOverflow::check_questions(question + 1)?;
+      entries.push(Entry {
+        user: UserIdx::from_usize(user),
+        question: QuestionIdx::from_usize(question),
+        score: row.score,
+      });
+    }
+
+    Ok(Dataset {
+      users: Arc::new(IndexedDomain::from_iter(users.values)),
+      questions: Arc::new(IndexedDomain::from_iter(questions.values)),
+      entries,
+    })
+  }
+
+  pub fn from_rows(rows: impl IntoIterator<Item = Row>) -> Result<Self, IndexOverflow> {
+    Self::try_from_rows(rows.into_iter().map(Ok))
+  }
+
+  /// Streams the rows of `path` into a dataset without materializing them.
+  pub fn load_with(path: impl AsRef<Path>, opts: &LoadOptions) -> Result<Self, LoadError> {
+    Self::try_from_rows(load::read_rows(path, opts)?)
+  }
+
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+    Self::load_with(path, &LoadOptions::default())
+  }
+
+  /// A hash of every user, question and entry, in order. Two datasets with
+  /// the same fingerprint assign the same ids, so saved search state for one
+  /// applies to the other.
+  pub fn fingerprint(&self) -> u64 {
+    let mut hasher = FxHasher64::default();
+    self.users.len().hash(&mut hasher);
+    self.users.iter().for_each(|u| u.hash(&mut hasher));
+    self.questions.len().hash(&mut hasher);
+    self.questions.iter().for_each(|q| q.hash(&mut hasher));
+    self.entries.hash(&mut hasher);
+    hasher.finish()
+  }
+
+  /// Each user's score on each question they answered. A repeated answer
+  /// replaces the earlier one, as in every index.
+  pub(crate) fn latest_scores(&self) -> HashMap<(UserIdx, QuestionIdx), u32> {
+    (self.entries.iter())
+      .map(|e| ((e.user, e.question), e.score))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    inner::alloc::AllocCorrSet,
+    inner::test_utils::{mock_data, mock_rows},
+    CorrSetInner,
+  };
+
+  #[test]
+  fn interning() {
+    let data = mock_data();
+    assert_eq!((data.users.len(), data.questions.len()), (3, 3));
+    assert_eq!(data.entries.len(), 9);
+    assert_eq!(data.users.value(data.entries[3].user), &User("b".into()));
+    assert_eq!(
+      data.questions.value(data.entries[3].question),
+      &Question("1".into())
+    );
+  }
+
+  #[test]
+  fn index_outlives_dataset() {
+    let cs = AllocCorrSet::build(&mock_data());
+    let handle = std::thread::spawn(move || cs.iter_qs().count());
+    assert_eq!(handle.join().unwrap(), 3);
+  }
+
+  #[test]
+  fn fingerprint() {
+    let data = mock_data();
+    assert_eq!(data.fingerprint(), mock_data().fingerprint());
+    let mut rows = mock_rows();
+    rows[0].score = 0;
+    assert_ne!(
+      data.fingerprint(),
+      Dataset::from_rows(rows).unwrap().fingerprint()
+    );
+  }
+
+  #[test]
+  fn overflow() {
+    let max = QuestionIdx::MAX_INDEX + 1;
+    assert_eq!(IndexOverflow::check_questions(max), Ok(()));
+    let err = IndexOverflow::check_questions(max + 1).unwrap_err();
+    assert_eq!(err.domain, "question");
+    assert_eq!(err.max, max);
+    assert!(IndexOverflow::check_users(UserIdx::MAX_INDEX + 2).is_err());
+  }
+
+  #[test]
+  fn many_questions() {
+    let rows = (0..=u16::MAX as usize + 1).map(|i| Row {
+      user: User("a".into()),
+      question: Question(i.to_string()),
+      score: 1,
+    });
+    let data = Dataset::from_rows(rows).unwrap();
+    assert_eq!(data.questions.len(), (1 << 16) + 1);
+  }
+}
@@ -0,0 +1,376 @@
+//! Differential tests: every registered inner with every registered exact
+//! outer must find the same best score as the simplest pair, `BasicCorrSet`
+//! with `CorrSetSerial`, on random datasets, targets and aggregators, and the
+//! same sets in the same order when scores tie exactly. The approximate
+//! outers must never find a better score.
+
+use std::sync::Arc;
+
+use proptest::prelude::*;
+
+use crate::{
+  inner::basic::BasicCorrSet,
+  metric::{self, Metric, Pearson},
+  metric_names,
+  outer::{serial::CorrSetSerial, CorrSetResult, DirectSearch, InnerSearch, KSet, SearchOptions},
+  registry::{self, InnerTask, OuterKind, OuterTask},
+  Aggregator, BuildOptions, CorrSetInner, CorrSetOuter, Dataset, Missing, Question, Row, Target,
+  User,
+};
+
+const TOLERANCE: f64 = 1e-9;
+/// Few enough samples to keep `sample` quick on every case.
+const SAMPLES: usize = 64;
+
+/// A random dataset and search over it.
+#[derive(Debug)]
+struct Case {
+  rows: Vec<Row>,
+  k: usize,
+  metric: String,
+  opts: BuildOptions,
+}
+
+/// Datasets of up to 10 users and 7 questions, where each answer is present
+/// with a probability drawn per dataset, so some are dense and others have
+/// many question sets with fewer than two users in common. Small score ranges
+/// give many ties, and a range of one makes every score the same.
+fn cases() -> impl Strategy<Value = Case> {
+  (1..=10usize, 2..=7usize, 0.1..=1.0f64, 1..=5u32).prop_flat_map(
+    |(n_users, n_questions, density, range)| {
+      let answer = prop::option::weighted(density, 0..range);
+      (
+        prop::collection::vec(answer, n_users * n_questions),
+        2..=n_questions.min(4),
+        prop::sample::select(metric_names()).prop_map(|name| name.replace("<threshold>", "2")),
+        targets(n_users, n_questions),
+        aggregators(n_questions),
+        missing(),
+        0..=4usize,
+      )
+        .prop_map(
+          move |(answers, k, metric, target, aggregator, missing, min_users)| {
+            let rows = answers
+              .into_iter()
+              .enumerate()
+              .filter_map(|(i, score)| {
+                Some(Row {
+                  user: User((i / n_questions).to_string()),
+                  question: Question((i % n_questions).to_string()),
+                  score: score?,
+                })
+              })
+              .collect();
+            Case {
+              rows,
+              k,
+              metric,
+              opts: BuildOptions {
+                target,
+                aggregator,
+                missing,
+                min_users,
+              },
+            }
+          },
+        )
+    },
+  )
+}
+
+/// Every kind of target, with outcomes for a random half of the users and
+/// subsets of any size, including none.
+fn targets(n_users: usize, n_questions: usize) -> impl Strategy<Value = Target> {
+  let outcome = prop::option::of(-10.0..10.0f64);
+  prop_oneof![
+    Just(Target::GrandTotal),
+    Just(Target::Rest),
+    prop::collection::vec(outcome, n_users).prop_map(|outcomes| {
+      let outcomes = outcomes.into_iter().enumerate();
+      let outcomes = outcomes.filter_map(|(u, outcome)| Some((User(u.to_string()), outcome?)));
+      Target::Outcome(Arc::new(outcomes.collect()))
+    }),
+    prop::sample::subsequence((0..n_questions).collect::<Vec<_>>(), 0..=n_questions).prop_map(
+      |subset| Target::Subset(
+        subset
+          .into_iter()
+          .map(|q| Question(q.to_string()))
+          .collect()
+      )
+    ),
+  ]
+}
+
+/// Every kind of aggregator, with weights for a random subset of the
+/// questions.
+fn aggregators(n_questions: usize) -> impl Strategy<Value = Aggregator> {
+  let weight = prop::option::of(-3.0..3.0f64);
+  prop_oneof![
+    Just(Aggregator::Sum),
+    Just(Aggregator::Mean),
+    Just(Aggregator::Max),
+    Just(Aggregator::Min),
+    prop::collection::vec(weight, n_questions).prop_map(|weights| {
+      let weights = weights.into_iter().enumerate();
+      let weights = weights.filter_map(|(q, weight)| Some((Question(q.to_string()), weight?)));
+      Aggregator::Weighted(Arc::new(weights.collect()))
+    }),
+    Just(Aggregator::ZSum),
+  ]
+}
+
+fn missing() -> impl Strategy<Value = Missing> {
+  prop_oneof![
+    Just(Missing::CompleteCase),
+    Just(Missing::Zero),
+    Just(Missing::Mean),
+    (1..=4usize).prop_map(Missing::AtLeast),
+  ]
+}
+
+/// The best `n` sets found by inner `I` with outer `O`, best first.
+struct Top<'a, O> {
+  data: &'a Dataset,
+  metric: &'a dyn Metric,
+  opts: &'a BuildOptions,
+  k: usize,
+  n: usize,
+  outer: O,
+}
+
+type Sets = Vec<(Vec<Question>, f64)>;
+
+fn sets(result: CorrSetResult) -> Sets {
+  let set = |set: KSet| (set.questions.into_iter().cloned().collect(), set.r);
+  result.sets.into_iter().map(set).collect()
+}
+
+impl<O: CorrSetOuter> InnerTask for Top<'_, O> {
+  type Output = Sets;
+
+  fn run<I: CorrSetInner>(self) -> Sets {
+    let cs = I::build_with(self.data, self.opts);
+    sets(
+      self
+        .outer
+        .k_top_n(&cs, self.metric, cs.combinations(self.k), self.n),
+    )
+  }
+}
+
+/// The best `n` sets found by inner `I` with search `S`, best first.
+struct SearchTop<'a, S> {
+  data: &'a Dataset,
+  metric: &'a dyn Metric,
+  opts: &'a BuildOptions,
+  k: usize,
+  n: usize,
+  search: S,
+}
+
+impl<S: InnerSearch> InnerTask for SearchTop<'_, S> {
+  type Output = Sets;
+
+  fn run<I: CorrSetInner>(self) -> Sets {
+    let cs = I::build_with(self.data, self.opts);
+    sets(self.search.k_top_n(&cs, self.metric, self.k, self.n).into())
+  }
+}
+
+/// The sets found by an outer with each inner, or by itself if it takes no
+/// inner.
+struct EveryInner<'a> {
+  data: &'a Dataset,
+  metric: &'a dyn Metric,
+  opts: &'a BuildOptions,
+  k: usize,
+  n: usize,
+}
+
+impl OuterTask for EveryInner<'_> {
+  type Output = Vec<(Option<&'static str>, Sets)>;
+
+  fn run<O: CorrSetOuter>(self) -> Self::Output {
+    let EveryInner {
+      data,
+      metric,
+      opts,
+      k,
+      n,
+    } = self;
+    registry::inners::<Top<O>>()
+      .iter()
+      .map(|inner| {
+        let found = inner.run(Top {
+          data,
+          metric,
+          opts,
+          k,
+          n,
+          outer: O::new(),
+        });
+        (Some(inner.name), found)
+      })
+      .collect()
+  }
+
+  fn run_direct<S: DirectSearch>(self) -> Self::Output {
+    let cs = S::build_with(self.data, self.opts);
+    vec![(None, sets(cs.k_top_n(self.metric, self.k, self.n)))]
+  }
+
+  fn run_search<S: InnerSearch>(self) -> Self::Output {
+    let EveryInner {
+      data,
+      metric,
+      opts,
+      k,
+      n,
+    } = self;
+    let search = SearchOptions {
+      samples: SAMPLES,
+      ..Default::default()
+    };
+    registry::inners::<SearchTop<S>>()
+      .iter()
+      .map(|inner| {
+        let found = inner.run(SearchTop {
+          data,
+          metric,
+          opts,
+          k,
+          n,
+          search: S::new(&search),
+        });
+        (Some(inner.name), found)
+      })
+      .collect()
+  }
+}
+
+/// The sets found by every strategy, named and with their outer's kind,
+/// starting with `BasicCorrSet` and `CorrSetSerial`.
+fn every_strategy(
+  data: &Dataset,
+  metric: &dyn Metric,
+  opts: &BuildOptions,
+  k: usize,
+  n: usize,
+) -> Vec<(String, OuterKind, Sets)> {
+  let basic = Top {
+    data,
+    metric,
+    opts,
+    k,
+    n,
+    outer: CorrSetSerial::new(),
+  }
+  .run::<BasicCorrSet>();
+  let mut found = vec![("basic".to_string(), OuterKind::Exhaustive, basic)];
+  for outer in registry::outers::<EveryInner>() {
+    let kind = registry::outer_kind(outer.name).unwrap();
+    let by_inner = outer.run(EveryInner {
+      data,
+      metric,
+      opts,
+      k,
+      n,
+    });
+    found.extend(by_inner.into_iter().map(|(inner, sets)| {
+      let name = match inner {
+        Some(inner) => format!("{}+{inner}", outer.name),
+        None => outer.name.to_string(),
+      };
+      (name, kind, sets)
+    }));
+  }
+  found
+}
+
+fn same(a: Option<f64>, b: Option<f64>) -> bool {
+  match (a, b) {
+    (Some(a), Some(b)) => (a - b).abs() <= TOLERANCE * a.abs().max(1.),
+    (a, b) => a.is_none() && b.is_none(),
+  }
+}
+
+/// Whether an approximate best score `a` is no better than the exact best `b`.
+fn no_better(a: Option<f64>, b: Option<f64>) -> bool {
+  match (a, b) {
+    (Some(a), Some(b)) => a <= b + TOLERANCE * b.abs().max(1.),
+    (a, _) => a.is_none(),
+  }
+}
+
+proptest! {
+  #![proptest_config(ProptestConfig::with_cases(256))]
+
+  #[test]
+  fn same_best_score(case in cases()) {
+    let data = Dataset::from_rows(case.rows).unwrap();
+    // A question only exists once someone answers it.
+    prop_assume!(data.questions.len() >= case.k);
+    let metric = &*metric::from_name(&case.metric).unwrap();
+    let best = |sets: &Sets| sets.first().map(|(_, r)| *r);
+
+    let mut found = every_strategy(&data, metric, &case.opts, case.k, 1).into_iter();
+    let expected = best(&found.next().unwrap().2);
+    for (name, kind, sets) in found {
+      let best = best(&sets);
+      if kind == OuterKind::Search {
+        prop_assert!(no_better(best, expected), "{name} found {best:?}, above {expected:?}");
+      } else {
+        prop_assert!(same(best, expected), "{name} found {best:?}, expected {expected:?}");
+      }
+    }
+  }
+}
+
+/// Duplicated questions give sets whose scores are exactly equal within each
+/// strategy, so every strategy must order them the same way: by their
+/// questions' ids.
+#[test]
+fn ties_break_by_question() {
+  let scores = [
+    [1, 0, 3, 2],
+    [2, 2, 0, 1],
+    [0, 3, 1, 1],
+    [3, 1, 2, 0],
+    [1, 1, 0, 3],
+  ];
+  let columns = [0, 1, 0, 2, 1, 3];
+  let rows = scores.iter().enumerate().flat_map(|(user, answers)| {
+    columns
+      .iter()
+      .enumerate()
+      .map(move |(question, column)| Row {
+        user: User(user.to_string()),
+        question: Question(question.to_string()),
+        score: answers[*column],
+      })
+  });
+  let data = Dataset::from_rows(rows).unwrap();
+  let (k, n) = (2, 15);
+
+  let mut found = every_strategy(&data, &Pearson, &BuildOptions::default(), k, n).into_iter();
+  let (_, _, expected) = found.next().unwrap();
+  assert_eq!(expected.len(), n);
+  let ids = |qs: &[Question]| {
+    qs.iter()
+      .map(|q| data.questions.index(q))
+      .collect::<Vec<_>>()
+  };
+  for pair in expected.windows(2) {
+    let ((a, a_r), (b, b_r)) = (&pair[0], &pair[1]);
+    assert!(a_r > b_r || (a_r == b_r && ids(a) < ids(b)), "{pair:?}");
+  }
+  assert!(expected.windows(2).any(|pair| pair[0].1 == pair[1].1));
+  let questions = |sets: &Sets| sets.iter().map(|(qs, _)| qs.clone()).collect::<Vec<_>>();
+  // The approximate outers may miss some of the best sets.
+  for (name, _, sets) in found.filter(|(_, kind, _)| *kind != OuterKind::Search) {
+    assert_eq!(questions(&sets), questions(&expected), "{name}");
+    for ((_, r), (_, expected)) in sets.iter().zip(&expected) {
+      assert!(same(Some(*r), Some(*expected)), "{name}");
+    }
+  }
+}
@@ -1,99 +1,130 @@
 use crate::{
+  checkpoint::{Checkpoint, Checkpointer},
+  dataset::QuestionIdx,
   inner::{
     alloc::{AllocCorrSet, UserSet},
-    indexed::QuestionIdx,
+    Corr,
   },
-  utils::pb_style,
-  CorrSetInner, Question, Row,
+  metric::Metric,
+  outer::{self, CorrSetResult, DirectSearch},
+  shard::Shard,
+  utils::{choose, pb_style, TopN},
+  BuildOptions, CorrSetInner, Dataset,
 };
-use float_ord::FloatOrd;
 
-use indicatif::{ProgressBar, ProgressIterator};
+use indicatif::ProgressIterator;
 use itertools::Itertools;
 use rayon::prelude::*;
 
-pub struct CorrSetFused<'a> {
-  inner: AllocCorrSet<'a>,
+pub struct CorrSetFused {
+  pub(crate) inner: AllocCorrSet,
 }
 
-struct QuestionCombinations<'a, 'b> {
-  inner: &'b AllocCorrSet<'a>,
+struct QuestionCombinations<'a, M: ?Sized> {
+  inner: &'a AllocCorrSet,
+  metric: &'a M,
   qs: Vec<QuestionIdx>,
   k: usize,
-  users: Vec<UserSet<'a>>,
-  qs_scores: &'b mut [f64],
-  grand_scores: &'b mut [f64],
+  users: Vec<UserSet>,
+  qs_scores: &'a mut Vec<f64>,
+  target_scores: &'a mut Vec<f64>,
   first: bool,
+  /// The number of sets skipped because a prefix had too few users.
+  n_too_few: usize,
 }
 
-impl<'a, 'b> QuestionCombinations<'a, 'b> {
+impl<'a, M: Metric + ?Sized> QuestionCombinations<'a, M> {
   pub fn new(
-    inner: &'b AllocCorrSet<'a>,
+    inner: &'a AllocCorrSet,
+    metric: &'a M,
     root: QuestionIdx,
     k: usize,
-    qs_scores: &'b mut [f64],
-    grand_scores: &'b mut [f64],
+    qs_scores: &'a mut Vec<f64>,
+    target_scores: &'a mut Vec<f64>,
   ) -> Self {
-    let qs = (0..k)
-      .map(|j| QuestionIdx::from_usize(root.index() + j))
-      .collect_vec();
-
-    let mut users = vec![inner.q_to_score[qs[0]].1.clone()];
-    for q in &qs[1..k] {
-      let mut last = users.last().unwrap().clone();
-      last.intersect(&inner.q_to_score[*q].1);
-      users.push(last);
-    }
-
     QuestionCombinations {
       inner,
-      qs,
+      metric,
+      qs: vec![root; k],
       k,
-      users,
+      users: vec![UserSet::new(&inner.users); k],
       qs_scores,
-      grand_scores,
+      target_scores,
       first: true,
+      n_too_few: 0,
     }
   }
+
+  /// Moves on to the next set after every set that shares its first `i + 1`
+  /// questions, by incrementing the last question at or before `i` that can
+  /// be. Returns the position of that question, or `None` once every set with
+  /// this root is visited.
+  #[inline]
+  fn advance(&mut self, mut i: usize) -> Option<usize> {
+    let n = self.inner.questions.len();
+    while i > 0 && self.qs[i] == n + i - self.k {
+      i -= 1;
+    }
+    if i == 0 {
+      return None;
+    }
+    self.qs[i] += 1;
+    Some(i)
+  }
+
+  /// Fills in the questions after `i` and the user sets from `i` on. If a
+  /// prefix is answered by too few users for any set extending it, returns
+  /// the position of its last question.
+  #[inline]
+  fn fill(&mut self, i: usize) -> Option<usize> {
+    for j in i..self.k {
+      if j > i {
+        self.qs[j] = self.qs[j - 1] + 1;
+      }
+      if j == 0 {
+        self.inner.init_users(&mut self.users[0], self.qs[0]);
+      } else {
+        let [cur, prev] = unsafe { self.users.get_many_unchecked_mut([j, j - 1]) };
+        cur.clone_from(prev);
+        self.inner.add_users(cur, self.qs[j]);
+      }
+      if j < self.k - 1 && self.inner.too_few_users(&self.users[j]) {
+        return Some(j);
+      }
+    }
+    None
+  }
 }
 
-impl<'a, 'b> Iterator for QuestionCombinations<'a, 'b> {
-  type Item = (Vec<QuestionIdx>, f64);
+impl<'a, M: Metric + ?Sized> Iterator for QuestionCombinations<'a, M> {
+  type Item = (Vec<QuestionIdx>, Corr);
 
   #[inline]
   fn next(&mut self) -> Option<Self::Item> {
-    if self.first {
+    let mut i = if self.first {
       self.first = false;
+      0
     } else {
-      let n = self.inner.questions.len();
-      let mut i = self.k - 1;
-
-      while self.qs[i] == n + i - self.k {
-        if i > 1 {
-          i -= 1;
-        } else {
-          return None;
-        }
-      }
+      self.advance(self.k - 1)?
+    };
 
-      self.qs[i] += 1;
-      let [cur, prev] = unsafe { self.users.get_many_unchecked_mut([i, i - 1]) };
-      cur.clone_from(prev);
-      cur.intersect(&self.inner.q_to_score[self.qs[i]].1);
-
-      for j in (i + 1)..self.k {
-        self.qs[j] = self.qs[j - 1] + 1;
-        let [cur, prev] = unsafe { self.users.get_many_unchecked_mut([j, j - 1]) };
-        cur.clone_from(prev);
-        cur.intersect(&self.inner.q_to_score[self.qs[j]].1);
+    // Every set extending a prefix with too few users has too few, so they
+    // are skipped together.
+    while let Some(j) = self.fill(i) {
+      let n = self.inner.questions.len();
+      self.n_too_few += choose(n - self.qs[j].index() - 1, self.k - j - 1);
+      if j == 0 {
+        return None;
       }
+      i = self.advance(j)?;
     }
 
     let output = (
       self.qs.clone(),
       self.inner.corr_set_score(
+        self.metric,
         self.qs_scores,
-        self.grand_scores,
+        self.target_scores,
         unsafe { self.users.last().unwrap_unchecked() },
         &self.qs,
       ),
@@ -103,36 +134,146 @@ impl<'a, 'b> Iterator for QuestionCombinations<'a, 'b> {
   }
 }
 
-impl<'a> CorrSetFused<'a> {
+impl CorrSetFused {
+  /// The best `n` of the sets whose first question is `root`.
   #[inline]
-  pub fn build(data: &'a [Row]) -> Self {
-    CorrSetFused {
-      inner: AllocCorrSet::build(data),
+  pub(crate) fn root_top<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    root: QuestionIdx,
+    k: usize,
+    n: usize,
+    (qs_scores, target_scores, _): &mut <AllocCorrSet as CorrSetInner>::Scratch,
+  ) -> TopN<QuestionIdx> {
+    let mut top = TopN::new(n);
+    let mut combinations =
+      QuestionCombinations::new(&self.inner, metric, root, k, qs_scores, target_scores);
+    for (qs, corr) in &mut combinations {
+      top.push(qs, corr);
     }
+    top.push_too_few(combinations.n_too_few);
+    top
   }
 
-  #[inline]
-  pub fn k_set(&self, k: usize) -> Vec<&'a Question> {
-    let n = self.inner.questions.len();
-    let (qs, _) = self
-      .inner
-      .questions
-      .indices()
-      .take(n - k + 1)
-      .progress_with(ProgressBar::new((n - k) as u64).with_style(pb_style()))
+  /// The roots of every set, i.e. the questions that can come first.
+  pub(crate) fn roots(&self, k: usize) -> impl Iterator<Item = QuestionIdx> + Send + '_ {
+    let num_roots = (self.inner.questions.len() + 1).saturating_sub(k);
+    self.inner.questions.indices().take(num_roots)
+  }
+
+  fn top_of_roots<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    k: usize,
+    n: usize,
+    roots: Vec<QuestionIdx>,
+  ) -> TopN<QuestionIdx> {
+    roots
+      .into_iter()
+      .progress_with_style(pb_style())
       .par_bridge()
       .map_init(
         || self.inner.init_scratch(),
-        |(qs_scores, grand_scores, _), root| {
-          QuestionCombinations::new(&self.inner, root, k, qs_scores, grand_scores)
-            .max_by_key(|(_, r)| FloatOrd(*r))
-        },
+        |scratch, root| self.root_top(metric, root, k, n, scratch),
       )
-      .flatten()
-      .max_by_key(|(_, r)| FloatOrd(*r))
-      .unwrap();
-    qs.into_iter()
-      .map(|q| self.inner.to_question(q))
-      .collect_vec()
+      .reduce(|| TopN::new(n), TopN::merge)
+  }
+}
+
+impl DirectSearch for CorrSetFused {
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    CorrSetFused {
+      inner: AllocCorrSet::build_with(data, opts),
+    }
+  }
+
+  fn k_top_n<M: Metric + ?Sized>(&self, metric: &M, k: usize, n: usize) -> CorrSetResult<'_> {
+    let top = self.top_of_roots(metric, k, n, self.roots(k).collect_vec());
+    outer::resolve(&self.inner, top)
+  }
+
+  fn k_top_n_shard<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    k: usize,
+    n: usize,
+    shard: Shard,
+  ) -> CorrSetResult<'_> {
+    let roots = self
+      .roots(k)
+      .filter(|root| shard.has_root(root.index()))
+      .collect_vec();
+    let top = self.top_of_roots(metric, k, n, roots);
+    outer::resolve(&self.inner, top)
+  }
+
+  fn k_top_n_checkpointed<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    k: usize,
+    n: usize,
+    checkpointer: &Checkpointer,
+    resume: Option<Checkpoint>,
+  ) -> anyhow::Result<CorrSetResult<'_>> {
+    let top = checkpointer.search_roots(
+      k,
+      n,
+      self.roots(k),
+      resume,
+      || self.inner.init_scratch(),
+      |scratch, root| self.root_top(metric, root, k, n, scratch),
+    )?;
+    Ok(outer::resolve(&self.inner, top))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    inner::test_utils::sparse_data, metric::Pearson, outer::serial::CorrSetSerial, CorrSetOuter,
+    Missing,
+  };
+
+  #[test]
+  fn k_above_num_questions() {
+    let fused = CorrSetFused::build(&sparse_data());
+    let result = fused.k_top_n(&Pearson, 13, 5);
+    assert!(result.sets.is_empty());
+    assert_eq!(result.n_evaluated, 0);
+  }
+
+  #[test]
+  fn min_users() {
+    let data = sparse_data();
+    for missing in [Missing::CompleteCase, Missing::Zero] {
+      let opts = BuildOptions {
+        missing,
+        min_users: 3,
+        ..Default::default()
+      };
+      let fused = CorrSetFused::build_with(&data, &opts);
+      let alloc = AllocCorrSet::build_with(&data, &opts);
+      for k in 2..=4 {
+        let actual = fused.k_top_n(&Pearson, k, 5);
+        let expected = CorrSetSerial::new().k_top_n(&alloc, &Pearson, alloc.combinations(k), 5);
+        let sets = |result: &CorrSetResult| {
+          (result.sets.iter())
+            .map(|set| {
+              (
+                set.questions.iter().copied().cloned().collect_vec(),
+                set.r,
+                set.n_users,
+              )
+            })
+            .collect_vec()
+        };
+        assert_eq!(sets(&actual), sets(&expected));
+        assert!(actual.sets.iter().all(|set| set.n_users >= 3));
+        let counts = |result: &CorrSetResult| (result.n_evaluated, result.n_nan, result.n_pruned);
+        assert_eq!(counts(&actual), counts(&expected));
+        assert_eq!(actual.n_evaluated, choose(data.questions.len(), k));
+      }
+    }
   }
 }
@@ -1,133 +1,198 @@
 use super::{
   imap::{QuestionMap, UserMap},
-  indexed::{QuestionIdx, QuestionRef, UserRef},
-  CorrSetInner,
+  BuildOptions, Corr, CorrSetInner,
+};
+use crate::{
+  aggregate::Aggregation,
+  dataset::QuestionIdx,
+  metric::{Metric, Moments},
+  target::Targets,
+  Dataset, Question, User,
 };
-use crate::{utils, utils::Captures, Question, Row};
-use fxhash::FxHashSet as HashSet;
 use indexical::{bitset::simd::SimdBitset, pointer::ArcFamily, IndexSet, IndexedDomain};
 use std::sync::Arc;
 
-pub type UserSet<'a> = IndexSet<'a, UserRef<'a>, SimdBitset<u64, 16>, ArcFamily>;
-pub type QuestionEntry<'a> = (UserMap<'a, u32>, UserSet<'a>);
-pub struct AllocCorrSet<'a> {
-  pub questions: Arc<IndexedDomain<QuestionRef<'a>>>,
-  pub users: Arc<IndexedDomain<UserRef<'a>>>,
-  pub q_to_score: QuestionMap<'a, QuestionEntry<'a>>,
-  grand_totals: UserMap<'a, u32>,
+pub type UserSet = IndexSet<'static, User, SimdBitset<u64, 16>, ArcFamily>;
+pub type QuestionEntry = (UserMap<u32>, UserSet);
+pub struct AllocCorrSet {
+  pub questions: Arc<IndexedDomain<Question>>,
+  pub users: Arc<IndexedDomain<User>>,
+  pub q_to_score: QuestionMap<QuestionEntry>,
+  pub(crate) targets: Targets,
+  pub(crate) aggregation: Aggregation,
+  pub(crate) min_users: usize,
 }
 
-impl<'a> AllocCorrSet<'a> {
+impl AllocCorrSet {
+  /// Sets `users` to the users who answered `q`.
+  #[inline]
+  pub fn init_users(&self, users: &mut UserSet, q: QuestionIdx) {
+    users.clone_from(&self.q_to_score[q].1);
+  }
+
+  /// Narrows `users` to those who also answered `q`, or, if missing answers
+  /// are imputed, widens it to those who answered either.
+  #[inline]
+  pub fn add_users(&self, users: &mut UserSet, q: QuestionIdx) {
+    let answered = &self.q_to_score[q].1;
+    if self.aggregation.is_complete_case() {
+      users.intersect(answered);
+    } else {
+      users.union(answered);
+    }
+  }
+
+  /// Whether every set whose first questions were answered by `users` is
+  /// scored by fewer than [`BuildOptions::min_users`] users. Only when users
+  /// with missing answers are left out, since otherwise adding questions adds
+  /// users.
+  #[inline]
+  pub fn too_few_users(&self, users: &UserSet) -> bool {
+    self.aggregation.is_complete_case() && users.len() < self.min_users
+  }
+
+  /// Scores the users in `users` who have a target. Metrics that can be
+  /// streamed are fed one user at a time, and the rest are handed `qs_scores`
+  /// and `target_scores`, which are grown as needed.
   #[inline]
-  pub fn corr_set_score(
+  pub fn corr_set_score<M: Metric + ?Sized>(
     &self,
-    qs_scores: &mut [f64],
-    grand_scores: &mut [f64],
-    users: &UserSet<'a>,
+    metric: &M,
+    qs_scores: &mut Vec<f64>,
+    target_scores: &mut Vec<f64>,
+    users: &UserSet,
     qs: &[QuestionIdx],
-  ) -> f64 {
+  ) -> Corr {
+    if users.len() < self.min_users {
+      return Corr::too_few(users.len());
+    }
+    let stream = metric.streaming();
+    if stream.is_none() && qs_scores.len() < self.users.len() {
+      qs_scores.resize(self.users.len(), 0.);
+      target_scores.resize(self.users.len(), 0.);
+    }
+
+    let complete = self.aggregation.is_complete_case();
+    let mut moments = Moments::default();
     let mut n = 0;
-    for (i, u) in users.indices().enumerate() {
-      let total = qs
-        .iter()
-        .map(|q| unsafe {
-          let (u_scores, _) = self.q_to_score.get_unchecked(*q);
-          *u_scores.get_unchecked(u)
-        })
-        .sum::<u32>();
-      let grand_total = unsafe { *self.grand_totals.get_unchecked(u) };
-      unsafe {
-        *qs_scores.get_unchecked_mut(i) = total as f64;
-        *grand_scores.get_unchecked_mut(i) = grand_total as f64;
+    for u in users.indices() {
+      let scores = qs.iter().map(|q| unsafe {
+        let (u_scores, answered) = self.q_to_score.get_unchecked(*q);
+        let score = *u_scores.get_unchecked(u);
+        (*q, (complete || answered.contains(u)).then_some(score))
+      });
+      let Some((total, value)) = (unsafe { self.aggregation.try_combine_unchecked(scores) }) else {
+        continue;
+      };
+      let Some(target) = (unsafe { self.targets.get_unchecked(u, total) }) else {
+        continue;
+      };
+      if stream.is_some() {
+        moments.push(value, target);
+      } else {
+        unsafe {
+          *qs_scores.get_unchecked_mut(n) = value;
+          *target_scores.get_unchecked_mut(n) = target;
+        }
       }
       n += 1;
     }
-    utils::correlation(&qs_scores[..n], &grand_scores[..n])
+    if n < self.min_users {
+      return Corr::too_few(n);
+    }
+    let r = match stream {
+      Some(score) => score(&moments),
+      None => metric.score(&qs_scores[..n], &target_scores[..n]),
+    };
+    Corr { r, n_users: n }
   }
 }
 
-impl<'a> CorrSetInner<'a> for AllocCorrSet<'a> {
+impl CorrSetInner for AllocCorrSet {
   type Q = QuestionIdx;
-  type Scratch = (
-    Vec<f64>,
-    Vec<f64>,
-    IndexSet<'a, UserRef<'a>, SimdBitset<u64, 16>, ArcFamily>,
-  );
+  type Scratch = (Vec<f64>, Vec<f64>, UserSet);
 
   #[inline]
-  fn build(data: &'a [Row]) -> Self {
-    let (users, questions): (HashSet<_>, HashSet<_>) = data
-      .iter()
-      .map(|row| (UserRef(&row.user), QuestionRef(&row.question)))
-      .unzip();
-    let users = Arc::new(IndexedDomain::from_iter(users));
-    let questions = Arc::new(IndexedDomain::from_iter(questions));
-
-    let mut q_to_score = QuestionMap::new(&questions, |_| {
-      (
-        UserMap::<'_, u32>::new(&users, |_| 0),
-        IndexSet::new(&users),
-      )
-    });
-    for r in data {
-      let (q_idx, u_idx) = (
-        questions.index(&QuestionRef(&r.question)),
-        users.index(&UserRef(&r.user)),
-      );
-      let (scores, set) = q_to_score.get_mut(q_idx).unwrap();
-      scores.insert(u_idx, r.score);
-      set.insert(u_idx);
-    }
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    let (users, questions) = (&data.users, &data.questions);
 
-    let grand_totals = UserMap::new(&users, |u| {
-      q_to_score
-        .values()
-        .filter_map(|(scores, set)| set.contains(u).then_some(*scores.get(u).unwrap()))
-        .sum::<u32>()
+    let mut q_to_score = QuestionMap::new(questions, |_| {
+      (UserMap::<u32>::new(users, |_| 0), IndexSet::new(users))
     });
+    for e in &data.entries {
+      let (scores, set) = q_to_score.get_mut(e.question).unwrap();
+      scores.insert(e.user, e.score);
+      set.insert(e.user);
+    }
 
     AllocCorrSet {
-      questions,
-      users,
+      questions: questions.clone(),
+      users: users.clone(),
       q_to_score,
-      grand_totals,
+      targets: Targets::new(&opts.target, data),
+      aggregation: Aggregation::new(opts, data),
+      min_users: opts.min_users,
     }
   }
 
   #[inline]
-  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + Captures<'a> + '_ {
+  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + '_ {
     self.questions.indices()
   }
 
   #[inline]
-  fn to_question(&self, q: Self::Q) -> &'a Question {
-    self.questions.value(q).0
+  fn to_question(&self, q: Self::Q) -> &Question {
+    self.questions.value(q)
   }
 
   #[inline]
   fn init_scratch(&self) -> Self::Scratch {
-    (
-      vec![0.; self.users.len()],
-      vec![0.; self.users.len()],
-      IndexSet::new(&self.users),
-    )
+    (Vec::new(), Vec::new(), IndexSet::new(&self.users))
   }
 
   #[inline]
-  fn corr_set(&self, (qs_scores, grand_scores, users): &mut Self::Scratch, qs: &[Self::Q]) -> f64 {
-    users.clone_from(&self.q_to_score[qs[0]].1);
+  fn corr_set<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    (qs_scores, target_scores, users): &mut Self::Scratch,
+    qs: &[Self::Q],
+  ) -> Corr {
+    self.init_users(users, qs[0]);
     for q in &qs[1..] {
-      users.intersect(&self.q_to_score[*q].1);
+      self.add_users(users, *q);
     }
 
-    self.corr_set_score(qs_scores, grand_scores, users, qs)
+    self.corr_set_score(metric, qs_scores, target_scores, users, qs)
   }
 }
 
 #[cfg(test)]
 mod test {
   use super::*;
-  use crate::test_inner;
+  use crate::{inner::test_utils::mock_data, metric::Pearson, test_inner, utils};
 
   test_inner!(alloc, AllocCorrSet);
+
+  /// Pearson without [`Metric::streaming`], so scores go through the buffers.
+  struct Buffered;
+
+  impl Metric for Buffered {
+    fn score(&self, xs: &[f64], ys: &[f64]) -> f64 {
+      utils::correlation(xs, ys)
+    }
+  }
+
+  #[test]
+  fn streamed_matches_buffered() {
+    let cs = AllocCorrSet::build(&mock_data());
+    let mut scratch = cs.init_scratch();
+    for qs in cs.combinations(2) {
+      let streamed = cs.corr_set(&Pearson, &mut scratch, &qs);
+      let buffered = cs.corr_set(&Buffered, &mut scratch, &qs);
+      assert_eq!(streamed.n_users, buffered.n_users);
+      assert!(
+        (streamed.r - buffered.r).abs() < 1e-12 || (streamed.r.is_nan() && buffered.r.is_nan())
+      );
+    }
+  }
 }
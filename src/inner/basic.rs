@@ -1,57 +1,79 @@
-use crate::{utils, Question, Row, User};
+use std::sync::Arc;
+
+use crate::{
+  aggregate::Aggregation, dataset::QuestionIdx, metric::Metric, target::Targets, utils, Dataset,
+  Question, User,
+};
 use fxhash::FxHashMap as HashMap;
+use indexical::IndexedDomain;
 
-use super::CorrSetInner;
+use super::{BuildOptions, Corr, CorrSetInner};
 
-pub struct BasicCorrSet<'a> {
-  q_to_score: HashMap<&'a Question, HashMap<&'a User, u32>>,
-  grand_totals: HashMap<&'a User, u32>,
+pub struct BasicCorrSet {
+  users: Arc<IndexedDomain<User>>,
+  questions: Arc<IndexedDomain<Question>>,
+  q_to_score: HashMap<Question, HashMap<User, u32>>,
+  targets: Targets,
+  aggregation: Aggregation,
+  min_users: usize,
 }
 
-impl<'a> CorrSetInner<'a> for BasicCorrSet<'a> {
-  type Q = &'a Question;
+impl CorrSetInner for BasicCorrSet {
+  type Q = QuestionIdx;
   type Scratch = ();
 
-  fn build(data: &'a [Row]) -> Self {
-    let q_to_score = utils::group_by(data.iter().map(|r| (&r.question, &r.user, r.score)));
-    let u_to_score = utils::group_by(data.iter().map(|r| (&r.user, &r.question, r.score)));
-    let grand_totals = u_to_score
-      .iter()
-      .map(|(user, scores)| {
-        let total = scores.values().sum::<u32>();
-        (*user, total)
-      })
-      .collect::<HashMap<_, _>>();
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    let rows = data.entries.iter().map(|e| {
+      let user = data.users.value(e.user).clone();
+      let question = data.questions.value(e.question).clone();
+      (question, user, e.score)
+    });
 
     BasicCorrSet {
-      q_to_score,
-      grand_totals,
+      users: data.users.clone(),
+      questions: data.questions.clone(),
+      q_to_score: utils::group_by(rows),
+      targets: Targets::new(&opts.target, data),
+      aggregation: Aggregation::new(opts, data),
+      min_users: opts.min_users,
     }
   }
 
-  fn iter_qs(&self) -> impl Iterator<Item = &'a Question> + '_ {
-    self.q_to_score.keys().copied()
+  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + '_ {
+    self.questions.indices()
   }
 
-  fn to_question(&self, q: &'a Question) -> &'a Question {
-    q
+  fn to_question(&self, q: QuestionIdx) -> &Question {
+    self.questions.value(q)
   }
 
   fn init_scratch(&self) -> Self::Scratch {}
 
-  fn corr_set(&self, _: &mut (), qs: &[Self::Q]) -> f64 {
-    let (qs_scores, grand_scores): (Vec<_>, Vec<_>) = self
-      .grand_totals
-      .iter()
-      .filter_map(|(u, grand_total)| {
-        let total = qs
-          .iter()
-          .map(|q| self.q_to_score[*q].get(u).copied())
-          .sum::<Option<u32>>()?;
-        Some((total as f64, *grand_total as f64))
+  fn corr_set<M: Metric + ?Sized>(&self, metric: &M, _: &mut (), qs: &[Self::Q]) -> Corr {
+    // Users are visited in order, so that scores are summed in the same order
+    // as the other inners and equal sets score equally.
+    let (qs_scores, target_scores): (Vec<_>, Vec<_>) = self
+      .users
+      .indices()
+      .filter_map(|u| {
+        let user = self.users.value(u);
+        let scores = qs.iter().map(|q| {
+          let score = self.q_to_score[self.questions.value(*q)].get(user).copied();
+          (*q, score)
+        });
+        let (total, value) = self.aggregation.try_combine(scores)?;
+        let target = self.targets.get(u, total)?;
+        Some((value, target))
       })
       .unzip();
-    utils::correlation(&qs_scores, &grand_scores)
+    let n_users = qs_scores.len();
+    if n_users < self.min_users {
+      return Corr::too_few(n_users);
+    }
+    Corr {
+      r: metric.score(&qs_scores, &target_scores),
+      n_users,
+    }
   }
 }
 
@@ -1,96 +1,95 @@
 use super::{
   imap::{QuestionMap, UserMap},
-  indexed::{QuestionIdx, QuestionRef, UserRef},
-  CorrSetInner,
+  BuildOptions, Corr, CorrSetInner,
+};
+use crate::{
+  aggregate::Aggregation, dataset::QuestionIdx, metric::Metric, target::Targets, Dataset, Question,
+  User,
 };
-use crate::{utils, utils::Captures, Question, Row};
-use fxhash::FxHashSet as HashSet;
 use indexical::{bitset::BitSet, pointer::ArcFamily, IndexSet, IndexedDomain};
 use std::sync::Arc;
 
-pub type QuestionEntry<'a, S> = (UserMap<'a, u32>, IndexSet<'a, UserRef<'a>, S, ArcFamily>);
-pub struct BitsetCorrSet<'a, S: BitSet> {
-  questions: Arc<IndexedDomain<QuestionRef<'a>>>,
-  q_to_score: QuestionMap<'a, QuestionEntry<'a, S>>,
-  grand_totals: UserMap<'a, u32>,
+pub type QuestionEntry<S> = (UserMap<u32>, IndexSet<'static, User, S, ArcFamily>);
+pub struct BitsetCorrSet<S: BitSet> {
+  pub(super) users: Arc<IndexedDomain<User>>,
+  pub(super) questions: Arc<IndexedDomain<Question>>,
+  pub(super) q_to_score: QuestionMap<QuestionEntry<S>>,
+  pub(super) targets: Targets,
+  pub(super) aggregation: Aggregation,
+  pub(super) min_users: usize,
 }
 
-pub type BvecCorrSet<'a> = BitsetCorrSet<'a, indexical::bitset::bitvec::BitVec>;
-pub type SimdCorrSet<'a> = BitsetCorrSet<'a, indexical::bitset::simd::SimdBitset<u64, 16>>;
+pub type BvecCorrSet = BitsetCorrSet<indexical::bitset::bitvec::BitVec>;
+pub type SimdCorrSet = BitsetCorrSet<indexical::bitset::simd::SimdBitset<u64, 16>>;
 
-impl<'a, S: BitSet + Send + Sync> CorrSetInner<'a> for BitsetCorrSet<'a, S> {
+impl<S: BitSet + Send + Sync> CorrSetInner for BitsetCorrSet<S> {
   type Q = QuestionIdx;
   type Scratch = ();
 
-  fn build(data: &'a [Row]) -> Self {
-    let (users, questions): (HashSet<_>, HashSet<_>) = data
-      .iter()
-      .map(|row| (UserRef(&row.user), QuestionRef(&row.question)))
-      .unzip();
-    let users = Arc::new(IndexedDomain::from_iter(users));
-    let questions = Arc::new(IndexedDomain::from_iter(questions));
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    let (users, questions) = (&data.users, &data.questions);
 
-    let mut q_to_score = QuestionMap::new(&questions, |_| {
-      (
-        UserMap::<'_, u32>::new(&users, |_| 0),
-        IndexSet::new(&users),
-      )
+    let mut q_to_score = QuestionMap::new(questions, |_| {
+      (UserMap::<u32>::new(users, |_| 0), IndexSet::new(users))
     });
-    for r in data {
-      let (q_idx, u_idx) = (
-        questions.index(&QuestionRef(&r.question)),
-        users.index(&UserRef(&r.user)),
-      );
-      let (scores, set) = q_to_score.get_mut(q_idx).unwrap();
-      scores.insert(u_idx, r.score);
-      set.insert(u_idx);
+    for e in &data.entries {
+      let (scores, set) = q_to_score.get_mut(e.question).unwrap();
+      scores.insert(e.user, e.score);
+      set.insert(e.user);
     }
 
-    let grand_totals = UserMap::new(&users, |u| {
-      q_to_score
-        .values()
-        .filter_map(|(scores, set)| set.contains(u).then_some(*scores.get(u).unwrap()))
-        .sum::<u32>()
-    });
-
     BitsetCorrSet {
-      questions,
+      users: users.clone(),
+      questions: questions.clone(),
       q_to_score,
-      grand_totals,
+      targets: Targets::new(&opts.target, data),
+      aggregation: Aggregation::new(opts, data),
+      min_users: opts.min_users,
     }
   }
 
-  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + Captures<'a> + '_ {
+  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + '_ {
     self.questions.indices()
   }
 
-  fn to_question(&self, q: Self::Q) -> &'a Question {
-    self.questions.value(q).0
+  fn to_question(&self, q: Self::Q) -> &Question {
+    self.questions.value(q)
   }
 
   fn init_scratch(&self) -> Self::Scratch {}
 
-  fn corr_set(&self, _: &mut (), qs: &[Self::Q]) -> f64 {
+  fn corr_set<M: Metric + ?Sized>(&self, metric: &M, _: &mut (), qs: &[Self::Q]) -> Corr {
+    let complete = self.aggregation.is_complete_case();
     let mut users = self.q_to_score[qs[0]].1.clone();
     for q in &qs[1..] {
-      users.intersect(&self.q_to_score[*q].1);
+      if complete {
+        users.intersect(&self.q_to_score[*q].1);
+      } else {
+        users.union(&self.q_to_score[*q].1);
+      }
     }
 
-    let (qs_scores, grand_scores): (Vec<_>, Vec<_>) = users
+    let (qs_scores, target_scores): (Vec<_>, Vec<_>) = users
       .indices()
-      .map(|u| {
-        let total = qs
-          .iter()
-          .map(|q| unsafe {
-            let (u_scores, _) = self.q_to_score.get_unchecked(*q);
-            *u_scores.get_unchecked(u)
-          })
-          .sum::<u32>();
-        let grand_total = unsafe { *self.grand_totals.get_unchecked(u) };
-        (total as f64, grand_total as f64)
+      .filter_map(|u| {
+        let scores = qs.iter().map(|q| unsafe {
+          let (u_scores, answered) = self.q_to_score.get_unchecked(*q);
+          let score = *u_scores.get_unchecked(u);
+          (*q, (complete || answered.contains(u)).then_some(score))
+        });
+        let (total, value) = unsafe { self.aggregation.try_combine_unchecked(scores) }?;
+        let target = unsafe { self.targets.get_unchecked(u, total) }?;
+        Some((value, target))
       })
       .unzip();
-    utils::correlation(&qs_scores, &grand_scores)
+    let n_users = qs_scores.len();
+    if n_users < self.min_users {
+      return Corr::too_few(n_users);
+    }
+    Corr {
+      r: metric.score(&qs_scores, &target_scores),
+      n_users,
+    }
   }
 }
 
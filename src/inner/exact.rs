@@ -0,0 +1,112 @@
+use super::{
+  alloc::{AllocCorrSet, UserSet},
+  BuildOptions, Corr, CorrSetInner,
+};
+use crate::{
+  dataset::QuestionIdx,
+  metric::{ExactSums, Metric},
+  Dataset, Question,
+};
+
+/// An [`AllocCorrSet`] that scores streamable metrics from exact integer sums
+/// of the `u32` scores, rather than converting each score to `f64` first.
+/// Other metrics, and targets or aggregators that aren't sums of scores, fall
+/// back to [`AllocCorrSet::corr_set_score`].
+pub struct ExactCorrSet {
+  pub(crate) inner: AllocCorrSet,
+}
+
+impl ExactCorrSet {
+  #[inline]
+  fn sums(&self, users: &UserSet, qs: &[QuestionIdx]) -> ExactSums {
+    let inner = &self.inner;
+    let mut sums = ExactSums::default();
+    for u in users.indices() {
+      let total = qs
+        .iter()
+        .map(|q| unsafe {
+          let (u_scores, _) = inner.q_to_score.get_unchecked(*q);
+          *u_scores.get_unchecked(u)
+        })
+        .sum::<u32>();
+      sums.push(total, unsafe { inner.targets.exact_unchecked(u, total) });
+    }
+    sums
+  }
+}
+
+impl CorrSetInner for ExactCorrSet {
+  type Q = QuestionIdx;
+  type Scratch = <AllocCorrSet as CorrSetInner>::Scratch;
+
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    ExactCorrSet {
+      inner: AllocCorrSet::build_with(data, opts),
+    }
+  }
+
+  #[inline]
+  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + '_ {
+    self.inner.iter_qs()
+  }
+
+  #[inline]
+  fn to_question(&self, q: Self::Q) -> &Question {
+    self.inner.to_question(q)
+  }
+
+  #[inline]
+  fn init_scratch(&self) -> Self::Scratch {
+    self.inner.init_scratch()
+  }
+
+  #[inline]
+  fn corr_set<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    (qs_scores, target_scores, users): &mut Self::Scratch,
+    qs: &[Self::Q],
+  ) -> Corr {
+    self.inner.init_users(users, qs[0]);
+    for q in &qs[1..] {
+      self.inner.add_users(users, *q);
+    }
+
+    match metric.streaming() {
+      Some(score) if self.inner.targets.is_integral() && self.inner.aggregation.is_total() => {
+        let sums = self.sums(users, qs);
+        let n_users = sums.n as usize;
+        if n_users < self.inner.min_users {
+          return Corr::too_few(n_users);
+        }
+        Corr {
+          r: score(&sums.moments()),
+          n_users,
+        }
+      }
+      _ => self
+        .inner
+        .corr_set_score(metric, qs_scores, target_scores, users, qs),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{inner::test_utils::mock_data, metric::Pearson, test_inner};
+
+  test_inner!(exact, ExactCorrSet);
+
+  #[test]
+  fn matches_alloc() {
+    let cs = ExactCorrSet::build(&mock_data());
+    let mut scratch = cs.init_scratch();
+    for qs in cs.combinations(2) {
+      let exact = cs.corr_set(&Pearson, &mut scratch, &qs);
+      let alloc = cs.inner.corr_set(&Pearson, &mut scratch, &qs);
+      assert_eq!(exact.n_users, alloc.n_users);
+      assert!((exact.r - alloc.r).abs() < 1e-12 || (exact.r.is_nan() && alloc.r.is_nan()));
+    }
+  }
+}
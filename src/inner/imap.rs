@@ -1,83 +1,79 @@
 use std::sync::Arc;
 
-use super::{
-  indexed::{QuestionIdx, QuestionRef, UserRef},
-  CorrSetInner,
-};
+use super::{BuildOptions, Corr, CorrSetInner};
 use crate::{
-  utils::{self, Captures},
-  Question, Row,
+  aggregate::Aggregation, dataset::QuestionIdx, metric::Metric, target::Targets, Dataset, Question,
+  User,
 };
-use fxhash::FxHashSet as HashSet;
 use indexical::{map::DenseArcIndexMap as DenseIndexMap, IndexedDomain};
 
-pub type QuestionMap<'a, T> = DenseIndexMap<'a, QuestionRef<'a>, T>;
-pub type UserMap<'a, T> = DenseIndexMap<'a, UserRef<'a>, T>;
+pub type QuestionMap<T> = DenseIndexMap<'static, Question, T>;
+pub type UserMap<T> = DenseIndexMap<'static, User, T>;
 
-pub struct ImapCorrSet<'a> {
-  questions: Arc<IndexedDomain<QuestionRef<'a>>>,
-  users: Arc<IndexedDomain<UserRef<'a>>>,
-  q_to_score: QuestionMap<'a, UserMap<'a, Option<u32>>>,
-  grand_totals: UserMap<'a, u32>,
+pub struct ImapCorrSet {
+  pub(super) questions: Arc<IndexedDomain<Question>>,
+  pub(super) users: Arc<IndexedDomain<User>>,
+  pub(super) q_to_score: QuestionMap<UserMap<Option<u32>>>,
+  pub(super) targets: Targets,
+  pub(super) aggregation: Aggregation,
+  pub(super) min_users: usize,
 }
 
-impl<'a> CorrSetInner<'a> for ImapCorrSet<'a> {
+impl CorrSetInner for ImapCorrSet {
   type Q = QuestionIdx;
   type Scratch = ();
 
-  fn build(data: &'a [Row]) -> Self {
-    let (users, questions): (HashSet<_>, HashSet<_>) = data
-      .iter()
-      .map(|row| (UserRef(&row.user), QuestionRef(&row.question)))
-      .unzip();
-    let users = Arc::new(IndexedDomain::from_iter(users));
-    let questions = Arc::new(IndexedDomain::from_iter(questions));
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    let (users, questions) = (&data.users, &data.questions);
 
-    let mut q_to_score = QuestionMap::new(&questions, |_| {
-      UserMap::<'_, Option<u32>>::new(&users, |_| None)
-    });
-    for r in data {
+    let mut q_to_score =
+      QuestionMap::new(questions, |_| UserMap::<Option<u32>>::new(users, |_| None));
+    for e in &data.entries {
       q_to_score
-        .get_mut(&QuestionRef(&r.question))
+        .get_mut(e.question)
         .unwrap()
-        .insert(UserRef(&r.user), Some(r.score));
+        .insert(e.user, Some(e.score));
     }
 
-    let grand_totals = UserMap::new(&users, |u| {
-      q_to_score.values().filter_map(|v| v[u]).sum::<u32>()
-    });
     ImapCorrSet {
-      questions,
-      users,
+      questions: questions.clone(),
+      users: users.clone(),
       q_to_score,
-      grand_totals,
+      targets: Targets::new(&opts.target, data),
+      aggregation: Aggregation::new(opts, data),
+      min_users: opts.min_users,
     }
   }
 
-  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + Captures<'a> + '_ {
+  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + '_ {
     self.questions.indices()
   }
 
-  fn to_question(&self, q: Self::Q) -> &'a Question {
-    self.questions.value(q).0
+  fn to_question(&self, q: Self::Q) -> &Question {
+    self.questions.value(q)
   }
 
   fn init_scratch(&self) -> Self::Scratch {}
 
-  fn corr_set(&self, _: &mut (), qs: &[Self::Q]) -> f64 {
-    let (qs_scores, grand_scores): (Vec<_>, Vec<_>) = self
+  fn corr_set<M: Metric + ?Sized>(&self, metric: &M, _: &mut (), qs: &[Self::Q]) -> Corr {
+    let (qs_scores, target_scores): (Vec<_>, Vec<_>) = self
       .users
       .indices()
       .filter_map(|u| {
-        let total = qs
-          .iter()
-          .map(|q| self.q_to_score[*q][u])
-          .sum::<Option<u32>>()?;
-        let grand_total = self.grand_totals[u];
-        Some((total as f64, grand_total as f64))
+        let scores = qs.iter().map(|q| (*q, self.q_to_score[*q][u]));
+        let (total, value) = self.aggregation.try_combine(scores)?;
+        let target = self.targets.get(u, total)?;
+        Some((value, target))
       })
       .unzip();
-    utils::correlation(&qs_scores, &grand_scores)
+    let n_users = qs_scores.len();
+    if n_users < self.min_users {
+      return Corr::too_few(n_users);
+    }
+    Corr {
+      r: metric.score(&qs_scores, &target_scores),
+      n_users,
+    }
   }
 }
 
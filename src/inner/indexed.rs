@@ -1,102 +1,73 @@
+use std::sync::Arc;
+
 use crate::{
-  utils::{self, Captures},
-  Question, Row, User,
+  aggregate::Aggregation,
+  dataset::{QuestionIdx, UserIdx},
+  metric::Metric,
+  target::Targets,
+  utils, Dataset, Question, User,
 };
 
-use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
-use indexical::{define_index_type, IndexedDomain};
-
-use super::CorrSetInner;
-
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub struct QuestionRef<'a>(pub &'a Question);
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub struct UserRef<'a>(pub &'a User);
+use fxhash::FxHashMap as HashMap;
+use indexical::IndexedDomain;
 
-define_index_type! {
-  pub struct QuestionIdx for QuestionRef<'a> = u16;
-  DISABLE_MAX_INDEX_CHECK = cfg!(not(debug_assertions));
-}
-
-define_index_type! {
-  pub struct UserIdx for UserRef<'a> = u32;
-  DISABLE_MAX_INDEX_CHECK = cfg!(not(debug_assertions));
-}
+use super::{BuildOptions, Corr, CorrSetInner};
 
-pub struct IndexedCorrSet<'a> {
-  users: IndexedDomain<UserRef<'a>>,
-  questions: IndexedDomain<QuestionRef<'a>>,
-  q_to_score: HashMap<QuestionIdx, HashMap<UserIdx, u32>>,
-  grand_totals: HashMap<UserIdx, u32>,
+pub struct IndexedCorrSet {
+  pub(super) users: Arc<IndexedDomain<User>>,
+  pub(super) questions: Arc<IndexedDomain<Question>>,
+  pub(super) q_to_score: HashMap<QuestionIdx, HashMap<UserIdx, u32>>,
+  pub(super) targets: Targets,
+  pub(super) aggregation: Aggregation,
+  pub(super) min_users: usize,
 }
 
-impl<'a> CorrSetInner<'a> for IndexedCorrSet<'a> {
+impl CorrSetInner for IndexedCorrSet {
   type Q = QuestionIdx;
   type Scratch = ();
 
-  fn build(data: &'a [Row]) -> Self {
-    let (users, questions): (HashSet<_>, HashSet<_>) = data
-      .iter()
-      .map(|row| (UserRef(&row.user), QuestionRef(&row.question)))
-      .unzip();
-
-    let users = IndexedDomain::from_iter(users);
-    let questions = IndexedDomain::from_iter(questions);
-
-    let q_to_score = utils::group_by(data.iter().map(|r| {
-      (
-        questions.index(&(QuestionRef(&r.question))),
-        users.index(&(UserRef(&r.user))),
-        r.score,
-      )
-    }));
-    let u_to_score = utils::group_by(data.iter().map(|r| {
-      (
-        users.index(&(UserRef(&r.user))),
-        questions.index(&(QuestionRef(&r.question))),
-        r.score,
-      )
-    }));
-    let grand_totals = u_to_score
-      .iter()
-      .map(|(user, scores)| {
-        let total = scores.values().sum::<u32>();
-        (*user, total)
-      })
-      .collect::<HashMap<_, _>>();
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    let q_to_score = utils::group_by(data.entries.iter().map(|e| (e.question, e.user, e.score)));
 
     IndexedCorrSet {
-      users,
-      questions,
+      users: data.users.clone(),
+      questions: data.questions.clone(),
       q_to_score,
-      grand_totals,
+      targets: Targets::new(&opts.target, data),
+      aggregation: Aggregation::new(opts, data),
+      min_users: opts.min_users,
     }
   }
 
-  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + Captures<'a> + '_ {
+  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + '_ {
     self.questions.indices()
   }
 
-  fn to_question(&self, q: Self::Q) -> &'a Question {
-    self.questions.value(q).0
+  fn to_question(&self, q: Self::Q) -> &Question {
+    self.questions.value(q)
   }
 
   fn init_scratch(&self) -> Self::Scratch {}
 
-  fn corr_set(&self, _: &mut (), qs: &[Self::Q]) -> f64 {
-    let (qs_scores, grand_scores): (Vec<_>, Vec<_>) = self
+  fn corr_set<M: Metric + ?Sized>(&self, metric: &M, _: &mut (), qs: &[Self::Q]) -> Corr {
+    let (qs_scores, target_scores): (Vec<_>, Vec<_>) = self
       .users
       .indices()
       .filter_map(|u| {
-        let total = qs
-          .iter()
-          .map(|q| self.q_to_score[q].get(&u).copied())
-          .sum::<Option<u32>>()?;
-        let grand_total = self.grand_totals[&u];
-        Some((total as f64, grand_total as f64))
+        let scores = qs.iter().map(|q| (*q, self.q_to_score[q].get(&u).copied()));
+        let (total, value) = self.aggregation.try_combine(scores)?;
+        let target = self.targets.get(u, total)?;
+        Some((value, target))
       })
       .unzip();
-    utils::correlation(&qs_scores, &grand_scores)
+    let n_users = qs_scores.len();
+    if n_users < self.min_users {
+      return Corr::too_few(n_users);
+    }
+    Corr {
+      r: metric.score(&qs_scores, &target_scores),
+      n_users,
+    }
   }
 }
 
@@ -0,0 +1,577 @@
+//! A built [`AllocCorrSet`] persisted to a versioned binary file, and an inner
+//! that reads the scores and user sets directly out of the mapped file.
+//!
+//! Layout, with all numbers little-endian and every section 8-byte aligned:
+//! - a header of `HEADER_LEN` `u64`s: magic, version, user count, question
+//!   count, words per user set, flags, the [`Missing`] policy as a number,
+//!   [`BuildOptions::min_users`], the [`Source`] the index was built from,
+//!   and the byte offsets of the sections
+//!   below. Bit 0 of the flags is set if the target is
+//!   [`Target::Rest`](crate::Target::Rest), and
+//!   the bits above it say how scores are combined,
+//! - scores: for each question, a `u32` per user (0 if unanswered),
+//! - user sets: for each question, a bitset of `u64` words over users,
+//! - targets: an `f64` per user, as stored by [`Targets`](crate::target::Targets),
+//! - transforms: a scale, offset and imputed score `f64` per question, as
+//!   stored by [`Aggregation`](crate::aggregate::Aggregation),
+//! - names: the users, the questions, the target, the aggregator, then the
+//!   missing-answer policy as written by their `Display`, each a `u32` byte
+//!   length and UTF-8 bytes.
+
+use std::{
+  fs::{self, File},
+  io::{self, BufWriter, Write},
+  mem::size_of,
+  path::Path,
+  process, slice,
+  sync::Arc,
+  time::UNIX_EPOCH,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use indexical::IndexedDomain;
+use memmap2::Mmap;
+
+use super::{alloc::AllocCorrSet, BuildOptions, Corr, CorrSetInner};
+use crate::{
+  aggregate::{Combine, Missing, Transform},
+  dataset::{IndexOverflow, QuestionIdx},
+  metric::{Metric, Moments},
+  target, Dataset, Question, User,
+};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"CORRSET\0");
+const VERSION: u64 = 5;
+const HEADER_LEN: usize = 15;
+const REST: u64 = 1;
+const COMBINE_SHIFT: u64 = 1;
+
+fn align8(n: usize) -> usize {
+  (n + 7) & !7
+}
+
+/// The bytes taken by `count` values of `T`, padded to 8 bytes. `None` if the
+/// count or the size overflows.
+fn section_len<T>(count: Option<usize>) -> Option<usize> {
+  let len = count?.checked_mul(size_of::<T>())?;
+  Some(len.checked_add(7)? & !7)
+}
+
+/// The data file an index was built from, identified by its length and
+/// modification time, so that an index cached next to the data can be
+/// rebuilt once the data changes. The default identifies no file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Source {
+  pub len: u64,
+  /// Nanoseconds since the Unix epoch.
+  pub modified: u64,
+}
+
+impl Source {
+  pub fn of(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref();
+    let metadata =
+      fs::metadata(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok(Source {
+      len: metadata.len(),
+      modified: modified.as_nanos() as u64,
+    })
+  }
+}
+
+struct Layout {
+  num_users: usize,
+  num_questions: usize,
+  words: usize,
+  flags: u64,
+  missing: u64,
+  min_users: u64,
+  source: Source,
+  scores: usize,
+  sets: usize,
+  targets: usize,
+  transforms: usize,
+  names: usize,
+}
+
+impl Layout {
+  /// Lays out the sections for the given header fields. `None` if an offset
+  /// overflows, which only a corrupt header can cause.
+  fn new(
+    num_users: usize,
+    num_questions: usize,
+    flags: u64,
+    missing: u64,
+    min_users: u64,
+    source: Source,
+  ) -> Option<Self> {
+    let words = num_users / 64 + usize::from(num_users % 64 != 0);
+    let scores = HEADER_LEN * size_of::<u64>();
+    let sets = scores.checked_add(section_len::<u32>(num_questions.checked_mul(num_users))?)?;
+    let targets = sets.checked_add(section_len::<u64>(num_questions.checked_mul(words))?)?;
+    let transforms = targets.checked_add(section_len::<f64>(Some(num_users))?)?;
+    let names = transforms.checked_add(section_len::<Transform>(Some(num_questions))?)?;
+    Some(Layout {
+      num_users,
+      num_questions,
+      words,
+      flags,
+      missing,
+      min_users,
+      source,
+      scores,
+      sets,
+      targets,
+      transforms,
+      names,
+    })
+  }
+
+  fn combine(&self) -> Option<Combine> {
+    Combine::ALL
+      .get((self.flags >> COMBINE_SHIFT) as usize)
+      .copied()
+  }
+
+  fn header(&self) -> [u64; HEADER_LEN] {
+    [
+      MAGIC,
+      VERSION,
+      self.num_users as u64,
+      self.num_questions as u64,
+      self.words as u64,
+      self.flags,
+      self.missing,
+      self.min_users,
+      self.source.len,
+      self.source.modified,
+      self.scores as u64,
+      self.sets as u64,
+      self.targets as u64,
+      self.transforms as u64,
+      self.names as u64,
+    ]
+  }
+}
+
+impl AllocCorrSet {
+  /// Writes the built index to `w`, noting that it was built from `source`.
+  pub fn write_index(&self, source: Source, mut w: impl Write) -> io::Result<()> {
+    let combine = Combine::ALL
+      .iter()
+      .position(|c| *c == self.aggregation.combine());
+    let mut flags = (combine.unwrap() as u64) << COMBINE_SHIFT;
+    if self.targets.is_rest() {
+      flags |= REST;
+    }
+    let missing = self.aggregation.missing();
+    let layout = Layout::new(
+      self.users.len(),
+      self.questions.len(),
+      flags,
+      missing.code(),
+      self.min_users as u64,
+      source,
+    )
+    .expect("a built index fits in memory");
+    let pad = |w: &mut dyn Write, n: usize| w.write_all(&[0; 8][..align8(n) - n]);
+
+    for x in layout.header() {
+      w.write_all(&x.to_le_bytes())?;
+    }
+    for (scores, _) in self.q_to_score.values() {
+      for score in scores.values() {
+        w.write_all(&score.to_le_bytes())?;
+      }
+    }
+    pad(
+      &mut w,
+      layout.num_questions * layout.num_users * size_of::<u32>(),
+    )?;
+    let mut words = vec![0u64; layout.words];
+    for (_, set) in self.q_to_score.values() {
+      words.fill(0);
+      for u in set.indices() {
+        words[u.index() / 64] |= 1 << (u.index() % 64);
+      }
+      for word in &words {
+        w.write_all(&word.to_le_bytes())?;
+      }
+    }
+    for target in self.targets.values() {
+      w.write_all(&target.to_le_bytes())?;
+    }
+    for t in self.aggregation.transforms() {
+      for x in [t.scale, t.offset, t.fill] {
+        w.write_all(&x.to_le_bytes())?;
+      }
+    }
+    let target = self.targets.target().to_string();
+    let aggregator = self.aggregation.aggregator().to_string();
+    let missing = missing.to_string();
+    let names = self.users.iter().map(|u| &u.0);
+    let names = names.chain(self.questions.iter().map(|q| &q.0));
+    for name in names.chain([&target, &aggregator, &missing]) {
+      w.write_all(&(name.len() as u32).to_le_bytes())?;
+      w.write_all(name.as_bytes())?;
+    }
+    w.flush()
+  }
+
+  /// Writes the built index to `path`, to be opened later with
+  /// [`MappedCorrSet::open`], noting that it was built from `source`. The
+  /// index is written to a temporary file that then replaces `path`, so
+  /// processes that mapped an older index keep reading it unchanged.
+  pub fn save(&self, path: impl AsRef<Path>, source: Source) -> Result<()> {
+    let path = path.as_ref();
+    let tmp = path.with_extension(format!("{}.tmp", process::id()));
+    let file = File::create(&tmp).with_context(|| format!("failed to create {}", tmp.display()))?;
+    let written = self
+      .write_index(source, BufWriter::new(file))
+      .and_then(|()| fs::rename(&tmp, path));
+    if written.is_err() {
+      let _ = fs::remove_file(&tmp);
+    }
+    written.with_context(|| format!("failed to write {}", path.display()))
+  }
+}
+
+enum Storage {
+  Owned(Vec<u64>),
+  Mapped(Mmap),
+}
+
+impl Storage {
+  fn bytes(&self) -> &[u8] {
+    match self {
+      Storage::Owned(words) => unsafe {
+        slice::from_raw_parts(words.as_ptr().cast(), words.len() * size_of::<u64>())
+      },
+      Storage::Mapped(mmap) => mmap,
+    }
+  }
+}
+
+/// An [`AllocCorrSet`] read from a file written by [`AllocCorrSet::save`].
+///
+/// Only the user and question names are copied out of the file. Scores, user
+/// sets, targets and transforms are read in place, so opening an index is
+/// roughly as fast as reading its names.
+pub struct MappedCorrSet {
+  storage: Storage,
+  layout: Layout,
+  combine: Combine,
+  policy: Missing,
+  pub users: Arc<IndexedDomain<User>>,
+  pub questions: Arc<IndexedDomain<Question>>,
+  /// The [`Target`](crate::Target) the index was built with, as written by its `Display`.
+  pub target: String,
+  /// The [`Aggregator`](crate::Aggregator) the index was built with, as
+  /// written by its `Display`.
+  pub aggregator: String,
+  /// The [`Missing`] policy the index was built with, as written by its
+  /// `Display`.
+  pub missing: String,
+  /// Sets scored by fewer users are skipped, as by
+  /// [`BuildOptions::min_users`]. Opened as the value the index was built
+  /// with, but it doesn't change the index, so it can be set freely.
+  pub min_users: usize,
+  /// The data file the index was built from.
+  pub source: Source,
+}
+
+impl MappedCorrSet {
+  pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    // SAFETY: the index is treated as read-only. `AllocCorrSet::save`
+    // replaces indices rather than modifying them, so we assume no other
+    // process modifies it while mapped.
+    let mmap = unsafe { Mmap::map(&file)? };
+    Self::from_storage(Storage::Mapped(mmap))
+      .with_context(|| format!("failed to read index {}", path.display()))
+  }
+
+  fn from_storage(storage: Storage) -> Result<Self> {
+    ensure!(
+      cfg!(target_endian = "little"),
+      "indices can only be mapped on little-endian targets"
+    );
+    let bytes = storage.bytes();
+    ensure!(
+      bytes.len() >= HEADER_LEN * size_of::<u64>() && bytes.as_ptr() as usize % 8 == 0,
+      "file is too short to be an index"
+    );
+    let header = unsafe { slice::from_raw_parts(bytes.as_ptr().cast::<u64>(), HEADER_LEN) };
+    ensure!(header[0] == MAGIC, "not an index file");
+    if header[1] != VERSION {
+      bail!(
+        "index has version {}, expected version {VERSION}",
+        header[1]
+      );
+    }
+
+    let source = Source {
+      len: header[8],
+      modified: header[9],
+    };
+    let layout = Layout::new(
+      header[2] as usize,
+      header[3] as usize,
+      header[5],
+      header[6],
+      header[7],
+      source,
+    )
+    .filter(|layout| layout.header() == header && layout.names <= bytes.len())
+    .context("index header is corrupt")?;
+    let combine = layout.combine().context("index header is corrupt")?;
+    IndexOverflow::check_users(layout.num_users)?;
+    IndexOverflow::check_questions(layout.num_questions)?;
+
+    let mut names = &bytes[layout.names..];
+    let mut read_name = || -> Result<String> {
+      ensure!(names.len() >= 4, "index is truncated");
+      let (len, rest) = names.split_at(4);
+      let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+      ensure!(rest.len() >= len, "index is truncated");
+      let (name, rest) = rest.split_at(len);
+      names = rest;
+      Ok(String::from_utf8(name.to_vec())?)
+    };
+    let users = (0..layout.num_users)
+      .map(|_| read_name().map(User))
+      .collect::<Result<Vec<_>>>()?;
+    let questions = (0..layout.num_questions)
+      .map(|_| read_name().map(Question))
+      .collect::<Result<Vec<_>>>()?;
+    let target = read_name()?;
+    let aggregator = read_name()?;
+    let missing = read_name()?;
+
+    Ok(MappedCorrSet {
+      layout,
+      combine,
+      policy: Missing::from_code(header[6]),
+      users: Arc::new(IndexedDomain::from_iter(users)),
+      questions: Arc::new(IndexedDomain::from_iter(questions)),
+      target,
+      aggregator,
+      missing,
+      min_users: header[7] as usize,
+      source,
+      storage,
+    })
+  }
+
+  #[inline]
+  fn section<T>(&self, offset: usize, len: usize) -> &[T] {
+    // SAFETY: `from_storage` checked that every section lies within the
+    // storage, and sections are aligned for `T` by construction.
+    unsafe { slice::from_raw_parts(self.storage.bytes().as_ptr().add(offset).cast(), len) }
+  }
+
+  /// Scores for every question, indexed by `q * num_users + u`.
+  #[inline]
+  fn scores(&self) -> &[u32] {
+    self.section(
+      self.layout.scores,
+      self.layout.num_questions * self.layout.num_users,
+    )
+  }
+
+  #[inline]
+  fn user_set(&self, q: QuestionIdx) -> &[u64] {
+    let n = self.layout.words;
+    &self.section(self.layout.sets, self.layout.num_questions * n)[q.index() * n..][..n]
+  }
+
+  #[inline]
+  fn targets(&self) -> &[f64] {
+    self.section(self.layout.targets, self.layout.num_users)
+  }
+
+  /// Each question's scale and offset.
+  #[inline]
+  fn transforms(&self) -> &[Transform] {
+    self.section(self.layout.transforms, self.layout.num_questions)
+  }
+}
+
+impl CorrSetInner for MappedCorrSet {
+  type Q = QuestionIdx;
+  type Scratch = (Vec<f64>, Vec<f64>, Vec<u64>);
+
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    let mut bytes = Vec::new();
+    AllocCorrSet::build_with(data, opts)
+      .write_index(Source::default(), &mut bytes)
+      .unwrap();
+    let mut words = vec![0u64; (bytes.len() + 7) / 8];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(8)) {
+      let mut buf = [0; 8];
+      buf[..chunk.len()].copy_from_slice(chunk);
+      *word = u64::from_le_bytes(buf);
+    }
+    Self::from_storage(Storage::Owned(words)).unwrap()
+  }
+
+  #[inline]
+  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + '_ {
+    self.questions.indices()
+  }
+
+  #[inline]
+  fn to_question(&self, q: Self::Q) -> &Question {
+    self.questions.value(q)
+  }
+
+  #[inline]
+  fn init_scratch(&self) -> Self::Scratch {
+    (Vec::new(), Vec::new(), vec![0; self.layout.words])
+  }
+
+  #[inline]
+  fn corr_set<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    (qs_scores, target_scores, users): &mut Self::Scratch,
+    qs: &[Self::Q],
+  ) -> Corr {
+    let complete = self.policy == Missing::CompleteCase;
+    users.copy_from_slice(self.user_set(qs[0]));
+    for q in &qs[1..] {
+      for (word, other) in users.iter_mut().zip(self.user_set(*q)) {
+        if complete {
+          *word &= other;
+        } else {
+          *word |= other;
+        }
+      }
+    }
+
+    let (scores, num_users) = (self.scores(), self.layout.num_users);
+    let (targets, rest) = (self.targets(), self.layout.flags & REST != 0);
+    let (transforms, combine) = (self.transforms(), self.combine);
+    let stream = metric.streaming();
+    if stream.is_none() && qs_scores.len() < num_users {
+      qs_scores.resize(num_users, 0.);
+      target_scores.resize(num_users, 0.);
+    }
+
+    let mut moments = Moments::default();
+    let mut n = 0;
+    for (i, word) in users.iter().enumerate() {
+      let mut word = *word;
+      while word != 0 {
+        let u = i * 64 + word.trailing_zeros() as usize;
+        word &= word - 1;
+        let scores = qs.iter().map(|q| unsafe {
+          let score = *scores.get_unchecked(q.index() * num_users + u);
+          let answered = complete || self.user_set(*q)[u / 64] & (1 << (u % 64)) != 0;
+          (
+            answered.then_some(score),
+            *transforms.get_unchecked(q.index()),
+          )
+        });
+        let Some((total, value)) = combine.apply(self.policy, scores) else {
+          continue;
+        };
+        let Some(target) = target::resolve(unsafe { *targets.get_unchecked(u) }, rest, total)
+        else {
+          continue;
+        };
+        if stream.is_some() {
+          moments.push(value, target);
+        } else {
+          unsafe {
+            *qs_scores.get_unchecked_mut(n) = value;
+            *target_scores.get_unchecked_mut(n) = target;
+          }
+        }
+        n += 1;
+      }
+    }
+    if n < self.min_users {
+      return Corr::too_few(n);
+    }
+    let r = match stream {
+      Some(score) => score(&moments),
+      None => metric.score(&qs_scores[..n], &target_scores[..n]),
+    };
+    Corr { r, n_users: n }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    inner::test_utils::mock_data, metric::Pearson, test_inner, Aggregator, Missing, Target,
+  };
+
+  test_inner!(mmap, MappedCorrSet);
+
+  #[test]
+  fn save_and_open() {
+    let data = mock_data();
+    let path = std::env::temp_dir().join(format!("corrset-{}.idx", std::process::id()));
+    let source = Source {
+      len: 1,
+      modified: 2,
+    };
+    AllocCorrSet::build(&data).save(&path, source).unwrap();
+    let cs = MappedCorrSet::open(&path).unwrap();
+    assert_eq!((cs.source, cs.min_users), (source, 0));
+    // Replacing the index leaves the one already mapped as it was.
+    let opts = BuildOptions {
+      target: Target::Rest,
+      ..Default::default()
+    };
+    AllocCorrSet::build_with(&data, &opts)
+      .save(&path, source)
+      .unwrap();
+    assert_eq!(MappedCorrSet::open(&path).unwrap().target, "rest");
+    std::fs::remove_file(&path).unwrap();
+
+    let alloc = AllocCorrSet::build(&data);
+    let qs = data.questions.indices().take(2).collect::<Vec<_>>();
+    assert_eq!(
+      cs.corr_set(&Pearson, &mut cs.init_scratch(), &qs),
+      alloc.corr_set(&Pearson, &mut alloc.init_scratch(), &qs)
+    );
+    assert_eq!(cs.to_question(qs[1]), alloc.to_question(qs[1]));
+    assert_eq!(cs.target, "grand-total");
+
+    let opts = BuildOptions {
+      target: Target::Rest,
+      aggregator: Aggregator::ZSum,
+      missing: Missing::AtLeast(1),
+      min_users: 3,
+    };
+    let rest = AllocCorrSet::build_with(&data, &opts);
+    let mapped = MappedCorrSet::build_with(&data, &opts);
+    assert_eq!(
+      (&*mapped.target, &*mapped.aggregator, &*mapped.missing),
+      ("rest", "z-sum", "at-least:1")
+    );
+    assert_eq!(mapped.min_users, 3);
+    assert_eq!(
+      mapped.corr_set(&Pearson, &mut mapped.init_scratch(), &qs),
+      rest.corr_set(&Pearson, &mut rest.init_scratch(), &qs)
+    );
+
+    assert!(MappedCorrSet::from_storage(Storage::Owned(vec![0; 16])).is_err());
+  }
+
+  #[test]
+  fn huge_header() {
+    let mut words = vec![0; HEADER_LEN];
+    words[..4].copy_from_slice(&[MAGIC, VERSION, u64::MAX / 2, u64::MAX / 2]);
+    let err = MappedCorrSet::from_storage(Storage::Owned(words))
+      .err()
+      .unwrap();
+    assert_eq!(err.to_string(), "index header is corrupt");
+  }
+}
@@ -1,58 +1,92 @@
-use crate::{utils, Question, Row};
+use std::ops::Range;
+
+use crate::{
+  aggregate::{Aggregator, Missing},
+  combinations::Combinations,
+  metric::Metric,
+  target::Target,
+  utils, Dataset, Question,
+};
 use itertools::Itertools;
 
 pub mod alloc;
 pub mod basic;
 pub mod bchecks;
 pub mod bitset;
+pub mod exact;
 pub mod imap;
 pub mod indexed;
+pub mod mmap;
+pub mod outcome;
 #[cfg(test)]
-mod test_utils;
+pub(crate) mod test_utils;
+pub mod update;
 
-pub trait CorrSetInner<'a>: Send + Sync + Sized {
-  type Q: Send + Clone;
-  type Scratch;
-  fn build(data: &'a [Row]) -> Self;
-  fn iter_qs(&self) -> impl Iterator<Item = Self::Q> + Send + '_;
-  fn to_question(&self, q: Self::Q) -> &'a Question;
-  fn init_scratch(&self) -> Self::Scratch;
-  fn corr_set(&self, scratch: &mut Self::Scratch, qs: &[Self::Q]) -> f64;
-  fn combinations<'b>(
-    &'b self,
-    k: usize,
-  ) -> impl Iterator<Item = Vec<<Self as CorrSetInner<'a>>::Q>> + Send + 'b
-  where
-    'a: 'b,
-  {
-    utils::with_pb(self.iter_qs().count(), k, self.iter_qs().combinations(k))
-  }
+/// The score of a question set under a [`Metric`], along with the number of
+/// users scored: those the [`Missing`] policy kept and who have a target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Corr {
+  pub r: f64,
+  pub n_users: usize,
 }
 
-#[macro_export]
-macro_rules! dispatch_inner_method {
-  ($key:expr, $f:ident, $($arg:expr),*) => {{
-    match $key {
-      "0_basic" => $f::<$crate::inner::basic::BasicCorrSet>($($arg),*),
-      "1_indexed" => $f::<$crate::inner::indexed::IndexedCorrSet>($($arg),*),
-      "2_imap" => $f::<$crate::inner::imap::ImapCorrSet>($($arg),*),
-      "3_bchecks" => $f::<$crate::inner::bchecks::BchecksCorrSet>($($arg),*),
-      "4_bitset" => $f::<$crate::inner::bitset::BvecCorrSet>($($arg),*),
-      "5_simd" => $f::<$crate::inner::bitset::SimdCorrSet>($($arg),*),
-      "6_alloc" => $f::<$crate::inner::alloc::AllocCorrSet>($($arg),*),
-      k => unimplemented!("{k}"),
+impl Corr {
+  /// A set scored by fewer than [`BuildOptions::min_users`] users, which is
+  /// skipped like a set with a NaN score.
+  #[inline]
+  pub(crate) fn too_few(n_users: usize) -> Self {
+    Corr {
+      r: f64::NAN,
+      n_users,
     }
-  }};
+  }
+}
+
+/// What an index scores question sets by.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BuildOptions {
+  /// What each user's value on a set is correlated against.
+  pub target: Target,
+  /// How a user's scores on a set's questions are combined into that value.
+  pub aggregator: Aggregator,
+  /// What to do about users who didn't answer every question of a set.
+  pub missing: Missing,
+  /// Sets scored by fewer users are skipped, since correlations over a
+  /// handful of users are noise.
+  pub min_users: usize,
 }
 
-pub fn inner_names() -> Vec<String> {
-  vec![
-    "0_basic".into(),
-    "1_indexed".into(),
-    "2_imap".into(),
-    "3_bchecks".into(),
-    "4_bitset".into(),
-    "5_simd".into(),
-    "6_alloc".into(),
-  ]
+pub trait CorrSetInner: Send + Sync + Sized {
+  /// A question. Ordered so that sets with equal scores can be ranked.
+  type Q: Send + Clone + Ord;
+  type Scratch;
+  /// Builds an index that correlates sets' totals with users' grand totals.
+  fn build(data: &Dataset) -> Self {
+    Self::build_with(data, &BuildOptions::default())
+  }
+  /// Builds an index that scores sets as `opts` says.
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self;
+  fn iter_qs(&self) -> impl Iterator<Item = Self::Q> + Send + '_;
+  fn to_question(&self, q: Self::Q) -> &Question;
+  fn init_scratch(&self) -> Self::Scratch;
+  fn corr_set<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    scratch: &mut Self::Scratch,
+    qs: &[Self::Q],
+  ) -> Corr;
+  fn combinations(&self, k: usize) -> impl Iterator<Item = Vec<Self::Q>> + Send + '_ {
+    utils::with_pb(self.iter_qs().count(), k, self.iter_qs().combinations(k))
+  }
+  /// The sets of `k` questions whose positions in [`combinations`](Self::combinations)
+  /// are in `ranks`, in the same order.
+  fn combination_range(
+    &self,
+    k: usize,
+    ranks: Range<usize>,
+  ) -> impl Iterator<Item = Vec<Self::Q>> + Send + '_ {
+    let qs = self.iter_qs().collect_vec();
+    Combinations::range(qs.len(), k, ranks)
+      .map(move |comb| comb.into_iter().map(|i| qs[i].clone()).collect())
+  }
 }
@@ -0,0 +1,109 @@
+use super::{
+  alloc::{AllocCorrSet, UserSet},
+  BuildOptions, Corr, CorrSetInner,
+};
+use crate::{dataset::QuestionIdx, metric::Metric, Dataset, Question};
+
+/// An [`AllocCorrSet`] for predicting an external outcome. The users without
+/// an outcome are found once when building, and left out of every
+/// intersection up front, rather than skipped user by user when scoring.
+/// Targets other than [`Target::Outcome`](crate::Target::Outcome) are scored
+/// as by [`AllocCorrSet`].
+pub struct OutcomeCorrSet {
+  pub(crate) inner: AllocCorrSet,
+  /// The users with an outcome, for an outcome target.
+  pub with_outcome: Option<UserSet>,
+}
+
+impl CorrSetInner for OutcomeCorrSet {
+  type Q = QuestionIdx;
+  type Scratch = <AllocCorrSet as CorrSetInner>::Scratch;
+
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self {
+    let inner = AllocCorrSet::build_with(data, opts);
+    OutcomeCorrSet {
+      with_outcome: inner.targets.users(&inner.users),
+      inner,
+    }
+  }
+
+  #[inline]
+  fn iter_qs(&self) -> impl Iterator<Item = QuestionIdx> + '_ {
+    self.inner.iter_qs()
+  }
+
+  #[inline]
+  fn to_question(&self, q: Self::Q) -> &Question {
+    self.inner.to_question(q)
+  }
+
+  #[inline]
+  fn init_scratch(&self) -> Self::Scratch {
+    self.inner.init_scratch()
+  }
+
+  #[inline]
+  fn corr_set<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    scratch: &mut Self::Scratch,
+    qs: &[Self::Q],
+  ) -> Corr {
+    let Some(with_outcome) = &self.with_outcome else {
+      return self.inner.corr_set(metric, scratch, qs);
+    };
+    let (qs_scores, target_scores, users) = scratch;
+    if self.inner.aggregation.is_complete_case() {
+      users.clone_from(with_outcome);
+      for q in qs {
+        users.intersect(&self.inner.q_to_score[*q].1);
+      }
+    } else {
+      self.inner.init_users(users, qs[0]);
+      for q in &qs[1..] {
+        self.inner.add_users(users, *q);
+      }
+      users.intersect(with_outcome);
+    }
+
+    self
+      .inner
+      .corr_set_score(metric, qs_scores, target_scores, users, qs)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::{
+    inner::test_utils::sparse_data, metric::Pearson, target::Outcomes, test_inner, Target, User,
+  };
+
+  test_inner!(outcome, OutcomeCorrSet);
+
+  #[test]
+  fn matches_alloc() {
+    let data = sparse_data();
+    let outcomes = (0..30)
+      .filter(|u| u % 3 != 0)
+      .map(|u| (User(u.to_string()), (u * 7 % 11) as f64 / 2.))
+      .collect::<Outcomes>();
+    let opts = BuildOptions {
+      target: Target::Outcome(Arc::new(outcomes)),
+      ..Default::default()
+    };
+    let cs = OutcomeCorrSet::build_with(&data, &opts);
+    assert_eq!(cs.with_outcome.as_ref().unwrap().len(), 20);
+
+    let alloc = AllocCorrSet::build_with(&data, &opts);
+    let (mut scratch, mut alloc_scratch) = (cs.init_scratch(), alloc.init_scratch());
+    for qs in cs.combinations(2) {
+      let expected = alloc.corr_set(&Pearson, &mut alloc_scratch, &qs);
+      let actual = cs.corr_set(&Pearson, &mut scratch, &qs);
+      assert_eq!(actual.n_users, expected.n_users);
+      assert!((actual.r - expected.r).abs() < 1e-12 || (actual.r.is_nan() && expected.r.is_nan()));
+    }
+  }
+}
@@ -2,9 +2,12 @@ use std::collections::HashSet;
 
 use maplit::hashset;
 
-use crate::{outer::serial::CorrSetSerial, CorrSetInner, CorrSetOuter, Question, Row, User};
+use crate::{
+  metric::Pearson, outer::serial::CorrSetSerial, CorrSetInner, CorrSetOuter, Dataset, Question,
+  Row, User,
+};
 
-pub fn mock_data() -> Vec<Row> {
+pub fn mock_rows() -> Vec<Row> {
   let mk = |u: &str, q: &str, s| Row {
     user: User(u.to_string()),
     question: Question(q.to_string()),
@@ -23,18 +26,61 @@ pub fn mock_data() -> Vec<Row> {
   ]
 }
 
-pub fn test<'a, T: CorrSetInner<'a>>(data: &'a [Row]) {
+pub fn mock_data() -> Dataset {
+  Dataset::from_rows(mock_rows()).unwrap()
+}
+
+/// A pseudo-random dataset of 30 users and 12 questions where each user
+/// answers about 30% of questions, so many question sets have fewer than two
+/// users in common.
+pub fn sparse_data() -> Dataset {
+  let mut state = 17u64;
+  let mut next = move || {
+    state = state
+      .wrapping_mul(6364136223846793005)
+      .wrapping_add(1442695040888963407);
+    (state >> 33) as u32
+  };
+  let mut rows = Vec::new();
+  for u in 0..30 {
+    for q in 0..12 {
+      if next() % 10 < 3 {
+        rows.push(Row {
+          user: User(u.to_string()),
+          question: Question(q.to_string()),
+          score: next() % 4,
+        });
+      }
+    }
+  }
+  Dataset::from_rows(rows).unwrap()
+}
+
+pub fn test<T: CorrSetInner>(data: &Dataset) {
   let outer = CorrSetSerial::new();
   let inner = T::build(data);
 
   assert_eq!(
     outer
-      .k_set(&inner, inner.combinations(2))
-      .into_iter()
+      .k_set(&inner, &Pearson, inner.combinations(2))
+      .best()
+      .unwrap()
+      .questions
+      .iter()
+      .copied()
       .cloned()
       .collect::<HashSet<_>>(),
     hashset![Question("1".to_string()), Question("2".to_string())]
   );
+
+  let top = outer.k_top_n(&inner, &Pearson, inner.combinations(2), 10);
+  assert_eq!(top.n_evaluated, 3);
+  assert_eq!(top.sets.len() + top.n_nan, 3);
+  assert!(top.sets.windows(2).all(|w| w[0].r >= w[1].r));
+  assert_eq!(top.best().unwrap().n_users, 3);
+
+  let rest = outer.k_top_n(&inner, &Pearson, inner.combination_range(2, 1..3), 10);
+  assert_eq!(rest.n_evaluated, 2);
 }
 
 #[macro_export]
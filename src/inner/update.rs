@@ -0,0 +1,524 @@
+//! Incremental updates to a built index, so new data doesn't require a rebuild.
+//!
+//! Answers for unseen users or questions grow the index's domains, which
+//! reallocates every per-question user map, so each new user or question costs
+//! about as much as a pass over the index. Removing a user's last answer
+//! leaves the user (and likewise a question) in the domain. Growing a domain
+//! past what its index type can address fails with [`IndexOverflow`] and
+//! leaves the index unchanged.
+//!
+//! [`MappedCorrSet`](super::mmap::MappedCorrSet) is read-only: update an
+//! [`AllocCorrSet`] and save it again instead.
+
+use std::sync::Arc;
+
+use indexical::{
+  bitset::{simd::SimdBitset, BitSet},
+  pointer::ArcFamily,
+  IndexSet, IndexedDomain, IndexedValue,
+};
+
+use super::{
+  alloc::AllocCorrSet,
+  bchecks::BchecksCorrSet,
+  bitset::{BitsetCorrSet, QuestionEntry},
+  exact::ExactCorrSet,
+  imap::{ImapCorrSet, QuestionMap, UserMap},
+  indexed::IndexedCorrSet,
+};
+use crate::{
+  aggregate::Aggregation,
+  dataset::{IndexOverflow, QuestionIdx, UserIdx},
+  fused::CorrSetFused,
+  target::Targets,
+  Question, Row, User,
+};
+
+pub trait CorrSetUpdate {
+  /// The score `user` got on `question`, if they answered it.
+  fn score(&self, user: &User, question: &Question) -> Option<u32>;
+
+  /// Records `row`, returning the score it replaced if the user had already
+  /// answered the question.
+  fn insert_row(&mut self, row: &Row) -> Result<Option<u32>, IndexOverflow>;
+
+  /// Forgets `user`'s answer to `question`, returning its score.
+  fn remove_row(&mut self, user: &User, question: &Question) -> Option<u32>;
+
+  /// Changes the score of an existing answer, returning the old score. Does
+  /// nothing if `user` has not answered `question`.
+  fn update_score(&mut self, user: &User, question: &Question, score: u32) -> Option<u32> {
+    self.score(user, question)?;
+    let row = Row {
+      user: user.clone(),
+      question: question.clone(),
+      score,
+    };
+    self
+      .insert_row(&row)
+      .expect("rescoring an existing answer doesn't grow the index")
+  }
+}
+
+fn lookup<T: IndexedValue>(domain: &IndexedDomain<T>, value: &T) -> Option<T::Index> {
+  domain.contains(value).then(|| domain.index(value))
+}
+
+fn lookup_pair(
+  users: &IndexedDomain<User>,
+  questions: &IndexedDomain<Question>,
+  user: &User,
+  question: &Question,
+) -> Option<(UserIdx, QuestionIdx)> {
+  Some((lookup(users, user)?, lookup(questions, question)?))
+}
+
+/// Checks that the missing user or question can be added without overflowing
+/// its index type, before anything is changed.
+fn check_room(
+  users: &IndexedDomain<User>,
+  questions: &IndexedDomain<Question>,
+  u: Option<UserIdx>,
+  q: Option<QuestionIdx>,
+) -> Result<(), IndexOverflow> {
+  if u.is_none() {
+    IndexOverflow::check_users(users.len() + 1)?;
+  }
+  if q.is_none() {
+    IndexOverflow::check_questions(questions.len() + 1)?;
+  }
+  Ok(())
+}
+
+/// Replaces `domain` with a copy that also contains `value`.
+///
+/// [`IndexedDomain::insert`] doesn't update the reverse lookup table, so the
+/// domain is rebuilt instead.
+fn extend_domain<T: IndexedValue>(domain: &mut Arc<IndexedDomain<T>>, value: T) -> T::Index {
+  let values = domain.iter().cloned().chain([value.clone()]);
+  *domain = Arc::new(IndexedDomain::from_iter(values));
+  domain.index(&value)
+}
+
+fn resize_set<S: BitSet>(
+  set: Option<&IndexSet<'static, User, S, ArcFamily>>,
+  users: &Arc<IndexedDomain<User>>,
+) -> IndexSet<'static, User, S, ArcFamily> {
+  let mut resized = IndexSet::new(users);
+  for u in set.into_iter().flat_map(|set| set.indices()) {
+    resized.insert(u);
+  }
+  resized
+}
+
+fn remove_from_set<S: BitSet>(
+  set: &mut IndexSet<'static, User, S, ArcFamily>,
+  users: &Arc<IndexedDomain<User>>,
+  u: UserIdx,
+) {
+  let mut single = IndexSet::new(users);
+  single.insert(u);
+  set.subtract(&single);
+}
+
+/// Shared by inners that store `Option<u32>` scores per question and user.
+struct OptionIndex<'a> {
+  users: &'a mut Arc<IndexedDomain<User>>,
+  questions: &'a mut Arc<IndexedDomain<Question>>,
+  q_to_score: &'a mut QuestionMap<UserMap<Option<u32>>>,
+  targets: &'a mut Targets,
+  aggregation: &'a mut Aggregation,
+}
+
+impl OptionIndex<'_> {
+  fn ensure(
+    &mut self,
+    user: &User,
+    question: &Question,
+  ) -> Result<(UserIdx, QuestionIdx), IndexOverflow> {
+    let (u, q) = (lookup(self.users, user), lookup(self.questions, question));
+    if let (Some(u), Some(q)) = (u, q) {
+      return Ok((u, q));
+    }
+    check_room(self.users, self.questions, u, q)?;
+
+    let u = u.unwrap_or_else(|| extend_domain(self.users, user.clone()));
+    let q = q.unwrap_or_else(|| extend_domain(self.questions, question.clone()));
+    let users = &*self.users;
+    *self.q_to_score = QuestionMap::new(self.questions, |q| {
+      let scores = self.q_to_score.get(q);
+      UserMap::new(users, |u| scores.and_then(|s| s.get(u).copied().flatten()))
+    });
+    self.targets.resize(users);
+    self.aggregation.resize(self.questions);
+    Ok((u, q))
+  }
+
+  fn insert_row(mut self, row: &Row) -> Result<Option<u32>, IndexOverflow> {
+    let (u, q) = self.ensure(&row.user, &row.question)?;
+    let old = self.q_to_score[q][u].replace(row.score);
+    self.targets.update(u, &row.question, old, Some(row.score));
+    self
+      .aggregation
+      .update(q, &row.question, old, Some(row.score));
+    Ok(old)
+  }
+
+  fn remove_row(self, user: &User, question: &Question) -> Option<u32> {
+    let (u, q) = lookup_pair(self.users, self.questions, user, question)?;
+    let old = self.q_to_score[q][u].take()?;
+    self.targets.update(u, question, Some(old), None);
+    self.aggregation.update(q, question, Some(old), None);
+    Some(old)
+  }
+}
+
+/// Shared by inners that store dense scores alongside a set of the users who
+/// answered each question.
+struct BitsetIndex<'a, S: BitSet> {
+  users: &'a mut Arc<IndexedDomain<User>>,
+  questions: &'a mut Arc<IndexedDomain<Question>>,
+  q_to_score: &'a mut QuestionMap<QuestionEntry<S>>,
+  targets: &'a mut Targets,
+  aggregation: &'a mut Aggregation,
+}
+
+impl<S: BitSet> BitsetIndex<'_, S> {
+  fn ensure(
+    &mut self,
+    user: &User,
+    question: &Question,
+  ) -> Result<(UserIdx, QuestionIdx), IndexOverflow> {
+    let (u, q) = (lookup(self.users, user), lookup(self.questions, question));
+    if let (Some(u), Some(q)) = (u, q) {
+      return Ok((u, q));
+    }
+    check_room(self.users, self.questions, u, q)?;
+
+    let u = u.unwrap_or_else(|| extend_domain(self.users, user.clone()));
+    let q = q.unwrap_or_else(|| extend_domain(self.questions, question.clone()));
+    let users = &*self.users;
+    *self.q_to_score = QuestionMap::new(self.questions, |q| {
+      let entry = self.q_to_score.get(q);
+      let scores = UserMap::new(users, |u| {
+        entry.and_then(|(s, _)| s.get(u).copied()).unwrap_or(0)
+      });
+      (scores, resize_set(entry.map(|(_, set)| set), users))
+    });
+    self.targets.resize(users);
+    self.aggregation.resize(self.questions);
+    Ok((u, q))
+  }
+
+  fn score(&self, user: &User, question: &Question) -> Option<u32> {
+    let (u, q) = lookup_pair(self.users, self.questions, user, question)?;
+    let (scores, set) = &self.q_to_score[q];
+    set.contains(u).then(|| scores[u])
+  }
+
+  fn insert_row(mut self, row: &Row) -> Result<Option<u32>, IndexOverflow> {
+    let old = self.score(&row.user, &row.question);
+    let (u, q) = self.ensure(&row.user, &row.question)?;
+    let (scores, set) = &mut self.q_to_score[q];
+    scores[u] = row.score;
+    set.insert(u);
+    self.targets.update(u, &row.question, old, Some(row.score));
+    self
+      .aggregation
+      .update(q, &row.question, old, Some(row.score));
+    Ok(old)
+  }
+
+  fn remove_row(self, user: &User, question: &Question) -> Option<u32> {
+    let old = self.score(user, question)?;
+    let (u, q) = lookup_pair(self.users, self.questions, user, question)?;
+    let (scores, set) = &mut self.q_to_score[q];
+    scores[u] = 0;
+    remove_from_set(set, self.users, u);
+    self.targets.update(u, question, Some(old), None);
+    self.aggregation.update(q, question, Some(old), None);
+    Some(old)
+  }
+}
+
+macro_rules! impl_option_update {
+  ($t:ty) => {
+    impl CorrSetUpdate for $t {
+      fn score(&self, user: &User, question: &Question) -> Option<u32> {
+        let (u, q) = lookup_pair(&self.users, &self.questions, user, question)?;
+        self.q_to_score[q][u]
+      }
+
+      fn insert_row(&mut self, row: &Row) -> Result<Option<u32>, IndexOverflow> {
+        self.option_index().insert_row(row)
+      }
+
+      fn remove_row(&mut self, user: &User, question: &Question) -> Option<u32> {
+        self.option_index().remove_row(user, question)
+      }
+    }
+
+    impl $t {
+      fn option_index(&mut self) -> OptionIndex<'_> {
+        OptionIndex {
+          users: &mut self.users,
+          questions: &mut self.questions,
+          q_to_score: &mut self.q_to_score,
+          targets: &mut self.targets,
+          aggregation: &mut self.aggregation,
+        }
+      }
+    }
+  };
+}
+
+impl_option_update!(ImapCorrSet);
+impl_option_update!(BchecksCorrSet);
+
+macro_rules! impl_bitset_update {
+  ([$($params:tt)*] $t:ty, $s:ty) => {
+    impl<$($params)*> CorrSetUpdate for $t {
+      fn score(&self, user: &User, question: &Question) -> Option<u32> {
+        let (u, q) = lookup_pair(&self.users, &self.questions, user, question)?;
+        let (scores, set) = &self.q_to_score[q];
+        set.contains(u).then(|| scores[u])
+      }
+
+      fn insert_row(&mut self, row: &Row) -> Result<Option<u32>, IndexOverflow> {
+        self.bitset_index().insert_row(row)
+      }
+
+      fn remove_row(&mut self, user: &User, question: &Question) -> Option<u32> {
+        self.bitset_index().remove_row(user, question)
+      }
+    }
+
+    impl<$($params)*> $t {
+      fn bitset_index(&mut self) -> BitsetIndex<'_, $s> {
+        BitsetIndex {
+          users: &mut self.users,
+          questions: &mut self.questions,
+          q_to_score: &mut self.q_to_score,
+          targets: &mut self.targets,
+          aggregation: &mut self.aggregation,
+        }
+      }
+    }
+  };
+}
+
+impl_bitset_update!([S: BitSet] BitsetCorrSet<S>, S);
+impl_bitset_update!([] AllocCorrSet, SimdBitset<u64, 16>);
+
+impl CorrSetUpdate for IndexedCorrSet {
+  fn score(&self, user: &User, question: &Question) -> Option<u32> {
+    let (u, q) = lookup_pair(&self.users, &self.questions, user, question)?;
+    self.q_to_score.get(&q)?.get(&u).copied()
+  }
+
+  fn insert_row(&mut self, row: &Row) -> Result<Option<u32>, IndexOverflow> {
+    let (u, q) = (
+      lookup(&self.users, &row.user),
+      lookup(&self.questions, &row.question),
+    );
+    check_room(&self.users, &self.questions, u, q)?;
+    let grown = u.is_none();
+    let u = u.unwrap_or_else(|| extend_domain(&mut self.users, row.user.clone()));
+    if grown {
+      self.targets.resize(&self.users);
+    }
+    let grown = q.is_none();
+    let q = q.unwrap_or_else(|| extend_domain(&mut self.questions, row.question.clone()));
+    if grown {
+      self.aggregation.resize(&self.questions);
+    }
+    let old = self.q_to_score.entry(q).or_default().insert(u, row.score);
+    self.targets.update(u, &row.question, old, Some(row.score));
+    self
+      .aggregation
+      .update(q, &row.question, old, Some(row.score));
+    Ok(old)
+  }
+
+  fn remove_row(&mut self, user: &User, question: &Question) -> Option<u32> {
+    let (u, q) = lookup_pair(&self.users, &self.questions, user, question)?;
+    let old = self.q_to_score.get_mut(&q)?.remove(&u)?;
+    self.targets.update(u, question, Some(old), None);
+    self.aggregation.update(q, question, Some(old), None);
+    Some(old)
+  }
+}
+
+impl CorrSetUpdate for CorrSetFused {
+  fn score(&self, user: &User, question: &Question) -> Option<u32> {
+    self.inner.score(user, question)
+  }
+
+  fn insert_row(&mut self, row: &Row) -> Result<Option<u32>, IndexOverflow> {
+    self.inner.insert_row(row)
+  }
+
+  fn remove_row(&mut self, user: &User, question: &Question) -> Option<u32> {
+    self.inner.remove_row(user, question)
+  }
+}
+
+impl CorrSetUpdate for ExactCorrSet {
+  fn score(&self, user: &User, question: &Question) -> Option<u32> {
+    self.inner.score(user, question)
+  }
+
+  fn insert_row(&mut self, row: &Row) -> Result<Option<u32>, IndexOverflow> {
+    self.inner.insert_row(row)
+  }
+
+  fn remove_row(&mut self, user: &User, question: &Question) -> Option<u32> {
+    self.inner.remove_row(user, question)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use fxhash::FxHashMap as HashMap;
+  use itertools::Itertools;
+
+  use super::*;
+  use crate::{
+    inner::{bitset::BvecCorrSet, test_utils::mock_rows, Corr},
+    metric::Pearson,
+    Aggregator, BuildOptions, CorrSetInner, Dataset, Missing, Target,
+  };
+
+  fn row(u: &str, q: &str, score: u32) -> Row {
+    Row {
+      user: User(u.to_string()),
+      question: Question(q.to_string()),
+      score,
+    }
+  }
+
+  fn pairs<T: CorrSetInner>(cs: &T) -> HashMap<Vec<Question>, Corr> {
+    let mut scratch = cs.init_scratch();
+    cs.combinations(2)
+      .map(|qs| {
+        let corr = cs.corr_set(&Pearson, &mut scratch, &qs);
+        let names = qs.into_iter().map(|q| cs.to_question(q).clone());
+        (names.sorted_by(|a, b| a.0.cmp(&b.0)).collect(), corr)
+      })
+      .collect()
+  }
+
+  fn check<T: CorrSetInner + CorrSetUpdate>() {
+    let outcomes = [("a", 1.), ("c", 0.5), ("d", 2.)].map(|(u, outcome)| (User(u.into()), outcome));
+    for target in [
+      Target::GrandTotal,
+      Target::Rest,
+      Target::Outcome(Arc::new(outcomes.into_iter().collect())),
+      Target::Subset(vec![Question("1".into()), Question("4".into())]),
+    ] {
+      check_options::<T>(&BuildOptions {
+        target,
+        ..Default::default()
+      });
+    }
+    let weights = [("1", 2.), ("5", -1.)].map(|(q, weight)| (Question(q.into()), weight));
+    for aggregator in [
+      Aggregator::Mean,
+      Aggregator::Max,
+      Aggregator::Min,
+      Aggregator::Weighted(Arc::new(weights.into_iter().collect())),
+      Aggregator::ZSum,
+    ] {
+      check_options::<T>(&BuildOptions {
+        target: Target::Rest,
+        aggregator,
+        ..Default::default()
+      });
+    }
+    for missing in [Missing::Zero, Missing::Mean, Missing::AtLeast(1)] {
+      check_options::<T>(&BuildOptions {
+        target: Target::Rest,
+        aggregator: Aggregator::ZSum,
+        missing,
+        ..Default::default()
+      });
+    }
+  }
+
+  fn check_options<T: CorrSetInner + CorrSetUpdate>(opts: &BuildOptions) {
+    let mut rows = mock_rows();
+    rows.extend([
+      row("d", "1", 1),
+      row("d", "4", 1),
+      row("a", "4", 0),
+      row("b", "4", 1),
+    ]);
+    let expected = T::build_with(&Dataset::from_rows(rows.clone()).unwrap(), opts);
+    let mut actual = T::build_with(&Dataset::from_rows(rows[..4].to_vec()).unwrap(), opts);
+    for r in &rows[4..] {
+      assert_eq!(actual.insert_row(r), Ok(None));
+    }
+
+    assert_eq!(actual.insert_row(&row("c", "5", 2)), Ok(None));
+    assert_eq!(
+      actual.remove_row(&User("c".into()), &Question("5".into())),
+      Some(2)
+    );
+    assert_eq!(
+      actual.update_score(&User("a".into()), &Question("1".into()), 0),
+      Some(1)
+    );
+    assert_eq!(actual.insert_row(&row("a", "1", 1)), Ok(Some(0)));
+    assert_eq!(
+      actual.update_score(&User("e".into()), &Question("1".into()), 1),
+      None
+    );
+    assert_eq!(
+      actual.score(&User("d".into()), &Question("4".into())),
+      Some(1)
+    );
+    assert_eq!(actual.score(&User("c".into()), &Question("5".into())), None);
+
+    let actual = pairs(&actual);
+    for (qs, corr) in pairs(&expected) {
+      let other = actual[&qs];
+      assert_eq!(corr.n_users, other.n_users, "{opts:?}, {qs:?}");
+      assert!(
+        (corr.r - other.r).abs() < 1e-12 || (corr.r.is_nan() && other.r.is_nan()),
+        "{opts:?}, {qs:?}: {} != {}",
+        corr.r,
+        other.r
+      );
+    }
+  }
+
+  #[test]
+  fn update_indexed() {
+    check::<IndexedCorrSet>();
+  }
+
+  #[test]
+  fn update_imap() {
+    check::<ImapCorrSet>();
+  }
+
+  #[test]
+  fn update_bchecks() {
+    check::<BchecksCorrSet>();
+  }
+
+  #[test]
+  fn update_bitset() {
+    check::<BvecCorrSet>();
+  }
+
+  #[test]
+  fn update_alloc() {
+    check::<AllocCorrSet>();
+  }
+
+  #[test]
+  fn update_exact() {
+    check::<ExactCorrSet>();
+  }
+}
@@ -7,15 +7,34 @@
 use anyhow::Result;
 
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::BufReader, path::Path};
-
+use std::path::Path;
+
+pub mod aggregate;
+pub mod approx;
+pub mod bnb;
+pub mod checkpoint;
+pub mod combinations;
+pub mod dataset;
+#[cfg(test)]
+mod differential;
 pub mod fused;
 pub mod inner;
+pub mod load;
+pub mod metric;
 pub mod outer;
+pub mod registry;
+pub mod sample;
+pub mod shard;
+pub mod target;
 mod utils;
 
-pub use inner::{inner_names, CorrSetInner};
-pub use outer::{outer_names, CorrSetOuter};
+pub use aggregate::{Aggregator, Missing};
+pub use dataset::Dataset;
+pub use inner::{BuildOptions, CorrSetInner};
+pub use metric::{metric_names, Metric};
+pub use outer::CorrSetOuter;
+pub use registry::{inner_names, outer_names};
+pub use target::Target;
 
 #[derive(PartialEq, Eq, Hash, Clone, Serialize, Deserialize, Debug)]
 pub struct User(pub String);
@@ -30,6 +49,8 @@ pub struct Row {
   pub score: u32,
 }
 
+/// Loads every row of `path`, inferring the format from its extension.
+/// See [`load`] for more control over the input format.
 pub fn load_rows(path: impl AsRef<Path>) -> Result<Vec<Row>> {
-  Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+  Ok(load::load(path, &load::LoadOptions::default())?)
 }
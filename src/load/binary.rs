@@ -0,0 +1,122 @@
+//! A compact binary encoding of rows. Users and questions are written once in
+//! string tables, and each row refers to them by position.
+//!
+//! Layout, with all integers little-endian:
+//! - the magic bytes `CSRB` and a `u32` format version,
+//! - a `u32` user count, then each user as a `u32` byte length and UTF-8 bytes,
+//! - the same for questions,
+//! - a `u64` row count, then each row as `u32` user, `u32` question and `u32` score.
+
+use std::io::{self, ErrorKind, Read, Write};
+
+use fxhash::FxHashMap as HashMap;
+
+use super::LoadError;
+use crate::{Question, Row, User};
+
+const MAGIC: &[u8; 4] = b"CSRB";
+const VERSION: u32 = 1;
+
+pub fn write_binary(rows: &[Row], mut writer: impl Write) -> io::Result<()> {
+  fn intern<'a>(table: &mut HashMap<&'a str, u32>, order: &mut Vec<&'a str>, s: &'a str) -> u32 {
+    *table.entry(s).or_insert_with(|| {
+      order.push(s);
+      (order.len() - 1) as u32
+    })
+  }
+
+  let (mut users, mut user_order) = (HashMap::default(), Vec::new());
+  let (mut questions, mut question_order) = (HashMap::default(), Vec::new());
+  let ids = rows
+    .iter()
+    .map(|row| {
+      (
+        intern(&mut users, &mut user_order, &row.user.0),
+        intern(&mut questions, &mut question_order, &row.question.0),
+        row.score,
+      )
+    })
+    .collect::<Vec<_>>();
+
+  writer.write_all(MAGIC)?;
+  writer.write_all(&VERSION.to_le_bytes())?;
+  for table in [&user_order, &question_order] {
+    writer.write_all(&(table.len() as u32).to_le_bytes())?;
+    for s in table {
+      writer.write_all(&(s.len() as u32).to_le_bytes())?;
+      writer.write_all(s.as_bytes())?;
+    }
+  }
+  writer.write_all(&(ids.len() as u64).to_le_bytes())?;
+  for (user, question, score) in ids {
+    writer.write_all(&user.to_le_bytes())?;
+    writer.write_all(&question.to_le_bytes())?;
+    writer.write_all(&score.to_le_bytes())?;
+  }
+  writer.flush()
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+  let mut buf = [0; 4];
+  reader.read_exact(&mut buf)?;
+  Ok(u32::from_le_bytes(buf))
+}
+
+fn read_table(reader: &mut impl Read) -> Result<Vec<String>, LoadError> {
+  let len = read_u32(reader)?;
+  (0..len)
+    .map(|_| {
+      let mut buf = vec![0; read_u32(reader)? as usize];
+      reader.read_exact(&mut buf)?;
+      String::from_utf8(buf).map_err(|e| io::Error::new(ErrorKind::InvalidData, e).into())
+    })
+    .collect()
+}
+
+pub(super) fn read<R: Read>(
+  mut reader: R,
+) -> Result<impl Iterator<Item = Result<Row, LoadError>>, LoadError> {
+  let mut magic = [0; 4];
+  reader.read_exact(&mut magic)?;
+  let version = read_u32(&mut reader)?;
+  if &magic != MAGIC || version != VERSION {
+    return Err(
+      io::Error::new(
+        ErrorKind::InvalidData,
+        "not a binary row file (or unsupported version)",
+      )
+      .into(),
+    );
+  }
+
+  let users = read_table(&mut reader)?;
+  let questions = read_table(&mut reader)?;
+  let mut count = [0; 8];
+  reader.read_exact(&mut count)?;
+  let count = u64::from_le_bytes(count);
+
+  Ok((1..=count).map(move |record| {
+    let mut buf = [0; 12];
+    reader.read_exact(&mut buf).map_err(|e| match e.kind() {
+      ErrorKind::UnexpectedEof => LoadError::row(record, None, "truncated record"),
+      _ => e.into(),
+    })?;
+    let [user, question, score] =
+      [0, 4, 8].map(|i| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap()));
+
+    let lookup = |table: &[String], idx: u32, field: &str| {
+      table.get(idx as usize).cloned().ok_or_else(|| {
+        LoadError::row(
+          record,
+          Some(field),
+          format!("index {idx} out of range for {} entries", table.len()),
+        )
+      })
+    };
+    Ok(Row {
+      user: User(lookup(&users, user, "user")?),
+      question: Question(lookup(&questions, question, "question")?),
+      score,
+    })
+  }))
+}
@@ -0,0 +1,66 @@
+use std::io::Read;
+
+use super::{Columns, LoadError};
+use crate::{Question, Row, User};
+
+fn csv_error(e: csv::Error) -> LoadError {
+  if e.is_io_error() {
+    match e.into_kind() {
+      csv::ErrorKind::Io(e) => LoadError::Io(e),
+      _ => unreachable!(),
+    }
+  } else {
+    let line = e.position().map_or(0, |pos| pos.line());
+    LoadError::row(line, None, e.to_string())
+  }
+}
+
+/// Reads CSV-like input whose first record names the columns.
+pub(super) fn read<R: Read>(
+  reader: R,
+  delimiter: u8,
+  columns: &Columns,
+) -> Result<impl Iterator<Item = Result<Row, LoadError>>, LoadError> {
+  let mut reader = csv::ReaderBuilder::new()
+    .delimiter(delimiter)
+    .from_reader(reader);
+
+  let headers = reader.headers().map_err(csv_error)?;
+  let find = |name: &str| {
+    headers
+      .iter()
+      .position(|header| header.trim() == name)
+      .ok_or_else(|| LoadError::MissingColumn(name.to_string()))
+  };
+  let (user_col, question_col, score_col) = (
+    find(&columns.user)?,
+    find(&columns.question)?,
+    find(&columns.score)?,
+  );
+  let columns = columns.clone();
+
+  Ok(reader.into_records().map(move |record| {
+    let record = record.map_err(csv_error)?;
+    let line = record.position().map_or(0, |pos| pos.line());
+    let field = |idx: usize, name: &str| {
+      let value = record.get(idx).unwrap_or("").trim();
+      if value.is_empty() {
+        Err(LoadError::row(line, Some(name), "missing value"))
+      } else {
+        Ok(value)
+      }
+    };
+
+    let user = field(user_col, &columns.user)?;
+    let question = field(question_col, &columns.question)?;
+    let score = field(score_col, &columns.score)?
+      .parse::<u32>()
+      .map_err(|e| LoadError::row(line, Some(&columns.score), e.to_string()))?;
+
+    Ok(Row {
+      user: User(user.to_string()),
+      question: Question(question.to_string()),
+      score,
+    })
+  }))
+}
@@ -0,0 +1,288 @@
+//! Loaders for the `(user, question, score)` rows that every index is built from.
+//!
+//! Rows are read lazily through [`read_rows`], so large exports can be consumed
+//! without first materializing the whole file. The format is inferred from the
+//! file extension unless given explicitly in [`LoadOptions`].
+
+use std::{
+  error::Error,
+  fmt,
+  fs::File,
+  io::{self, BufReader},
+  path::Path,
+  str::FromStr,
+};
+
+use crate::{dataset::IndexOverflow, Row};
+
+mod binary;
+mod delimited;
+mod ndjson;
+
+pub use binary::write_binary;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+  /// A single JSON array of rows, as produced by `gen-data`.
+  Json,
+  /// One JSON object per line.
+  Ndjson,
+  Csv,
+  Tsv,
+  /// The compact encoding written by [`write_binary`].
+  Binary,
+}
+
+impl Format {
+  pub fn from_path(path: &Path) -> Option<Self> {
+    path.extension()?.to_str()?.parse().ok()
+  }
+}
+
+impl FromStr for Format {
+  type Err = LoadError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(match s {
+      "json" => Format::Json,
+      "ndjson" | "jsonl" => Format::Ndjson,
+      "csv" => Format::Csv,
+      "tsv" => Format::Tsv,
+      "bin" => Format::Binary,
+      _ => return Err(LoadError::UnknownFormat(s.to_string())),
+    })
+  }
+}
+
+/// Names of the fields holding each part of a row in CSV, TSV and NDJSON input.
+#[derive(Clone, Debug)]
+pub struct Columns {
+  pub user: String,
+  pub question: String,
+  pub score: String,
+}
+
+impl Default for Columns {
+  fn default() -> Self {
+    Columns {
+      user: "user".into(),
+      question: "question".into(),
+      score: "score".into(),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+  /// Overrides the format inferred from the file extension.
+  pub format: Option<Format>,
+  pub columns: Columns,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+  Io(io::Error),
+  UnknownFormat(String),
+  MissingColumn(String),
+  Overflow(IndexOverflow),
+  /// A row that could not be parsed. `line` is 1-based, or the record number
+  /// for binary input.
+  Row {
+    line: u64,
+    field: Option<String>,
+    message: String,
+  },
+}
+
+impl LoadError {
+  fn row(line: u64, field: Option<&str>, message: impl Into<String>) -> Self {
+    LoadError::Row {
+      line,
+      field: field.map(String::from),
+      message: message.into(),
+    }
+  }
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LoadError::Io(e) => write!(f, "{e}"),
+      LoadError::UnknownFormat(s) => write!(f, "unknown input format `{s}`"),
+      LoadError::MissingColumn(c) => write!(f, "missing column `{c}`"),
+      LoadError::Overflow(e) => write!(f, "{e}"),
+      LoadError::Row {
+        line,
+        field: Some(field),
+        message,
+      } => write!(f, "line {line}, field `{field}`: {message}"),
+      LoadError::Row {
+        line,
+        field: None,
+        message,
+      } => write!(f, "line {line}: {message}"),
+    }
+  }
+}
+
+impl Error for LoadError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      LoadError::Io(e) => Some(e),
+      LoadError::Overflow(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for LoadError {
+  fn from(e: io::Error) -> Self {
+    LoadError::Io(e)
+  }
+}
+
+impl From<IndexOverflow> for LoadError {
+  fn from(e: IndexOverflow) -> Self {
+    LoadError::Overflow(e)
+  }
+}
+
+pub type Rows = Box<dyn Iterator<Item = Result<Row, LoadError>>>;
+
+/// Opens `path` and returns an iterator over its rows.
+///
+/// Errors in the header (e.g. a missing column) are returned immediately,
+/// while errors in individual rows are yielded by the iterator.
+pub fn read_rows(path: impl AsRef<Path>, opts: &LoadOptions) -> Result<Rows, LoadError> {
+  let path = path.as_ref();
+  let format = match opts.format {
+    Some(format) => format,
+    None => {
+      Format::from_path(path).ok_or_else(|| LoadError::UnknownFormat(path.display().to_string()))?
+    }
+  };
+  let reader = BufReader::new(File::open(path)?);
+  Ok(match format {
+    Format::Json => {
+      let rows: Vec<Row> = serde_json::from_reader(reader)
+        .map_err(|e| LoadError::row(e.line() as u64, None, e.to_string()))?;
+      Box::new(rows.into_iter().map(Ok))
+    }
+    Format::Ndjson => Box::new(ndjson::read(reader, &opts.columns)),
+    Format::Csv => Box::new(delimited::read(reader, b',', &opts.columns)?),
+    Format::Tsv => Box::new(delimited::read(reader, b'\t', &opts.columns)?),
+    Format::Binary => Box::new(binary::read(reader)?),
+  })
+}
+
+/// Reads every row of `path`, stopping at the first malformed one.
+pub fn load(path: impl AsRef<Path>, opts: &LoadOptions) -> Result<Vec<Row>, LoadError> {
+  read_rows(path, opts)?.collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{Question, User};
+
+  fn row(u: &str, q: &str, score: u32) -> Row {
+    Row {
+      user: User(u.to_string()),
+      question: Question(q.to_string()),
+      score,
+    }
+  }
+
+  fn assert_rows(actual: Vec<Row>, expected: Vec<Row>) {
+    let key = |r: &Row| (r.user.0.clone(), r.question.0.clone(), r.score);
+    assert_eq!(
+      actual.iter().map(key).collect::<Vec<_>>(),
+      expected.iter().map(key).collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn csv_columns() {
+    let columns = Columns {
+      user: "student".into(),
+      question: "item".into(),
+      score: "points".into(),
+    };
+    let input = "item\tpoints\tstudent\n1\t2\ta\n\"2\"\t0\tb\n";
+    let rows = delimited::read(input.as_bytes(), b'\t', &columns)
+      .unwrap()
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap();
+    assert_rows(rows, vec![row("a", "1", 2), row("b", "2", 0)]);
+
+    let err = delimited::read("user,question\n".as_bytes(), b',', &Columns::default())
+      .err()
+      .unwrap();
+    assert!(matches!(&err, LoadError::MissingColumn(c) if c == "score"));
+  }
+
+  #[test]
+  fn csv_bad_field() {
+    let input = "user,question,score\na,1,1\nb,1,x\n";
+    let err = delimited::read(input.as_bytes(), b',', &Columns::default())
+      .unwrap()
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap_err();
+    assert!(
+      matches!(&err, LoadError::Row { line: 3, field: Some(f), .. } if f == "score"),
+      "{err}"
+    );
+  }
+
+  #[test]
+  fn ndjson() {
+    let input = "{\"user\": \"a\", \"question\": 7, \"score\": 1}\n\n{\"user\": \"b\", \"question\": \"7\", \"score\": 0}\n";
+    let rows = ndjson::read(input.as_bytes(), &Columns::default())
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap();
+    assert_rows(rows, vec![row("a", "7", 1), row("b", "7", 0)]);
+
+    let input = "{\"user\": \"a\", \"question\": \"1\", \"score\": 1}\n{\"user\": \"a\", \"question\": \"2\", \"score\": -1}\n";
+    let err = ndjson::read(input.as_bytes(), &Columns::default())
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap_err();
+    assert!(
+      matches!(&err, LoadError::Row { line: 2, field: Some(f), .. } if f == "score"),
+      "{err}"
+    );
+  }
+
+  #[test]
+  fn binary_roundtrip() {
+    let rows = vec![row("a", "1", 1), row("b", "1", 0), row("a", "2", 5)];
+    let mut buf = Vec::new();
+    write_binary(&rows, &mut buf).unwrap();
+    let read = binary::read(&buf[..])
+      .unwrap()
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap();
+    assert_rows(read, rows);
+
+    let truncated = binary::read(&buf[..buf.len() - 2])
+      .unwrap()
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap_err();
+    assert!(
+      matches!(truncated, LoadError::Row { line: 3, .. }),
+      "{truncated}"
+    );
+  }
+
+  #[test]
+  fn format_from_path() {
+    assert_eq!(
+      Format::from_path(Path::new("data/x.jsonl")),
+      Some(Format::Ndjson)
+    );
+    assert_eq!(
+      Format::from_path(Path::new("data/x.bin")),
+      Some(Format::Binary)
+    );
+    assert_eq!(Format::from_path(Path::new("data/x")), None);
+  }
+}
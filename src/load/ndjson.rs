@@ -0,0 +1,63 @@
+use std::io::BufRead;
+
+use serde_json::Value;
+
+use super::{Columns, LoadError};
+use crate::{Question, Row, User};
+
+/// Reads one JSON object per line, skipping blank lines.
+pub(super) fn read<R: BufRead>(
+  reader: R,
+  columns: &Columns,
+) -> impl Iterator<Item = Result<Row, LoadError>> {
+  let columns = columns.clone();
+  reader
+    .lines()
+    .enumerate()
+    .filter_map(move |(i, line)| match line {
+      Ok(line) if line.trim().is_empty() => None,
+      Ok(line) => Some(parse_line(&line, i as u64 + 1, &columns)),
+      Err(e) => Some(Err(e.into())),
+    })
+}
+
+fn parse_line(line: &str, line_no: u64, columns: &Columns) -> Result<Row, LoadError> {
+  let value: Value =
+    serde_json::from_str(line).map_err(|e| LoadError::row(line_no, None, e.to_string()))?;
+
+  let field = |name: &str| {
+    value
+      .get(name)
+      .ok_or_else(|| LoadError::row(line_no, Some(name), "missing field"))
+  };
+  // Ids are often numeric in exports, so accept either strings or numbers.
+  let id = |name: &str| match field(name)? {
+    Value::String(s) => Ok(s.clone()),
+    Value::Number(n) => Ok(n.to_string()),
+    other => Err(LoadError::row(
+      line_no,
+      Some(name),
+      format!("expected a string or number, found `{other}`"),
+    )),
+  };
+
+  let user = id(&columns.user)?;
+  let question = id(&columns.question)?;
+  let score = field(&columns.score)?;
+  let score = score
+    .as_u64()
+    .and_then(|s| u32::try_from(s).ok())
+    .ok_or_else(|| {
+      LoadError::row(
+        line_no,
+        Some(&columns.score),
+        format!("expected a non-negative 32-bit integer, found `{score}`"),
+      )
+    })?;
+
+  Ok(Row {
+    user: User(user),
+    question: Question(question),
+    score,
+  })
+}
@@ -0,0 +1,499 @@
+//! Measures of how well a question set's scores predict users' targets.
+//!
+//! Every inner gathers each scored user's value on a question set and their
+//! [`Target`](crate::Target), then hands them to a [`Metric`]. Searches keep the sets with the
+//! highest score, so every metric is oriented so that higher is better.
+
+use fxhash::FxHashMap as HashMap;
+
+use crate::utils;
+
+pub trait Metric: Send + Sync {
+  /// Scores how well `xs` (each user's value on a question set) predicts `ys`
+  /// (the same users' targets). Returns NaN when the score is undefined,
+  /// e.g. when either side is constant.
+  fn score(&self, xs: &[f64], ys: &[f64]) -> f64;
+
+  /// Computes the score from [`Moments`] streamed one user at a time, if it
+  /// only depends on them. Inners then skip collecting the scores.
+  fn streaming(&self) -> Option<fn(&Moments) -> f64> {
+    None
+  }
+
+  /// An upper bound on the score of any question set answered by at most a
+  /// given number of users, if the metric has one that tightens as users are
+  /// left out. Lets [`CorrSetBranchBound`](crate::bnb::CorrSetBranchBound)
+  /// skip sets that can't make the top. The correlations are bounded by 1
+  /// whatever the users, which rules out nothing, so they have none.
+  fn upper_bound(&self) -> Option<fn(usize) -> f64> {
+    None
+  }
+}
+
+/// Running means and co-moments of `(x, y)` pairs, updated with Welford's
+/// algorithm so that large user counts don't lose precision.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Moments {
+  pub n: usize,
+  mean_x: f64,
+  mean_y: f64,
+  m2_x: f64,
+  m2_y: f64,
+  c_xy: f64,
+}
+
+impl Moments {
+  #[inline]
+  pub fn push(&mut self, x: f64, y: f64) {
+    self.n += 1;
+    let n = self.n as f64;
+    let dx = x - self.mean_x;
+    let dy = y - self.mean_y;
+    self.mean_x += dx / n;
+    self.mean_y += dy / n;
+    let dy_new = y - self.mean_y;
+    self.m2_x += dx * (x - self.mean_x);
+    self.m2_y += dy * dy_new;
+    self.c_xy += dx * dy_new;
+  }
+
+  pub fn correlation(&self) -> f64 {
+    self.c_xy / (self.m2_x * self.m2_y).sqrt()
+  }
+
+  pub fn covariance(&self) -> f64 {
+    self.c_xy / self.n as f64
+  }
+}
+
+/// Exact sums of integer `(x, y)` pairs, for scores that are counts. Converting
+/// them to [`Moments`] only rounds once per moment, so the result doesn't
+/// depend on the order users are visited in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExactSums {
+  pub n: u64,
+  sum_x: u64,
+  sum_y: u64,
+  sum_xx: u128,
+  sum_yy: u128,
+  sum_xy: u128,
+}
+
+impl ExactSums {
+  #[inline]
+  pub fn push(&mut self, x: u32, y: u32) {
+    let (x, y) = (x as u64, y as u64);
+    self.n += 1;
+    self.sum_x += x;
+    self.sum_y += y;
+    self.sum_xx += (x * x) as u128;
+    self.sum_yy += (y * y) as u128;
+    self.sum_xy += (x * y) as u128;
+  }
+
+  pub fn moments(&self) -> Moments {
+    let n = self.n as u128;
+    let (sum_x, sum_y) = (self.sum_x as u128, self.sum_y as u128);
+    let nf = self.n as f64;
+    Moments {
+      n: self.n as usize,
+      mean_x: self.sum_x as f64 / nf,
+      mean_y: self.sum_y as f64 / nf,
+      m2_x: (n * self.sum_xx - sum_x * sum_x) as f64 / nf,
+      m2_y: (n * self.sum_yy - sum_y * sum_y) as f64 / nf,
+      c_xy: ((n * self.sum_xy) as i128 - (sum_x * sum_y) as i128) as f64 / nf,
+    }
+  }
+}
+
+impl FromIterator<(f64, f64)> for Moments {
+  fn from_iter<I: IntoIterator<Item = (f64, f64)>>(iter: I) -> Self {
+    let mut moments = Moments::default();
+    for (x, y) in iter {
+      moments.push(x, y);
+    }
+    moments
+  }
+}
+
+/// Pearson's linear correlation coefficient.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pearson;
+
+impl Metric for Pearson {
+  #[inline]
+  fn score(&self, xs: &[f64], ys: &[f64]) -> f64 {
+    utils::correlation(xs, ys)
+  }
+
+  fn streaming(&self) -> Option<fn(&Moments) -> f64> {
+    Some(Moments::correlation)
+  }
+}
+
+/// Spearman's rank correlation: the Pearson correlation of the ranks, with
+/// tied values given their average rank.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Spearman;
+
+fn ranks(xs: &[f64]) -> Vec<f64> {
+  let mut order = (0..xs.len()).collect::<Vec<_>>();
+  order.sort_unstable_by(|&i, &j| xs[i].total_cmp(&xs[j]));
+  let mut ranks = vec![0.; xs.len()];
+  let mut start = 0;
+  while start < order.len() {
+    let x = xs[order[start]];
+    let end = start + order[start..].iter().take_while(|&&i| xs[i] == x).count();
+    let rank = (start + end + 1) as f64 / 2.;
+    for &i in &order[start..end] {
+      ranks[i] = rank;
+    }
+    start = end;
+  }
+  ranks
+}
+
+impl Metric for Spearman {
+  fn score(&self, xs: &[f64], ys: &[f64]) -> f64 {
+    utils::correlation(&ranks(xs), &ranks(ys))
+  }
+}
+
+/// Kendall's tau-b, which accounts for ties on either side. Computed with
+/// Knight's `O(n log n)` algorithm.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KendallTau;
+
+/// The number of pairs within runs of equal consecutive elements.
+fn tied_pairs<T: PartialEq>(xs: &[T]) -> u64 {
+  let mut pairs = 0;
+  let mut run = 1;
+  for w in xs.windows(2) {
+    if w[0] == w[1] {
+      run += 1;
+    } else {
+      pairs += run * (run - 1) / 2;
+      run = 1;
+    }
+  }
+  pairs + run * (run - 1) / 2
+}
+
+/// Sorts `xs` and returns the number of strictly inverted pairs.
+fn sort_counting_inversions(xs: &mut [f64]) -> u64 {
+  let mut buf = xs.to_vec();
+  let mut inversions = 0;
+  let mut width = 1;
+  while width < xs.len() {
+    for start in (0..xs.len()).step_by(2 * width) {
+      let mid = (start + width).min(xs.len());
+      let end = (start + 2 * width).min(xs.len());
+      let (mut i, mut j, mut k) = (start, mid, start);
+      while i < mid && j < end {
+        if xs[j] < xs[i] {
+          inversions += (mid - i) as u64;
+          buf[k] = xs[j];
+          j += 1;
+        } else {
+          buf[k] = xs[i];
+          i += 1;
+        }
+        k += 1;
+      }
+      buf[k..k + mid - i].copy_from_slice(&xs[i..mid]);
+      let k = k + mid - i;
+      buf[k..k + end - j].copy_from_slice(&xs[j..end]);
+    }
+    xs.copy_from_slice(&buf);
+    width *= 2;
+  }
+  inversions
+}
+
+impl Metric for KendallTau {
+  fn score(&self, xs: &[f64], ys: &[f64]) -> f64 {
+    let n = xs.len() as u64;
+    let mut pairs = xs
+      .iter()
+      .copied()
+      .zip(ys.iter().copied())
+      .collect::<Vec<_>>();
+    pairs.sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+
+    let total = n * n.saturating_sub(1) / 2;
+    let xs = pairs.iter().map(|p| p.0).collect::<Vec<_>>();
+    let x_ties = tied_pairs(&xs);
+    let joint_ties = tied_pairs(&pairs);
+    let mut ys = pairs.iter().map(|p| p.1).collect::<Vec<_>>();
+    let discordant = sort_counting_inversions(&mut ys);
+    let y_ties = tied_pairs(&ys);
+
+    let numer = (total + joint_ties) as f64 - (x_ties + y_ties + 2 * discordant) as f64;
+    let denom = (((total - x_ties) as f64) * ((total - y_ties) as f64)).sqrt();
+    numer / denom
+  }
+}
+
+/// The point-biserial correlation, treating users whose set total is above
+/// `threshold` as one group and everyone else as the other. Suited to sets of
+/// pass/fail questions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PointBiserial {
+  pub threshold: f64,
+}
+
+impl Metric for PointBiserial {
+  fn score(&self, xs: &[f64], ys: &[f64]) -> f64 {
+    let n = ys.len() as f64;
+    let (mut n1, mut sum1, mut sum0) = (0., 0., 0.);
+    for (x, y) in xs.iter().zip(ys) {
+      if *x > self.threshold {
+        n1 += 1.;
+        sum1 += y;
+      } else {
+        sum0 += y;
+      }
+    }
+    let n0 = n - n1;
+    let mean = (sum1 + sum0) / n;
+    let std = (ys.iter().map(|y| (y - mean).powi(2)).sum::<f64>() / n).sqrt();
+    (sum1 / n1 - sum0 / n0) / std * (n1 * n0).sqrt() / n
+  }
+}
+
+/// The population covariance. Unlike the correlations, it grows with the
+/// spread of the scores, so it favors sets that separate users widely.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Covariance;
+
+impl Metric for Covariance {
+  fn score(&self, xs: &[f64], ys: &[f64]) -> f64 {
+    let n = xs.len() as f64;
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let sum = xs
+      .iter()
+      .zip(ys)
+      .map(|(x, y)| (x - mean_x) * (y - mean_y))
+      .sum::<f64>();
+    sum / n
+  }
+
+  fn streaming(&self) -> Option<fn(&Moments) -> f64> {
+    Some(Moments::covariance)
+  }
+}
+
+/// The mutual information in nats, treating each distinct score as its own
+/// category. Picks up non-linear relationships that the correlations miss.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MutualInformation;
+
+impl Metric for MutualInformation {
+  fn score(&self, xs: &[f64], ys: &[f64]) -> f64 {
+    if xs.is_empty() {
+      return f64::NAN;
+    }
+    let mut x_counts = HashMap::<u64, usize>::default();
+    let mut y_counts = HashMap::<u64, usize>::default();
+    let mut joint_counts = HashMap::<(u64, u64), usize>::default();
+    for (x, y) in xs.iter().zip(ys) {
+      *x_counts.entry(x.to_bits()).or_default() += 1;
+      *y_counts.entry(y.to_bits()).or_default() += 1;
+      *joint_counts.entry((x.to_bits(), y.to_bits())).or_default() += 1;
+    }
+
+    let n = xs.len() as f64;
+    joint_counts
+      .into_iter()
+      .map(|((x, y), count)| {
+        let count = count as f64;
+        let expected = x_counts[&x] as f64 * y_counts[&y] as f64 / n;
+        count / n * (count / expected).ln()
+      })
+      .sum()
+  }
+
+  /// The information in `x` is at most `ln(n_users)` nats.
+  fn upper_bound(&self) -> Option<fn(usize) -> f64> {
+    Some(|n_users| (n_users as f64).ln())
+  }
+}
+
+/// Looks up a metric by one of the names in [`metric_names`], with any
+/// `<threshold>` replaced by a number.
+pub fn from_name(name: &str) -> Option<Box<dyn Metric>> {
+  if let Some(threshold) = name.strip_prefix("point_biserial:") {
+    let threshold = threshold.parse().ok()?;
+    return Some(Box::new(PointBiserial { threshold }));
+  }
+  Some(match name {
+    "pearson" => Box::new(Pearson),
+    "spearman" => Box::new(Spearman),
+    "kendall" => Box::new(KendallTau),
+    "point_biserial" => Box::new(PointBiserial { threshold: 0. }),
+    "covariance" => Box::new(Covariance),
+    "mutual_info" => Box::new(MutualInformation),
+    _ => return None,
+  })
+}
+
+pub fn metric_names() -> Vec<String> {
+  vec![
+    "pearson".into(),
+    "spearman".into(),
+    "kendall".into(),
+    "point_biserial".into(),
+    "point_biserial:<threshold>".into(),
+    "covariance".into(),
+    "mutual_info".into(),
+  ]
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn close(a: f64, b: f64) -> bool {
+    (a - b).abs() < 1e-12
+  }
+
+  /// The textbook `O(n^2)` definition of tau-b.
+  fn naive_kendall(xs: &[f64], ys: &[f64]) -> f64 {
+    let (mut concordant, mut discordant, mut x_ties, mut y_ties) = (0f64, 0., 0., 0.);
+    for i in 0..xs.len() {
+      for j in (i + 1)..xs.len() {
+        let s = (xs[i] - xs[j]).signum() * (ys[i] - ys[j]).signum();
+        match (xs[i] == xs[j], ys[i] == ys[j]) {
+          (true, true) => {}
+          (true, false) => x_ties += 1.,
+          (false, true) => y_ties += 1.,
+          _ if s > 0. => concordant += 1.,
+          _ => discordant += 1.,
+        }
+      }
+    }
+    let pairs = concordant + discordant;
+    (concordant - discordant) / ((pairs + x_ties) * (pairs + y_ties)).sqrt()
+  }
+
+  fn moments(xs: &[f64], ys: &[f64]) -> Moments {
+    xs.iter().copied().zip(ys.iter().copied()).collect()
+  }
+
+  #[test]
+  fn moments_match_correlation() {
+    let xs = (0..1000).map(|i| ((i * 7) % 13) as f64).collect::<Vec<_>>();
+    let ys = (0..1000)
+      .map(|i| ((i * 31) % 97 + i % 13) as f64)
+      .collect::<Vec<_>>();
+    let m = moments(&xs, &ys);
+    assert_eq!(m.n, 1000);
+    assert!((m.correlation() - utils::correlation(&xs, &ys)).abs() < 1e-12);
+    assert!((m.covariance() - Covariance.score(&xs, &ys)).abs() < 1e-9);
+
+    assert!(moments(&[1., 1., 1.], &[1., 2., 3.]).correlation().is_nan());
+    assert!(Moments::default().correlation().is_nan());
+  }
+
+  #[test]
+  fn exact_sums_match_moments() {
+    let pairs = (0..1000u32).map(|i| ((i * 7) % 13, (i * 31) % 97 + i % 13));
+    let mut sums = ExactSums::default();
+    for (x, y) in pairs.clone() {
+      sums.push(x, y);
+    }
+    let m = pairs
+      .map(|(x, y)| (x as f64, y as f64))
+      .collect::<Moments>();
+    assert_eq!(sums.n, 1000);
+    assert!((sums.moments().correlation() - m.correlation()).abs() < 1e-12);
+    assert!((sums.moments().covariance() - m.covariance()).abs() < 1e-9);
+
+    // Scores near `u32::MAX` overflow `f64`'s exact integers when squared.
+    let mut sums = ExactSums::default();
+    for (x, y) in [(u32::MAX, 1), (u32::MAX - 1, 2), (u32::MAX - 2, 3)] {
+      sums.push(x, y);
+    }
+    assert_eq!(sums.moments().correlation(), -1.);
+  }
+
+  #[test]
+  fn moments_are_stable() {
+    // A large offset loses most of the variance to cancellation in the naive
+    // sum of squares, but not in Welford's updates.
+    let offset = 1e9;
+    let xs = [0., 1., 2., 3., 4., 5.].map(|x| x + offset);
+    let ys = [1., 3., 2., 5., 4., 6.];
+    let expected = utils::correlation(&xs.map(|x| x - offset), &ys);
+    assert!((moments(&xs, &ys).correlation() - expected).abs() < 1e-9);
+  }
+
+  #[test]
+  fn rank_correlations() {
+    let xs = [1., 2., 2., 3., 5., 4., 0.];
+    let ys = [10., 30., 20., 20., 60., 40., 0.];
+    assert_eq!(ranks(&xs), vec![2., 3.5, 3.5, 5., 7., 6., 1.]);
+    assert!(close(
+      Spearman.score(&xs, &ys),
+      utils::correlation(&ranks(&xs), &ranks(&ys))
+    ));
+    assert!(close(KendallTau.score(&xs, &ys), naive_kendall(&xs, &ys)));
+
+    // Monotone but non-linear relationships are perfect rank correlations.
+    let cubes = xs.map(|x: f64| x.powi(3));
+    assert!(close(Spearman.score(&xs, &cubes), 1.));
+    assert!(close(KendallTau.score(&xs, &cubes), 1.));
+    assert!(Pearson.score(&xs, &cubes) < 1.);
+  }
+
+  #[test]
+  fn kendall_matches_naive() {
+    let xs = (0..50).map(|i| ((i * 7) % 11) as f64).collect::<Vec<_>>();
+    let ys = (0..50)
+      .map(|i| ((i * 13) % 17 + i / 5) as f64)
+      .collect::<Vec<_>>();
+    assert!(close(KendallTau.score(&xs, &ys), naive_kendall(&xs, &ys)));
+  }
+
+  #[test]
+  fn point_biserial_is_pearson_of_indicator() {
+    let xs = [0., 2., 1., 0., 2., 3.];
+    let ys = [3., 9., 4., 1., 7., 12.];
+    let threshold = 1.;
+    let indicator = xs.map(|x| if x > threshold { 1. } else { 0. });
+    assert!(close(
+      PointBiserial { threshold }.score(&xs, &ys),
+      Pearson.score(&indicator, &ys)
+    ));
+    assert!(close(
+      from_name("point_biserial:1").unwrap().score(&xs, &ys),
+      Pearson.score(&indicator, &ys)
+    ));
+    assert!(from_name("point_biserial:high").is_none());
+  }
+
+  #[test]
+  fn covariance_and_mutual_information() {
+    let xs = [1., 2., 3., 4.];
+    assert!(close(Covariance.score(&xs, &xs.map(|x| 2. * x)), 2.5));
+
+    // Two equally likely categories that determine each other share ln 2 nats.
+    let ys = [5., 7., 5., 7.];
+    let zs = [0., 1., 0., 1.];
+    assert!(close(MutualInformation.score(&ys, &zs), 2f64.ln()));
+    assert!(close(MutualInformation.score(&ys, &[0., 0., 1., 1.]), 0.));
+  }
+
+  #[test]
+  fn constant_scores_are_nan() {
+    let xs = [1., 1., 1.];
+    let ys = [1., 2., 3.];
+    for name in metric_names() {
+      let metric = from_name(&name.replace("<threshold>", "2")).unwrap();
+      if name != "mutual_info" && name != "covariance" {
+        assert!(metric.score(&xs, &ys).is_nan(), "{name}");
+      }
+    }
+  }
+}
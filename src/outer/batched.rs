@@ -1,9 +1,13 @@
-use crate::{utils::IteratorBatchedExt, CorrSetInner, CorrSetOuter, Question};
-use float_ord::FloatOrd;
+use crate::{
+  utils::{IteratorBatchedExt, TopN},
+  CorrSetInner, CorrSetOuter, Metric,
+};
 
 use itertools::Itertools;
 use rayon::prelude::*;
 
+use super::CorrSetResult;
+
 pub struct CorrSetBatched;
 
 impl CorrSetOuter for CorrSetBatched {
@@ -12,12 +16,14 @@ impl CorrSetOuter for CorrSetBatched {
   }
 
   #[inline]
-  fn k_set<'a, T: CorrSetInner<'a>>(
+  fn k_top_n<'a, T: CorrSetInner, M: Metric + ?Sized>(
     &self,
-    corrset: &T,
+    corrset: &'a T,
+    metric: &M,
     combs: impl Iterator<Item = Vec<T::Q>> + Send,
-  ) -> Vec<&'a Question> {
-    let (qs, _) = combs
+    n: usize,
+  ) -> CorrSetResult<'a> {
+    let top = combs
       .batched::<1024>()
       .par_bridge()
       .map_init(
@@ -25,16 +31,23 @@ impl CorrSetOuter for CorrSetBatched {
         |scratch, qs_batch| {
           qs_batch
             .into_iter()
-            .filter_map(|qs| {
-              let r = corrset.corr_set(scratch, &qs);
-              (!r.is_nan()).then_some((qs, r))
+            .map(|qs| {
+              let corr = corrset.corr_set(metric, scratch, &qs);
+              (qs, corr)
             })
             .collect_vec()
         },
       )
-      .flatten()
-      .max_by_key(|(_, r)| FloatOrd(*r))
-      .unwrap();
-    qs.into_iter().map(|q| corrset.to_question(q)).collect_vec()
+      .fold(
+        || TopN::new(n),
+        |mut top, batch| {
+          for (qs, corr) in batch {
+            top.push(qs, corr);
+          }
+          top
+        },
+      )
+      .reduce(|| TopN::new(n), TopN::merge);
+    super::resolve(corrset, top)
   }
 }
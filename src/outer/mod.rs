@@ -1,4 +1,19 @@
-use crate::{inner::CorrSetInner, Question};
+use std::fmt;
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{
+  approx::BEAM_WIDTH,
+  checkpoint::{Checkpoint, Checkpointer},
+  inner::CorrSetInner,
+  metric::Metric,
+  sample::SAMPLES,
+  shard::Shard,
+  utils::TopN,
+  BuildOptions, Dataset, Question,
+};
 
 pub mod batched;
 pub mod parallel;
@@ -6,27 +21,213 @@ pub mod serial;
 
 pub const TOP_N: usize = 10;
 
+/// A question set found by a search, with the score it achieved.
+#[derive(Serialize, Clone, Debug)]
+pub struct KSet<'a> {
+  pub questions: Vec<&'a Question>,
+  /// The set's score under the search's [`Metric`].
+  pub r: f64,
+  /// The number of users scored, i.e. those the
+  /// [`Missing`](crate::Missing) policy kept and who have a target.
+  pub n_users: usize,
+}
+
+/// The outcome of a search over question sets.
+#[derive(Serialize, Clone, Debug)]
+pub struct CorrSetResult<'a> {
+  /// The best question sets, best first.
+  pub sets: Vec<KSet<'a>>,
+  /// The number of question sets scored, or found to have too few users to
+  /// be scored.
+  pub n_evaluated: usize,
+  /// The number of evaluated question sets left out because their score was
+  /// NaN, including those answered by fewer than
+  /// [`BuildOptions::min_users`](crate::BuildOptions::min_users) users. Exact
+  /// searches may skip the latter without scoring them, but count them here
+  /// and as evaluated all the same.
+  pub n_nan: usize,
+  /// The number of question sets skipped without being scored, because a
+  /// bound showed they couldn't make the top `n`.
+  pub n_pruned: usize,
+}
+
+impl<'a> CorrSetResult<'a> {
+  /// The highest-scoring set, if any set had a non-NaN score.
+  pub fn best(&self) -> Option<&KSet<'a>> {
+    self.sets.first()
+  }
+}
+
+/// Writes `sets` as a ranked table, followed by the search's counts.
+pub(crate) fn write_table<'a>(
+  f: &mut fmt::Formatter<'_>,
+  sets: impl Iterator<Item = (f64, usize, Vec<&'a Question>)>,
+  [n_evaluated, n_nan, n_pruned]: [usize; 3],
+) -> fmt::Result {
+  writeln!(f, "{:>4}  {:>10}  {:>6}  questions", "rank", "r", "users")?;
+  for (i, (r, n_users, questions)) in sets.enumerate() {
+    let questions = questions.iter().map(|q| &q.0).join(", ");
+    writeln!(f, "{:>4}  {r:>10.6}  {n_users:>6}  {questions}", i + 1)?;
+  }
+  write!(f, "evaluated {n_evaluated}, NaN {n_nan}, pruned {n_pruned}")
+}
+
+impl fmt::Display for CorrSetResult<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write_table(
+      f,
+      (self.sets.iter()).map(|set| (set.r, set.n_users, set.questions.clone())),
+      [self.n_evaluated, self.n_nan, self.n_pruned],
+    )
+  }
+}
+
 pub trait CorrSetOuter {
   fn new() -> Self;
-  fn k_set<'a, T: CorrSetInner<'a>>(
+
+  /// Returns the `n` question sets with the highest score under `metric`,
+  /// best first.
+  fn k_top_n<'a, T: CorrSetInner, M: Metric + ?Sized>(
     &self,
-    corrset: &T,
+    corrset: &'a T,
+    metric: &M,
     combinations: impl Iterator<Item = Vec<T::Q>> + Send,
-  ) -> Vec<&'a Question>;
+    n: usize,
+  ) -> CorrSetResult<'a>;
+
+  fn k_set<'a, T: CorrSetInner, M: Metric + ?Sized>(
+    &self,
+    corrset: &'a T,
+    metric: &M,
+    combinations: impl Iterator<Item = Vec<T::Q>> + Send,
+  ) -> CorrSetResult<'a> {
+    self.k_top_n(corrset, metric, combinations, 1)
+  }
+
+  /// Like [`k_top_n`](Self::k_top_n) over every set of `k` questions, but
+  /// scores them a range of ranks at a time, saving its progress with
+  /// `checkpointer` as ranges complete, and once more at the end. Given a
+  /// checkpoint to `resume` from, skips the ranks it reached.
+  fn k_top_n_checkpointed<'a, T: CorrSetInner, M: Metric + ?Sized>(
+    &self,
+    corrset: &'a T,
+    metric: &M,
+    k: usize,
+    n: usize,
+    checkpointer: &Checkpointer,
+    resume: Option<Checkpoint>,
+  ) -> Result<CorrSetResult<'a>> {
+    checkpointer.search_ranks(self, corrset, metric, k, n, resume)
+  }
 }
 
-#[macro_export]
-macro_rules! dispatch_outer_method {
-  ($key:expr, $f:ident, $($arg:expr),*) => {{
-    match $key {
-      "0_serial" => $f::<$crate::outer::serial::CorrSetSerial>($($arg),*),
-      "1_parallel" => $f::<$crate::outer::parallel::CorrSetParallel>($($arg),*),
-      "2_batched" => $f::<$crate::outer::batched::CorrSetBatched>($($arg),*),
-      k => unimplemented!("{k}"),
+/// An exact search over an [`AllocCorrSet`](crate::inner::alloc::AllocCorrSet)
+/// that replaces both the inner and the outer, scoring the sets under each
+/// root (first question) together. Roots make it easy to split the search
+/// into shards, and to checkpoint it.
+pub trait DirectSearch: Sized {
+  fn build(data: &Dataset) -> Self {
+    Self::build_with(data, &BuildOptions::default())
+  }
+
+  fn build_with(data: &Dataset, opts: &BuildOptions) -> Self;
+
+  /// Checks that the search is worth running under `metric`.
+  fn check_metric<M: Metric + ?Sized>(_metric: &M) -> Result<()> {
+    Ok(())
+  }
+
+  /// Returns the `n` question sets with the highest score under `metric`,
+  /// best first.
+  fn k_top_n<M: Metric + ?Sized>(&self, metric: &M, k: usize, n: usize) -> CorrSetResult<'_>;
+
+  /// Like [`k_top_n`](Self::k_top_n), but only searches the sets whose roots
+  /// belong to `shard`.
+  fn k_top_n_shard<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    k: usize,
+    n: usize,
+    shard: Shard,
+  ) -> CorrSetResult<'_>;
+
+  /// Like [`k_top_n`](Self::k_top_n), but saves its progress with
+  /// `checkpointer` as roots complete, and once more at the end. Given a
+  /// checkpoint to `resume` from, skips the roots it completed.
+  fn k_top_n_checkpointed<M: Metric + ?Sized>(
+    &self,
+    metric: &M,
+    k: usize,
+    n: usize,
+    checkpointer: &Checkpointer,
+    resume: Option<Checkpoint>,
+  ) -> Result<CorrSetResult<'_>>;
+
+  fn k_set<M: Metric + ?Sized>(&self, metric: &M, k: usize) -> CorrSetResult<'_> {
+    self.k_top_n(metric, k, 1)
+  }
+}
+
+/// The settings of the [`InnerSearch`]es, each of which reads its own.
+#[derive(Clone, Copy, Debug)]
+pub struct SearchOptions {
+  /// The number of sets a beam search keeps at each size.
+  pub beam_width: usize,
+  /// The most improving swaps made to each set a greedy or beam search finds.
+  /// `usize::MAX` swaps until no swap helps.
+  pub swap_rounds: usize,
+  /// The number of random sets a sampler scores.
+  pub samples: usize,
+  /// The seed of the random sets a sampler scores.
+  pub seed: u64,
+}
+
+impl Default for SearchOptions {
+  fn default() -> Self {
+    SearchOptions {
+      beam_width: BEAM_WIDTH,
+      swap_rounds: usize::MAX,
+      samples: SAMPLES,
+      seed: 0,
     }
-  }};
+  }
+}
+
+/// A search that picks which question sets to score itself, through any
+/// inner, rather than scoring every set the inner yields. Not necessarily
+/// exact.
+pub trait InnerSearch {
+  /// What the search reports, including at least the best sets it found.
+  type Report<'a>: Serialize + fmt::Display + Into<CorrSetResult<'a>>;
+
+  fn new(opts: &SearchOptions) -> Self;
+
+  /// Searches for the `n` question sets of size `k` with the highest score
+  /// under `metric`.
+  fn k_top_n<'a, T: CorrSetInner, M: Metric + ?Sized>(
+    &self,
+    corrset: &'a T,
+    metric: &M,
+    k: usize,
+    n: usize,
+  ) -> Self::Report<'a>;
 }
 
-pub fn outer_names() -> Vec<String> {
-  vec!["0_serial".into(), "1_parallel".into(), "2_batched".into()]
+pub(crate) fn resolve<T: CorrSetInner>(corrset: &T, top: TopN<T::Q>) -> CorrSetResult<'_> {
+  let (n_evaluated, n_nan, n_pruned) = (top.n_evaluated, top.n_nan, top.n_pruned);
+  let sets = top
+    .into_sorted_vec()
+    .into_iter()
+    .map(|(qs, corr)| KSet {
+      questions: qs.into_iter().map(|q| corrset.to_question(q)).collect_vec(),
+      r: corr.r,
+      n_users: corr.n_users,
+    })
+    .collect();
+  CorrSetResult {
+    sets,
+    n_evaluated,
+    n_nan,
+    n_pruned,
+  }
 }
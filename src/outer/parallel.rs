@@ -1,9 +1,9 @@
-use crate::{CorrSetInner, CorrSetOuter, Question};
-use float_ord::FloatOrd;
+use crate::{utils::TopN, CorrSetInner, CorrSetOuter, Metric};
 
-use itertools::Itertools;
 use rayon::prelude::*;
 
+use super::CorrSetResult;
+
 pub struct CorrSetParallel;
 
 impl CorrSetOuter for CorrSetParallel {
@@ -12,24 +12,30 @@ impl CorrSetOuter for CorrSetParallel {
   }
 
   #[inline]
-  fn k_set<'a, T: CorrSetInner<'a>>(
+  fn k_top_n<'a, T: CorrSetInner, M: Metric + ?Sized>(
     &self,
-    corrset: &T,
+    corrset: &'a T,
+    metric: &M,
     combs: impl Iterator<Item = Vec<T::Q>> + Send,
-  ) -> Vec<&'a Question> {
-    let (qs, r) = combs
+    n: usize,
+  ) -> CorrSetResult<'a> {
+    let top = combs
       .par_bridge()
       .map_init(
         || corrset.init_scratch(),
         |scratch, qs: Vec<T::Q>| {
-          let r = corrset.corr_set(scratch, &qs);
-          (!r.is_nan()).then_some((qs, r))
+          let corr = corrset.corr_set(metric, scratch, &qs);
+          (qs, corr)
+        },
+      )
+      .fold(
+        || TopN::new(n),
+        |mut top, (qs, corr)| {
+          top.push(qs, corr);
+          top
         },
       )
-      .filter_map(|x| x)
-      .max_by_key(|(_, r)| FloatOrd(*r))
-      .unwrap();
-    println!("r={r:?}");
-    qs.into_iter().map(|q| corrset.to_question(q)).collect_vec()
+      .reduce(|| TopN::new(n), TopN::merge);
+    super::resolve(corrset, top)
   }
 }
@@ -1,10 +1,6 @@
-use float_ord::FloatOrd;
+use crate::{inner::CorrSetInner, metric::Metric, utils::TopN};
 
-use itertools::Itertools;
-
-use crate::{inner::CorrSetInner, Question};
-
-use super::CorrSetOuter;
+use super::{CorrSetOuter, CorrSetResult};
 
 pub struct CorrSetSerial;
 
@@ -14,19 +10,19 @@ impl CorrSetOuter for CorrSetSerial {
   }
 
   #[inline]
-  fn k_set<'a, T: CorrSetInner<'a>>(
+  fn k_top_n<'a, T: CorrSetInner, M: Metric + ?Sized>(
     &self,
-    corrset: &T,
+    corrset: &'a T,
+    metric: &M,
     combs: impl Iterator<Item = Vec<T::Q>> + Send,
-  ) -> Vec<&'a Question> {
+    n: usize,
+  ) -> CorrSetResult<'a> {
     let mut scratch = corrset.init_scratch();
-    let (qs, _) = combs
-      .filter_map(|qs| {
-        let r = corrset.corr_set(&mut scratch, &qs);
-        (!r.is_nan()).then_some((qs, r))
-      })
-      .max_by_key(|(_, r)| FloatOrd(*r))
-      .unwrap();
-    qs.into_iter().map(|q| corrset.to_question(q)).collect_vec()
+    let mut top = TopN::new(n);
+    for qs in combs {
+      let corr = corrset.corr_set(metric, &mut scratch, &qs);
+      top.push(qs, corr);
+    }
+    super::resolve(corrset, top)
   }
 }
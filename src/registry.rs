@@ -0,0 +1,252 @@
+//! The named inner and outer strategies.
+//!
+//! Each strategy is registered once, in [`inners`] or [`outers`], with a
+//! name and a short description. Code that runs a strategy by name implements
+//! [`InnerTask`] or [`OuterTask`], whose generic `run` is instantiated with the
+//! strategy's type, and passes it to [`run_inner`] or [`run_outer`]. Outers
+//! come in three [`OuterKind`]s, each with its own method on [`OuterTask`].
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+  approx, bnb, fused,
+  inner::{alloc, basic, bchecks, bitset, exact, imap, indexed, mmap, outcome},
+  outer::{batched, parallel, serial, DirectSearch, InnerSearch},
+  sample, CorrSetInner, CorrSetOuter,
+};
+
+/// Work generic over an inner strategy.
+pub trait InnerTask {
+  type Output;
+  fn run<I: CorrSetInner>(self) -> Self::Output;
+}
+
+/// The ways an outer strategy can search.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OuterKind {
+  /// A [`CorrSetOuter`], which scores every set an inner yields.
+  Exhaustive,
+  /// A [`DirectSearch`], which needs no inner.
+  Direct,
+  /// An [`InnerSearch`], which picks the sets it scores through an inner.
+  Search,
+}
+
+/// Work generic over an outer strategy, with a method for each
+/// [`OuterKind`].
+pub trait OuterTask {
+  type Output;
+  fn run<O: CorrSetOuter>(self) -> Self::Output;
+  fn run_direct<S: DirectSearch>(self) -> Self::Output;
+  fn run_search<S: InnerSearch>(self) -> Self::Output;
+}
+
+/// Does nothing, for reading the registered names and descriptions.
+impl InnerTask for () {
+  type Output = ();
+  fn run<I: CorrSetInner>(self) {}
+}
+
+impl OuterTask for () {
+  type Output = ();
+  fn run<O: CorrSetOuter>(self) {}
+  fn run_direct<S: DirectSearch>(self) {}
+  fn run_search<S: InnerSearch>(self) {}
+}
+
+/// Reads an outer's kind.
+struct Kind;
+
+impl OuterTask for Kind {
+  type Output = OuterKind;
+
+  fn run<O: CorrSetOuter>(self) -> OuterKind {
+    OuterKind::Exhaustive
+  }
+
+  fn run_direct<S: DirectSearch>(self) -> OuterKind {
+    OuterKind::Direct
+  }
+
+  fn run_search<S: InnerSearch>(self) -> OuterKind {
+    OuterKind::Search
+  }
+}
+
+/// A registered strategy, which runs a task of type `T` with its type.
+pub struct Strategy<T, Output> {
+  pub name: &'static str,
+  pub description: &'static str,
+  run: fn(T) -> Output,
+}
+
+impl<T, Output> Strategy<T, Output> {
+  pub fn run(&self, task: T) -> Output {
+    (self.run)(task)
+  }
+}
+
+impl<T: InnerTask> Strategy<T, T::Output> {
+  fn inner<I: CorrSetInner>(name: &'static str, description: &'static str) -> Self {
+    Strategy {
+      name,
+      description,
+      run: |task| task.run::<I>(),
+    }
+  }
+}
+
+impl<T: OuterTask> Strategy<T, T::Output> {
+  fn outer<O: CorrSetOuter>(name: &'static str, description: &'static str) -> Self {
+    Strategy {
+      name,
+      description,
+      run: |task| task.run::<O>(),
+    }
+  }
+
+  fn direct<S: DirectSearch>(name: &'static str, description: &'static str) -> Self {
+    Strategy {
+      name,
+      description,
+      run: |task| task.run_direct::<S>(),
+    }
+  }
+
+  fn search<S: InnerSearch>(name: &'static str, description: &'static str) -> Self {
+    Strategy {
+      name,
+      description,
+      run: |task| task.run_search::<S>(),
+    }
+  }
+}
+
+pub fn inners<T: InnerTask>() -> Vec<Strategy<T, T::Output>> {
+  vec![
+    Strategy::inner::<basic::BasicCorrSet>("0_basic", "hash maps keyed by user and question"),
+    Strategy::inner::<indexed::IndexedCorrSet>(
+      "1_indexed",
+      "users and questions interned as dense indices",
+    ),
+    Strategy::inner::<imap::ImapCorrSet>("2_imap", "dense index maps instead of hash maps"),
+    Strategy::inner::<bchecks::BchecksCorrSet>("3_bchecks", "2_imap without bounds checks"),
+    Strategy::inner::<bitset::BvecCorrSet>("4_bitset", "user sets as bit-vectors"),
+    Strategy::inner::<bitset::SimdCorrSet>("5_simd", "user sets as SIMD bitsets"),
+    Strategy::inner::<alloc::AllocCorrSet>("6_alloc", "5_simd with reused scratch buffers"),
+    Strategy::inner::<mmap::MappedCorrSet>("7_mmap", "a 6_alloc index in a memory-mapped file"),
+    Strategy::inner::<exact::ExactCorrSet>(
+      "8_exact",
+      "6_alloc with exact integer sums for streamable metrics",
+    ),
+    Strategy::inner::<outcome::OutcomeCorrSet>(
+      "9_outcome",
+      "6_alloc, leaving out users without an outcome up front",
+    ),
+  ]
+}
+
+pub fn outers<T: OuterTask>() -> Vec<Strategy<T, T::Output>> {
+  vec![
+    Strategy::outer::<serial::CorrSetSerial>("0_serial", "scores one set at a time"),
+    Strategy::outer::<parallel::CorrSetParallel>("1_parallel", "scores sets on every thread"),
+    Strategy::outer::<batched::CorrSetBatched>(
+      "2_batched",
+      "scores sets on every thread, in batches",
+    ),
+    Strategy::direct::<fused::CorrSetFused>("fused", "intersects user sets incrementally"),
+    Strategy::direct::<bnb::CorrSetBranchBound>(
+      "bnb",
+      "fused, skipping sets the metric's bound rules out; only for `mutual_info`",
+    ),
+    Strategy::search::<approx::Greedy>(
+      "greedy",
+      "grows one set a question at a time, then swaps questions",
+    ),
+    Strategy::search::<approx::ApproxSearch>(
+      "beam",
+      "greedy, keeping the best `--beam-width` sets of each size",
+    ),
+    Strategy::search::<sample::Sampler>(
+      "sample",
+      "scores random sets and estimates the best score",
+    ),
+  ]
+}
+
+pub fn inner_names() -> Vec<&'static str> {
+  inners::<()>().iter().map(|s| s.name).collect()
+}
+
+pub fn outer_names() -> Vec<&'static str> {
+  outers::<()>().iter().map(|s| s.name).collect()
+}
+
+/// The kind of the outer named `name`, if there is one.
+pub fn outer_kind(name: &str) -> Option<OuterKind> {
+  outers::<Kind>()
+    .into_iter()
+    .find(|s| s.name == name)
+    .map(|s| s.run(Kind))
+}
+
+/// Runs `task` with the inner named `name`.
+pub fn run_inner<T: InnerTask>(name: &str, task: T) -> Result<T::Output> {
+  let strategy = inners::<T>().into_iter().find(|s| s.name == name);
+  let strategy = strategy.ok_or_else(|| anyhow!("unknown inner `{name}`"))?;
+  Ok(strategy.run(task))
+}
+
+/// Runs `task` with the outer named `name`.
+pub fn run_outer<T: OuterTask>(name: &str, task: T) -> Result<T::Output> {
+  let strategy = outers::<T>().into_iter().find(|s| s.name == name);
+  let strategy = strategy.ok_or_else(|| anyhow!("unknown outer `{name}`"))?;
+  Ok(strategy.run(task))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::inner::test_utils::{mock_data, test};
+
+  struct TestInner;
+
+  impl InnerTask for TestInner {
+    type Output = &'static str;
+    fn run<I: CorrSetInner>(self) -> &'static str {
+      test::<I>(&mock_data());
+      std::any::type_name::<I>()
+    }
+  }
+
+  #[test]
+  fn every_inner() {
+    for strategy in inners::<TestInner>() {
+      strategy.run(TestInner);
+    }
+    assert!(run_inner("6_alloc", TestInner)
+      .unwrap()
+      .ends_with("AllocCorrSet"));
+    assert!(run_inner("9_missing", TestInner).is_err());
+  }
+
+  #[test]
+  fn unique_names() {
+    let mut names = inner_names();
+    names.extend(outer_names());
+    let n = names.len();
+    names.sort_unstable();
+    names.dedup();
+    assert_eq!(names.len(), n);
+    assert!(run_outer("2_batched", ()).is_ok());
+    assert!(run_outer("3_missing", ()).is_err());
+  }
+
+  #[test]
+  fn outer_kinds() {
+    assert_eq!(outer_kind("1_parallel"), Some(OuterKind::Exhaustive));
+    assert_eq!(outer_kind("bnb"), Some(OuterKind::Direct));
+    assert_eq!(outer_kind("sample"), Some(OuterKind::Search));
+    assert_eq!(outer_kind("3_missing"), None);
+  }
+}
@@ -0,0 +1,284 @@
+//! Estimates what an exhaustive search would find by scoring a uniform random
+//! sample of the question sets.
+//!
+//! Besides the best sets sampled, a [`SampleReport`] describes the sampled
+//! scores and how far the best sample is likely to be from the true best, to
+//! help decide whether an exhaustive run is worth it.
+
+use std::fmt;
+
+use fxhash::FxHashSet as HashSet;
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::index, SeedableRng};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+  inner::CorrSetInner,
+  metric::Metric,
+  outer::{self, CorrSetResult, InnerSearch, SearchOptions},
+  utils::{choose, TopN},
+};
+
+/// The default number of question sets to score.
+pub const SAMPLES: usize = 100_000;
+
+/// The confidence level of [`SampleReport::n_better`].
+pub const CONFIDENCE: f64 = 0.95;
+
+const QUANTILES: [f64; 9] = [0., 0.01, 0.05, 0.25, 0.5, 0.75, 0.95, 0.99, 1.];
+const BINS: usize = 20;
+
+/// Samples are scored in chunks, each with its own seeded generator, so that a
+/// seed gives the same sample however the chunks are scheduled.
+const CHUNK: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sampler {
+  /// The number of question sets to score, drawn with replacement.
+  pub samples: usize,
+  pub seed: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Histogram {
+  pub min: f64,
+  pub max: f64,
+  /// The number of scores in each of the equal-width bins from `min` to `max`.
+  pub counts: Vec<usize>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SampleReport<'a> {
+  /// The best sampled sets, with the number scored and the number that were NaN.
+  pub result: CorrSetResult<'a>,
+  /// The number of question sets of this size, saturating at `usize::MAX`.
+  pub n_combinations: usize,
+  /// Pairs of a probability `p` and the score that a fraction `p` of the
+  /// non-NaN samples are at or below.
+  pub quantiles: Vec<(f64, f64)>,
+  pub histogram: Histogram,
+  /// With [`CONFIDENCE`], at most this many question sets score higher than
+  /// the best sample.
+  pub n_better: f64,
+  /// A rough estimate of how much higher the true best score is than the best
+  /// sample, extrapolated from the spacing of the top samples. Assumes the
+  /// scores have an exponential-like upper tail, and ignores any upper bound
+  /// on the metric.
+  pub max_gap: f64,
+}
+
+impl<'a> From<SampleReport<'a>> for CorrSetResult<'a> {
+  fn from(report: SampleReport<'a>) -> Self {
+    report.result
+  }
+}
+
+impl fmt::Display for SampleReport<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let quantiles = (self.quantiles.iter())
+      .map(|(p, r)| format!("{p}: {r:.6}"))
+      .join(", ");
+    write!(
+      f,
+      "{}\nsampled from {} sets\nquantiles {quantiles}\n\
+       at most {:.0} sets score higher than the best sample, by about {:.6}",
+      self.result, self.n_combinations, self.n_better, self.max_gap
+    )
+  }
+}
+
+impl Sampler {
+  pub fn new(samples: usize) -> Self {
+    Sampler { samples, seed: 0 }
+  }
+
+  pub fn with_seed(self, seed: u64) -> Self {
+    Sampler { seed, ..self }
+  }
+
+  /// Scores `samples` question sets of size `k` drawn uniformly at random,
+  /// keeping the best `n`. Scores nothing if there are fewer than `k`
+  /// questions.
+  pub fn run<'a, T: CorrSetInner, M: Metric + ?Sized>(
+    &self,
+    corrset: &'a T,
+    metric: &M,
+    k: usize,
+    n: usize,
+  ) -> SampleReport<'a> {
+    let qs = corrset.iter_qs().collect_vec();
+    let samples = if k > qs.len() { 0 } else { self.samples };
+    let chunks = (0..samples.div_ceil(CHUNK))
+      .into_par_iter()
+      .map_init(
+        // Questions need not be `Sync`, so each thread collects its own.
+        || (corrset.init_scratch(), corrset.iter_qs().collect_vec()),
+        |(scratch, qs), chunk| {
+          let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(chunk as u64));
+          let len = CHUNK.min(samples - chunk * CHUNK);
+          let mut top = TopN::new(n);
+          let mut seen = HashSet::default();
+          let mut scores = Vec::with_capacity(len);
+          for _ in 0..len {
+            let mut set = index::sample(&mut rng, qs.len(), k).into_vec();
+            set.sort_unstable();
+            let questions = set.iter().map(|i| qs[*i].clone()).collect_vec();
+            let corr = corrset.corr_set(metric, scratch, &questions);
+            scores.push(corr.r);
+            // A set sampled again scores the same, so it needn't be kept twice.
+            if seen.insert(set.clone()) {
+              top.push(set, corr);
+            }
+          }
+          (top.into_sorted_vec(), scores)
+        },
+      )
+      .collect::<Vec<_>>();
+
+    let mut top = TopN::new(n);
+    let mut seen = HashSet::default();
+    let mut scores = Vec::with_capacity(samples);
+    for (sets, chunk_scores) in chunks {
+      for (set, corr) in sets {
+        if seen.insert(set.clone()) {
+          top.push(set.into_iter().map(|i| qs[i].clone()).collect(), corr);
+        }
+      }
+      scores.extend(chunk_scores);
+    }
+    top.n_evaluated = scores.len();
+    scores.retain(|r| !r.is_nan());
+    top.n_nan = top.n_evaluated - scores.len();
+    scores.sort_unstable_by(f64::total_cmp);
+
+    let n_combinations = choose(qs.len(), k);
+    let m = samples as f64;
+    SampleReport {
+      result: outer::resolve(corrset, top),
+      n_combinations,
+      quantiles: quantiles(&scores),
+      histogram: histogram(&scores),
+      // The chance that none of `m` samples lands in the top fraction `f` is
+      // `(1 - f)^m`, which falls below `1 - CONFIDENCE` for `f` above this.
+      n_better: (1. - (1. - CONFIDENCE).powf(1. / m)) * n_combinations as f64,
+      max_gap: max_gap(&scores, n_combinations as f64 / m),
+    }
+  }
+}
+
+impl InnerSearch for Sampler {
+  type Report<'a> = SampleReport<'a>;
+
+  fn new(opts: &SearchOptions) -> Self {
+    Sampler::new(opts.samples).with_seed(opts.seed)
+  }
+
+  fn k_top_n<'a, T: CorrSetInner, M: Metric + ?Sized>(
+    &self,
+    corrset: &'a T,
+    metric: &M,
+    k: usize,
+    n: usize,
+  ) -> SampleReport<'a> {
+    self.run(corrset, metric, k, n)
+  }
+}
+
+/// Nearest-rank quantiles of the sorted `scores`.
+fn quantiles(scores: &[f64]) -> Vec<(f64, f64)> {
+  if scores.is_empty() {
+    return Vec::new();
+  }
+  QUANTILES
+    .iter()
+    .map(|&p| {
+      let i = ((p * scores.len() as f64).ceil() as usize).clamp(1, scores.len()) - 1;
+      (p, scores[i])
+    })
+    .collect()
+}
+
+fn histogram(scores: &[f64]) -> Histogram {
+  let (min, max) = match (scores.first(), scores.last()) {
+    (Some(min), Some(max)) => (*min, *max),
+    _ => (f64::NAN, f64::NAN),
+  };
+  let mut counts = vec![0; BINS];
+  for r in scores {
+    let bin = ((r - min) / (max - min) * BINS as f64) as usize;
+    counts[bin.min(BINS - 1)] += 1;
+  }
+  Histogram { min, max, counts }
+}
+
+/// If the top scores have an exponential tail with scale `beta`, the maximum
+/// of `ratio` times as many scores is about `beta * ln(ratio)` higher. `beta`
+/// is estimated as the mean excess of the top samples over the next one.
+fn max_gap(scores: &[f64], ratio: f64) -> f64 {
+  let j = (scores.len() / 100).clamp(1, 100);
+  if scores.len() <= j || ratio <= 1. {
+    return 0.;
+  }
+  let threshold = scores[scores.len() - j - 1];
+  let beta = scores[scores.len() - j..]
+    .iter()
+    .map(|r| r - threshold)
+    .sum::<f64>()
+    / j as f64;
+  beta * ratio.ln()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    fused::CorrSetFused,
+    inner::{alloc::AllocCorrSet, test_utils::sparse_data},
+    metric::Pearson,
+    outer::DirectSearch,
+  };
+
+  #[test]
+  fn summary_statistics() {
+    let scores = (1..=100).map(|i| i as f64).collect_vec();
+    let q = quantiles(&scores);
+    assert_eq!(q[0], (0., 1.));
+    assert_eq!(q[4], (0.5, 50.));
+    assert_eq!(q[8], (1., 100.));
+
+    let h = histogram(&scores);
+    assert_eq!((h.min, h.max), (1., 100.));
+    assert_eq!(h.counts.iter().sum::<usize>(), 100);
+    assert_eq!(h.counts[BINS - 1], 5);
+
+    assert_eq!(max_gap(&scores, 1.), 0.);
+    assert!(max_gap(&scores, 10.) > 0.);
+    assert!(quantiles(&[]).is_empty());
+  }
+
+  #[test]
+  fn sample() {
+    let data = sparse_data();
+    let cs = AllocCorrSet::build(&data);
+    let fused = CorrSetFused::build(&data);
+    let exact = fused.k_set(&Pearson, 3);
+
+    let report = Sampler::new(3000).with_seed(7).run(&cs, &Pearson, 3, 5);
+    assert_eq!(report.result.n_evaluated, 3000);
+    assert_eq!(report.n_combinations, 220);
+    assert!(report.n_better < 1.);
+    // With far more samples than sets, the best set is all but surely sampled.
+    assert_eq!(report.result.best().unwrap().r, exact.best().unwrap().r);
+    let sets = &report.result.sets;
+    assert_eq!(sets.len(), 5);
+    assert!(sets.iter().map(|set| &set.questions).all_unique());
+
+    let again = Sampler::new(3000).with_seed(7).run(&cs, &Pearson, 3, 5);
+    assert_eq!(again.quantiles, report.quantiles);
+
+    let too_big = Sampler::new(3000).run(&cs, &Pearson, 13, 5);
+    assert_eq!(too_big.result.n_evaluated, 0);
+    assert!(too_big.result.sets.is_empty());
+  }
+}
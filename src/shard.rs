@@ -0,0 +1,375 @@
+//! Splitting one search across several processes, and merging their results.
+//!
+//! Each process searches one [`Shard`] of the question sets and saves a
+//! [`ShardOutput`]; [`merge`] then combines the outputs of every shard into
+//! the answer a single search would have given. Outputs name questions rather
+//! than ids, and carry the [`Dataset::fingerprint`] and the way the search was
+//! [`Split`] so that shards of different searches aren't mixed.
+
+use std::{
+  fmt, fs,
+  hash::{Hash, Hasher},
+  ops::Range,
+  path::Path,
+  str::FromStr,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use fxhash::FxHasher64;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  checkpoint::{complete_case, grand_total, sum},
+  combinations,
+  outer::{self, CorrSetResult},
+  BuildOptions, Dataset, Question,
+};
+
+/// Shard `index` of `count`, written `index/count`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shard {
+  pub index: usize,
+  pub count: usize,
+}
+
+impl Shard {
+  /// The shard containing every question set.
+  pub const ALL: Shard = Shard { index: 0, count: 1 };
+
+  /// This shard's contiguous range of ranks among the sets of `k` of `n`
+  /// questions, in the order of [`combinations`](crate::combinations).
+  pub fn ranks(&self, n: usize, k: usize) -> Range<usize> {
+    combinations::shard(n, k, self.index, self.count)
+  }
+
+  /// Whether the sets starting with question `root` belong to this shard.
+  /// Roots are dealt out in turn, since the early roots have the most sets.
+  pub fn has_root(&self, root: usize) -> bool {
+    root % self.count == self.index
+  }
+}
+
+/// How a search was divided into shards, which every shard of one search must
+/// share: shards split the same way partition the sets, but shards split in
+/// different ways overlap.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Split {
+  /// By [`Shard::ranks`], as the [`CorrSetOuter`](crate::CorrSetOuter)s split.
+  Ranks,
+  /// By [`Shard::has_root`], as the [`DirectSearch`](crate::outer::DirectSearch)es
+  /// split.
+  Roots,
+}
+
+impl fmt::Display for Split {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Split::Ranks => "rank",
+      Split::Roots => "root",
+    })
+  }
+}
+
+impl FromStr for Shard {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let Some((index, count)) = s.split_once('/') else {
+      bail!("expected a shard like `0/4`, got `{s}`");
+    };
+    let shard = Shard {
+      index: index.parse().context("parsing shard index")?,
+      count: count.parse().context("parsing shard count")?,
+    };
+    ensure!(shard.index < shard.count, "shard {shard} is out of range");
+    Ok(shard)
+  }
+}
+
+impl fmt::Display for Shard {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.index, self.count)
+  }
+}
+
+/// A question set in a [`ShardOutput`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SharedSet {
+  pub questions: Vec<Question>,
+  /// The questions' ids in the dataset, ascending, which order sets with
+  /// equal scores.
+  pub ids: Vec<usize>,
+  pub r: f64,
+  pub n_users: usize,
+}
+
+/// The best `n` sets of size `k` within one shard, with the counts of the
+/// search that found them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ShardOutput {
+  pub fingerprint: u64,
+  pub metric: String,
+  /// The [`Target`](crate::Target), as written by its `Display`.
+  #[serde(default = "grand_total")]
+  pub target: String,
+  /// The [`Aggregator`](crate::Aggregator), as written by its `Display`.
+  #[serde(default = "sum")]
+  pub aggregator: String,
+  /// The [`Missing`](crate::Missing) policy, as written by its `Display`.
+  #[serde(default = "complete_case")]
+  pub missing: String,
+  /// The [`BuildOptions::min_users`] threshold.
+  #[serde(default)]
+  pub min_users: usize,
+  pub k: usize,
+  pub n: usize,
+  pub shard: Shard,
+  pub split: Split,
+  /// The best sets, best first.
+  pub sets: Vec<SharedSet>,
+  pub n_evaluated: usize,
+  pub n_nan: usize,
+  pub n_pruned: usize,
+}
+
+impl ShardOutput {
+  /// Records `result`, the best `n` sets of size `k` in `shard` of `data`
+  /// under the metric named `metric` and `opts`, split by `split`.
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(
+    data: &Dataset,
+    metric: &str,
+    opts: &BuildOptions,
+    k: usize,
+    n: usize,
+    shard: Shard,
+    split: Split,
+    result: &CorrSetResult,
+  ) -> Self {
+    ShardOutput {
+      fingerprint: data.fingerprint(),
+      metric: metric.to_string(),
+      target: opts.target.to_string(),
+      aggregator: opts.aggregator.to_string(),
+      missing: opts.missing.to_string(),
+      min_users: opts.min_users,
+      k,
+      n,
+      shard,
+      split,
+      sets: result
+        .sets
+        .iter()
+        .map(|set| SharedSet {
+          questions: set.questions.iter().map(|q| (*q).clone()).collect(),
+          ids: set
+            .questions
+            .iter()
+            .map(|q| data.questions.index(q).index())
+            .sorted()
+            .collect(),
+          r: set.r,
+          n_users: set.n_users,
+        })
+        .collect(),
+      n_evaluated: result.n_evaluated,
+      n_nan: result.n_nan,
+      n_pruned: result.n_pruned,
+    }
+  }
+
+  /// A file name for this shard's output, like `k3-pearson-1a2b3c4d.shard-0-of-4.json`,
+  /// which differs between searches: after `k` and the metric comes a hash of
+  /// the search's other options.
+  pub fn file_name(&self) -> String {
+    let mut hasher = FxHasher64::default();
+    (&self.target, &self.aggregator, &self.missing).hash(&mut hasher);
+    (self.min_users, self.n, self.split).hash(&mut hasher);
+    format!(
+      "k{}-{}-{:08x}.shard-{}-of-{}.json",
+      self.k,
+      self.metric.replace(':', "-"),
+      hasher.finish() as u32,
+      self.shard.index,
+      self.shard.count
+    )
+  }
+
+  pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref();
+    let contents =
+      fs::read_to_string(path).with_context(|| format!("reading shard {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("parsing shard {}", path.display()))
+  }
+
+  pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    fs::write(path, serde_json::to_string_pretty(self)?)
+      .with_context(|| format!("writing shard {}", path.display()))
+  }
+}
+
+impl fmt::Display for ShardOutput {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    outer::write_table(
+      f,
+      (self.sets.iter()).map(|set| (set.r, set.n_users, set.questions.iter().collect())),
+      [self.n_evaluated, self.n_nan, self.n_pruned],
+    )
+  }
+}
+
+/// Combines the outputs of every shard of one search into the output for
+/// [`Shard::ALL`]. Errors if the outputs are from different searches, or if
+/// any shard is missing or repeated.
+pub fn merge(outputs: Vec<ShardOutput>) -> Result<ShardOutput> {
+  let Some(first) = outputs.first() else {
+    bail!("no shards to merge");
+  };
+  let count = first.shard.count;
+  let mut seen = vec![false; count];
+  for output in &outputs {
+    ensure!(
+      (
+        output.fingerprint,
+        &output.metric,
+        &output.target,
+        &output.aggregator,
+        &output.missing,
+        output.min_users,
+        output.k,
+        output.n
+      ) == (
+        first.fingerprint,
+        &first.metric,
+        &first.target,
+        &first.aggregator,
+        &first.missing,
+        first.min_users,
+        first.k,
+        first.n
+      ),
+      "shard {} is from a different search than shard {}",
+      output.shard,
+      first.shard
+    );
+    ensure!(
+      output.split == first.split,
+      "shard {} splits the search by {}, but shard {} splits it by {}",
+      output.shard,
+      output.split,
+      first.shard,
+      first.split
+    );
+    ensure!(
+      output.shard.count == count && output.shard.index < count,
+      "shard {} doesn't split the search into {count} shards",
+      output.shard
+    );
+    ensure!(
+      !seen[output.shard.index],
+      "shard {} appears more than once",
+      output.shard
+    );
+    seen[output.shard.index] = true;
+  }
+  if let Some(missing) = seen.iter().position(|seen| !seen) {
+    bail!("shard {missing}/{count} is missing");
+  }
+
+  let mut merged = ShardOutput {
+    shard: Shard::ALL,
+    sets: Vec::new(),
+    n_evaluated: 0,
+    n_nan: 0,
+    n_pruned: 0,
+    ..first.clone()
+  };
+  for output in outputs {
+    merged.sets.extend(output.sets);
+    merged.n_evaluated += output.n_evaluated;
+    merged.n_nan += output.n_nan;
+    merged.n_pruned += output.n_pruned;
+  }
+  merged
+    .sets
+    .sort_by(|a, b| b.r.total_cmp(&a.r).then_with(|| a.ids.cmp(&b.ids)));
+  merged.sets.truncate(merged.n);
+  Ok(merged)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    fused::CorrSetFused,
+    inner::{alloc::AllocCorrSet, test_utils::sparse_data},
+    metric::Pearson,
+    outer::{parallel::CorrSetParallel, CorrSetOuter, DirectSearch},
+    CorrSetInner,
+  };
+
+  #[test]
+  fn parse() {
+    assert_eq!(
+      "2/5".parse::<Shard>().unwrap(),
+      Shard { index: 2, count: 5 }
+    );
+    assert!("5/5".parse::<Shard>().is_err());
+    assert!("2".parse::<Shard>().is_err());
+  }
+
+  #[test]
+  fn merged_shards_match_whole() {
+    let data = sparse_data();
+    let (k, n, count) = (3, 5, 3);
+    let fused = CorrSetFused::build(&data);
+    let whole = ShardOutput::new(
+      &data,
+      "pearson",
+      &BuildOptions::default(),
+      k,
+      n,
+      Shard::ALL,
+      Split::Roots,
+      &fused.k_top_n(&Pearson, k, n),
+    );
+
+    let cs = AllocCorrSet::build(&data);
+    let outer = CorrSetParallel::new();
+    let opts = BuildOptions::default();
+    let shards = (0..count).map(|index| Shard { index, count });
+    let by_rank = shards
+      .clone()
+      .map(|shard| {
+        let combs = cs.combination_range(k, shard.ranks(data.questions.len(), k));
+        let result = outer.k_top_n(&cs, &Pearson, combs, n);
+        ShardOutput::new(&data, "pearson", &opts, k, n, shard, Split::Ranks, &result)
+      })
+      .collect::<Vec<_>>();
+    let by_root = shards
+      .map(|shard| {
+        let result = fused.k_top_n_shard(&Pearson, k, n, shard);
+        ShardOutput::new(&data, "pearson", &opts, k, n, shard, Split::Roots, &result)
+      })
+      .collect::<Vec<_>>();
+
+    let mut mixed = by_rank.clone();
+    mixed[1] = by_root[1].clone();
+    assert!(merge(mixed).is_err());
+
+    let name = by_rank[1].file_name();
+    assert!(name.starts_with("k3-pearson-") && name.ends_with(".shard-1-of-3.json"));
+    assert_ne!(by_rank[1].file_name(), by_root[1].file_name());
+
+    for outputs in [by_rank, by_root] {
+      assert!(merge(outputs[1..].to_vec()).is_err());
+      assert!(merge(vec![outputs[0].clone(); count]).is_err());
+      let merged = merge(outputs).unwrap();
+      assert_eq!(merged.sets, whole.sets);
+      assert_eq!(merged.n_evaluated, whole.n_evaluated);
+    }
+  }
+}
@@ -0,0 +1,356 @@
+//! What a question set's total scores are correlated against.
+//!
+//! A [`Target`] says which value each user's total is compared with, and
+//! [`Targets`] resolves it against the users of an index. Every inner stores
+//! its [`Targets`] in place of users' grand totals.
+
+use std::{
+  fmt,
+  hash::{Hash, Hasher},
+  path::Path,
+  sync::Arc,
+};
+
+use anyhow::Result;
+use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet, FxHasher64};
+use indexical::IndexedDomain;
+use itertools::Itertools;
+
+use crate::{
+  dataset::UserIdx,
+  inner::{alloc::UserSet, imap::UserMap},
+  load::Columns,
+  utils, Dataset, Question, User,
+};
+
+/// The value each user's total score on a question set is correlated against.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Target {
+  /// The user's total score over every question, including the set's.
+  #[default]
+  GrandTotal,
+  /// The user's total score over the questions not in the set.
+  Rest,
+  /// A score from outside the dataset. Users without one are skipped.
+  Outcome(Arc<Outcomes>),
+  /// The user's total score over the given questions.
+  Subset(Vec<Question>),
+}
+
+/// Written as `grand-total`, `rest`, `outcome:` and the outcomes'
+/// fingerprint, or `subset:` and the questions, so that state saved under one
+/// target isn't reused under another.
+impl fmt::Display for Target {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Target::GrandTotal => write!(f, "grand-total"),
+      Target::Rest => write!(f, "rest"),
+      Target::Outcome(outcomes) => write!(f, "outcome:{:016x}", outcomes.fingerprint()),
+      Target::Subset(questions) => {
+        write!(f, "subset:{}", questions.iter().map(|q| &q.0).join(","))
+      }
+    }
+  }
+}
+
+/// A score per user from outside the dataset, such as a final exam grade.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Outcomes(HashMap<User, f64>);
+
+impl Outcomes {
+  pub fn get(&self, user: &User) -> Option<f64> {
+    self.0.get(user).copied()
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  /// A hash of every user and outcome, independent of their order.
+  pub fn fingerprint(&self) -> u64 {
+    let mut hasher = FxHasher64::default();
+    let entries = self.0.iter().sorted_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+    for (user, outcome) in entries {
+      user.hash(&mut hasher);
+      outcome.to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+  }
+
+  /// Reads `path`, a CSV or TSV file with `user` and `outcome` columns, or a
+  /// JSON object from user to outcome.
+  pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+    let table = utils::load_table(
+      path.as_ref(),
+      "outcomes",
+      &Columns::default().user,
+      "outcome",
+    )?;
+    Ok(
+      table
+        .into_iter()
+        .map(|(user, outcome)| (User(user), outcome))
+        .collect(),
+    )
+  }
+
+  /// Matches the outcomes to `users`, noting who is missing on either side.
+  pub fn join(&self, users: &Arc<IndexedDomain<User>>) -> OutcomeJoin {
+    let mut missing = Vec::new();
+    let values = UserMap::new(users, |u| match self.get(users.value(u)) {
+      Some(outcome) => outcome,
+      None => {
+        missing.push(users.value(u).clone());
+        f64::NAN
+      }
+    });
+    let unknown = (self.0.keys())
+      .filter(|user| !users.contains(user))
+      .cloned()
+      .sorted_by(|a, b| a.0.cmp(&b.0))
+      .collect();
+    OutcomeJoin {
+      values,
+      missing,
+      unknown,
+    }
+  }
+}
+
+/// [`Outcomes`] matched to the users of a dataset.
+pub struct OutcomeJoin {
+  /// Each user's outcome, or NaN if they have none.
+  pub values: UserMap<f64>,
+  /// The users without an outcome, in user order. They are skipped when
+  /// scoring.
+  pub missing: Vec<User>,
+  /// The users with an outcome who aren't in the dataset, by name.
+  pub unknown: Vec<User>,
+}
+
+impl FromIterator<(User, f64)> for Outcomes {
+  fn from_iter<I: IntoIterator<Item = (User, f64)>>(iter: I) -> Self {
+    Outcomes(iter.into_iter().collect())
+  }
+}
+
+/// A [`Target`] resolved to a value for each user of an index.
+pub struct Targets {
+  target: Target,
+  subset: HashSet<Question>,
+  rest: bool,
+  /// Each user's value, or NaN if they have none. For [`Target::Rest`], the
+  /// grand total, from which the set's total is subtracted when scoring.
+  values: UserMap<f64>,
+}
+
+impl Targets {
+  pub fn new(target: &Target, data: &Dataset) -> Self {
+    let mut targets = Targets {
+      target: target.clone(),
+      subset: match target {
+        Target::Subset(questions) => questions.iter().cloned().collect(),
+        _ => HashSet::default(),
+      },
+      rest: *target == Target::Rest,
+      values: UserMap::new(&data.users, |_| 0.),
+    };
+
+    for ((u, q), score) in data.latest_scores() {
+      if targets.counts(data.questions.value(q)) {
+        targets.values[u] += score as f64;
+      }
+    }
+    if let Target::Outcome(outcomes) = target {
+      targets.values = outcomes.join(&data.users).values;
+    }
+    targets
+  }
+
+  pub fn target(&self) -> &Target {
+    &self.target
+  }
+
+  /// Whether every value is a sum of scores, so [`Targets::exact_unchecked`]
+  /// applies.
+  pub fn is_integral(&self) -> bool {
+    !matches!(self.target, Target::Outcome(_))
+  }
+
+  /// Whether the set's total is subtracted from each value.
+  pub fn is_rest(&self) -> bool {
+    self.rest
+  }
+
+  /// Whether answers to `question` add to users' values.
+  fn counts(&self, question: &Question) -> bool {
+    match self.target {
+      Target::GrandTotal | Target::Rest => true,
+      Target::Outcome(_) => false,
+      Target::Subset(_) => self.subset.contains(question),
+    }
+  }
+
+  /// User `u`'s target for a set on which they scored `total`, or `None` if
+  /// they have no target and should be skipped.
+  #[inline]
+  pub fn get(&self, u: UserIdx, total: u32) -> Option<f64> {
+    resolve(self.values[u], self.rest, total)
+  }
+
+  /// Like [`get`](Self::get), without checking that `u` is in the index.
+  ///
+  /// # Safety
+  /// `u` must be one of the index's users.
+  #[inline]
+  pub unsafe fn get_unchecked(&self, u: UserIdx, total: u32) -> Option<f64> {
+    resolve(*self.values.get_unchecked(u), self.rest, total)
+  }
+
+  /// Like [`get_unchecked`](Self::get_unchecked), as an exact integer. Only
+  /// meaningful if the targets are [integral](Self::is_integral).
+  ///
+  /// # Safety
+  /// `u` must be one of the index's users.
+  #[inline]
+  pub unsafe fn exact_unchecked(&self, u: UserIdx, total: u32) -> u32 {
+    let value = *self.values.get_unchecked(u) as u32;
+    if self.rest {
+      value - total
+    } else {
+      value
+    }
+  }
+
+  /// The users with a target, out of `users`, the index's users. `None` if
+  /// every user has one.
+  pub fn users(&self, users: &Arc<IndexedDomain<User>>) -> Option<UserSet> {
+    if !matches!(self.target, Target::Outcome(_)) {
+      return None;
+    }
+    let mut with_target = UserSet::new(users);
+    for u in users.indices() {
+      if !self.values[u].is_nan() {
+        with_target.insert(u);
+      }
+    }
+    Some(with_target)
+  }
+
+  /// Every user's stored value, in user order.
+  pub fn values(&self) -> impl Iterator<Item = f64> + '_ {
+    self.values.values().copied()
+  }
+
+  /// Accounts for `u`'s answer to `question` changing from `old` to `new`,
+  /// where `None` is no answer.
+  pub(crate) fn update(
+    &mut self,
+    u: UserIdx,
+    question: &Question,
+    old: Option<u32>,
+    new: Option<u32>,
+  ) {
+    if self.counts(question) {
+      self.values[u] += new.unwrap_or(0) as f64 - old.unwrap_or(0) as f64;
+    }
+  }
+
+  /// Resolves the targets of users added to the index's domain.
+  pub(crate) fn resize(&mut self, users: &Arc<IndexedDomain<User>>) {
+    let outcome = |u| match &self.target {
+      Target::Outcome(outcomes) => outcomes.get(users.value(u)).unwrap_or(f64::NAN),
+      _ => 0.,
+    };
+    self.values = UserMap::new(users, |u| {
+      self.values.get(u).copied().unwrap_or_else(|| outcome(u))
+    });
+  }
+}
+
+/// A user's target from its stored `value`. See [`Targets::get`].
+#[inline]
+pub(crate) fn resolve(value: f64, rest: bool, total: u32) -> Option<f64> {
+  if value.is_nan() {
+    None
+  } else if rest {
+    Some(value - total as f64)
+  } else {
+    Some(value)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::inner::test_utils::mock_data;
+
+  fn user(name: &str) -> User {
+    User(name.to_string())
+  }
+
+  #[test]
+  fn values() {
+    let data = mock_data();
+    let a = data.users.index(&user("a"));
+    let grand = Targets::new(&Target::GrandTotal, &data);
+    assert_eq!(grand.get(a, 1), Some(2.));
+    let rest = Targets::new(&Target::Rest, &data);
+    assert_eq!(rest.get(a, 1), Some(1.));
+    assert_eq!(unsafe { rest.exact_unchecked(a, 2) }, 0);
+    let subset = Targets::new(&Target::Subset(vec![Question("3".into())]), &data);
+    assert_eq!(subset.get(a, 1), Some(0.));
+
+    let outcomes = [(user("a"), 0.5), (user("z"), 1.)].into_iter().collect();
+    let outcome = Targets::new(&Target::Outcome(Arc::new(outcomes)), &data);
+    assert_eq!(outcome.get(a, 1), Some(0.5));
+    assert_eq!(outcome.get(data.users.index(&user("b")), 1), None);
+    assert!(!outcome.is_integral());
+  }
+
+  #[test]
+  fn join() {
+    let data = mock_data();
+    let outcomes = Outcomes::from_iter([(user("z"), 0.), (user("c"), 2.), (user("y"), 1.)]);
+    let join = outcomes.join(&data.users);
+    assert_eq!(join.missing, vec![user("a"), user("b")]);
+    assert_eq!(join.unknown, vec![user("y"), user("z")]);
+    let c = data.users.index(&user("c"));
+    assert_eq!(join.values[c], 2.);
+
+    let targets = Targets::new(&Target::Outcome(Arc::new(outcomes)), &data);
+    let with_target = targets.users(&data.users).unwrap();
+    assert_eq!(with_target.indices().collect_vec(), vec![c]);
+    assert!(Targets::new(&Target::GrandTotal, &data)
+      .users(&data.users)
+      .is_none());
+  }
+
+  #[test]
+  fn load_outcomes() {
+    let dir = std::env::temp_dir();
+    let csv = dir.join(format!("corrset-outcomes-{}.csv", std::process::id()));
+    std::fs::write(&csv, "outcome,user\n1.5,a\n-2,b\n").unwrap();
+    let outcomes = Outcomes::load(&csv);
+    std::fs::write(&csv, "outcome,user\nx,a\n").unwrap();
+    let bad = Outcomes::load(&csv);
+    std::fs::remove_file(&csv).unwrap();
+    let outcomes = outcomes.unwrap();
+    assert_eq!(outcomes.get(&user("b")), Some(-2.));
+    assert!(bad.is_err());
+
+    let json = dir.join(format!("corrset-outcomes-{}.json", std::process::id()));
+    std::fs::write(&json, r#"{"a": 1.5, "b": -2}"#).unwrap();
+    let from_json = Outcomes::load(&json);
+    std::fs::remove_file(&json).unwrap();
+    assert_eq!(from_json.unwrap(), outcomes);
+    assert_eq!(
+      Target::Outcome(Arc::new(outcomes.clone())).to_string(),
+      format!("outcome:{:016x}", outcomes.fingerprint())
+    );
+  }
+}
@@ -1,7 +1,17 @@
+use crate::{inner::Corr, load::Format};
+use anyhow::{bail, Context, Result};
 use arrayvec::ArrayVec;
+use float_ord::FloatOrd;
 use fxhash::FxHashMap as HashMap;
 use indicatif::ProgressStyle;
-use std::hash::Hash;
+use std::{
+  cmp::{Ordering, Reverse},
+  collections::BinaryHeap,
+  fs::File,
+  hash::Hash,
+  io::{BufReader, Read},
+  path::Path,
+};
 
 #[inline]
 pub fn correlation(a: &[f64], b: &[f64]) -> f64 {
@@ -28,6 +38,21 @@ pub fn correlation(a: &[f64], b: &[f64]) -> f64 {
   numer / denom
 }
 
+/// The number of ways to choose `k` of `n` items, saturating at `usize::MAX`.
+pub fn choose(n: usize, k: usize) -> usize {
+  if k > n {
+    return 0;
+  }
+  let mut c = 1u128;
+  for i in 0..k.min(n - k) {
+    c = c * (n - i) as u128 / (i + 1) as u128;
+    if c > usize::MAX as u128 {
+      return usize::MAX;
+    }
+  }
+  c as usize
+}
+
 #[allow(unused)]
 pub fn pb_style() -> ProgressStyle {
   ProgressStyle::with_template("{elapsed_precise} [{wide_bar:.cyan/blue}] {pos}/{len} {eta}")
@@ -46,11 +71,7 @@ pub fn with_pb<I>(
   {
     use indicatif::{ProgressBar, ProgressIterator};
 
-    fn n_choose_k(n: usize, k: usize) -> usize {
-      ((n - k + 1)..=n).product::<usize>() / (1..=k).product::<usize>()
-    }
-
-    let pb = ProgressBar::new(n_choose_k(n, k) as u64).with_style(pb_style());
+    let pb = ProgressBar::new(choose(n, k) as u64).with_style(pb_style());
 
     it.progress_with(pb)
   }
@@ -70,8 +91,56 @@ pub fn group_by<K1: Eq + Hash, K2: Eq + Hash, V>(
   map
 }
 
-pub trait Captures<'a> {}
-impl<'a, T: ?Sized> Captures<'a> for T {}
+/// Reads a number per name from `path`: a CSV or TSV file with `key` and
+/// `value` columns, or a JSON object from name to number. `what` names the
+/// file in errors.
+pub fn load_table(path: &Path, what: &str, key: &str, value: &str) -> Result<Vec<(String, f64)>> {
+  let reader =
+    BufReader::new(File::open(path).with_context(|| format!("opening {what} {}", path.display()))?);
+  match Format::from_path(path) {
+    Some(Format::Json) => Ok(
+      serde_json::from_reader::<_, HashMap<String, f64>>(reader)
+        .with_context(|| format!("parsing {what} {}", path.display()))?
+        .into_iter()
+        .collect(),
+    ),
+    Some(Format::Csv) => read_delimited(reader, b',', key, value),
+    Some(Format::Tsv) => read_delimited(reader, b'\t', key, value),
+    _ => bail!(
+      "{what} must be a .json, .csv or .tsv file, got {}",
+      path.display()
+    ),
+  }
+}
+
+fn read_delimited(
+  reader: impl Read,
+  delimiter: u8,
+  key: &str,
+  value: &str,
+) -> Result<Vec<(String, f64)>> {
+  let mut reader = csv::ReaderBuilder::new()
+    .delimiter(delimiter)
+    .from_reader(reader);
+  let headers = reader.headers()?.clone();
+  let find = |name: &str| {
+    let col = headers.iter().position(|header| header.trim() == name);
+    col.with_context(|| format!("missing column `{name}`"))
+  };
+  let (key_col, value_col) = (find(key)?, find(value)?);
+  reader
+    .records()
+    .map(|record| {
+      let record = record?;
+      let line = record.position().map_or(0, |pos| pos.line());
+      let field = |col| record.get(col).unwrap_or("").trim();
+      let number = field(value_col)
+        .parse::<f64>()
+        .with_context(|| format!("line {line}, field `{value}`"))?;
+      Ok((field(key_col).to_string(), number))
+    })
+    .collect()
+}
 
 pub struct Batched<const N: usize, I: Iterator> {
   iter: I,
@@ -97,6 +166,194 @@ impl<const N: usize, I: Iterator> Iterator for Batched<N, I> {
   }
 }
 
+#[derive(Clone)]
+struct Ranked<Q> {
+  r: FloatOrd<f64>,
+  n_users: usize,
+  qs: Vec<Q>,
+}
+
+impl<Q: Ord> PartialEq for Ranked<Q> {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl<Q: Ord> Eq for Ranked<Q> {}
+
+impl<Q: Ord> PartialOrd for Ranked<Q> {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+/// Higher scores rank higher, and among equal scores, the lexicographically
+/// smaller question set ranks higher. This is a total order, so the top `n`
+/// sets don't depend on the order sets are found in.
+impl<Q: Ord> Ord for Ranked<Q> {
+  fn cmp(&self, other: &Self) -> Ordering {
+    self.r.cmp(&other.r).then_with(|| other.qs.cmp(&self.qs))
+  }
+}
+
+/// Keeps the `n` highest-scoring question sets seen so far, and tallies how
+/// many sets were evaluated, skipped for a NaN correlation, or pruned unseen.
+///
+/// Sets with equal scores are ranked by their questions, which must be
+/// sorted. Internally a min-heap, so rejecting a set that doesn't beat the
+/// current worst entry is a single comparison.
+#[derive(Clone)]
+pub struct TopN<Q> {
+  n: usize,
+  heap: BinaryHeap<Reverse<Ranked<Q>>>,
+  pub n_evaluated: usize,
+  pub n_nan: usize,
+  pub n_pruned: usize,
+}
+
+impl<Q: Ord> TopN<Q> {
+  pub fn new(n: usize) -> Self {
+    TopN {
+      n,
+      heap: BinaryHeap::with_capacity(n + 1),
+      n_evaluated: 0,
+      n_nan: 0,
+      n_pruned: 0,
+    }
+  }
+
+  #[inline]
+  pub fn push(&mut self, qs: Vec<Q>, corr: Corr) {
+    self.n_evaluated += 1;
+    if corr.r.is_nan() {
+      self.n_nan += 1;
+      return;
+    }
+    self.insert(Ranked {
+      r: FloatOrd(corr.r),
+      n_users: corr.n_users,
+      qs,
+    });
+  }
+
+  /// Counts `count` sets skipped for having too few users as scored NaN,
+  /// without scoring them.
+  #[inline]
+  pub fn push_too_few(&mut self, count: usize) {
+    self.n_evaluated += count;
+    self.n_nan += count;
+  }
+
+  /// The number of sets kept.
+  pub fn n(&self) -> usize {
+    self.n
+  }
+
+  /// The score a new set must beat to be kept, once `n` sets are kept.
+  #[inline]
+  pub fn threshold(&self) -> Option<f64> {
+    if self.heap.len() < self.n {
+      return None;
+    }
+    self.heap.peek().map(|Reverse(worst)| worst.r.0)
+  }
+
+  #[inline]
+  fn insert(&mut self, entry: Ranked<Q>) {
+    if self.heap.len() < self.n {
+      self.heap.push(Reverse(entry));
+    } else if let Some(mut worst) = self.heap.peek_mut() {
+      if entry > worst.0 {
+        *worst = Reverse(entry);
+      }
+    }
+  }
+
+  pub fn merge(mut self, other: Self) -> Self {
+    self.n_evaluated += other.n_evaluated;
+    self.n_nan += other.n_nan;
+    self.n_pruned += other.n_pruned;
+    for Reverse(entry) in other.heap {
+      self.insert(entry);
+    }
+    self
+  }
+
+  /// Returns the retained sets, best first, with ties in question order.
+  pub fn into_sorted_vec(self) -> Vec<(Vec<Q>, Corr)> {
+    self
+      .heap
+      .into_sorted_vec()
+      .into_iter()
+      .map(|Reverse(entry)| {
+        let corr = Corr {
+          r: entry.r.0,
+          n_users: entry.n_users,
+        };
+        (entry.qs, corr)
+      })
+      .collect()
+  }
+}
+
+#[test]
+fn test_choose() {
+  assert_eq!(choose(5, 2), 10);
+  assert_eq!(choose(200, 5), 2_535_650_040);
+  assert_eq!(choose(2, 3), 0);
+  assert_eq!(choose(usize::MAX, usize::MAX / 2), usize::MAX);
+}
+
+#[test]
+fn test_top_n() {
+  let corr = |r| Corr { r, n_users: 1 };
+  let mut a = TopN::new(2);
+  a.push(vec![0], corr(0.1));
+  assert_eq!(a.threshold(), None);
+  a.push(vec![1], corr(0.5));
+  assert_eq!(a.threshold(), Some(0.1));
+  a.push(vec![2], corr(0.3));
+
+  let mut b = TopN::new(2);
+  b.push(vec![3], corr(0.4));
+  b.push(vec![4], corr(f64::NAN));
+
+  let top = a.merge(b);
+  assert_eq!((top.n_evaluated, top.n_nan), (5, 1));
+  assert_eq!(
+    top.into_sorted_vec(),
+    vec![(vec![1], corr(0.5)), (vec![3], corr(0.4))]
+  );
+  assert!(TopN::<usize>::new(0).into_sorted_vec().is_empty());
+}
+
+#[test]
+fn test_top_n_ties() {
+  let corr = |r| Corr { r, n_users: 1 };
+  let sets = [vec![2, 3], vec![0, 3], vec![1, 2], vec![0, 1]];
+  let expected = vec![vec![0, 1], vec![0, 3]];
+  for rotation in 0..sets.len() {
+    let mut top = TopN::new(2);
+    let mut other = TopN::new(2);
+    for (i, set) in sets
+      .iter()
+      .cycle()
+      .skip(rotation)
+      .take(sets.len())
+      .enumerate()
+    {
+      let half = if i % 2 == 0 { &mut top } else { &mut other };
+      half.push(set.clone(), corr(0.5));
+    }
+    let qs = top
+      .merge(other)
+      .into_sorted_vec()
+      .into_iter()
+      .map(|(qs, _)| qs);
+    assert_eq!(qs.collect::<Vec<_>>(), expected);
+  }
+}
+
 #[test]
 fn test_batched() {
   assert_eq!(